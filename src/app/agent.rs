@@ -2,19 +2,59 @@ use crate::{
     core::game::GameState,
     search::{
         self,
+        alphabeta::Engine,
+        mcts::{self, Budget, MoveStats},
         naive::{Evaluation, SearchMode},
     },
 };
 use std::collections::HashMap;
 use yew_agent::prelude::oneshot;
 
+/// Search run by the worker.
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum Search {
+    /// Evaluate all positions reachable from the position, see `naive::evaluate`.
+    Naive(SearchMode),
+    /// Evaluate the position only, searching at most `max_depth` moves deep. The position counts
+    /// as drawn if neither player can force a win within them.
+    AlphaBeta { max_depth: usize },
+    /// Play the position out `iterations` times with Monte Carlo tree search, see `mcts::Engine`.
+    /// This gives statistics of the moves rather than evaluations.
+    Mcts { iterations: u64 },
+}
+
+/// Outcome of `EvaluationTask`, depending on the search.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub enum SearchOutput {
+    Evaluations(HashMap<u64, Evaluation>),
+    /// Statistics of the legal moves of the position, in the order of `legal_moves`.
+    MoveStats(Vec<MoveStats>),
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct EvaluationTaskSpec {
     pub state: GameState,
-    pub search_mode: SearchMode,
+    pub search: Search,
 }
 
 #[oneshot]
-pub async fn EvaluationTask(spec: EvaluationTaskSpec) -> HashMap<u64, Evaluation> {
-    search::naive::evaluate(&spec.state, spec.search_mode)
+pub async fn EvaluationTask(spec: EvaluationTaskSpec) -> SearchOutput {
+    match spec.search {
+        Search::Naive(mode) => {
+            SearchOutput::Evaluations(search::naive::evaluate(&spec.state, mode))
+        }
+        Search::AlphaBeta { max_depth } => {
+            let result = Engine::new().search(&spec.state, max_depth);
+            SearchOutput::Evaluations(HashMap::from([(
+                spec.state.zobrist_hash,
+                result.evaluation,
+            )]))
+        }
+        Search::Mcts { iterations } => {
+            // Seeded by the position, so that the same position always gets the same statistics
+            let result = mcts::Engine::new(spec.state.zobrist_hash)
+                .search(&spec.state, Budget::Iterations(iterations));
+            SearchOutput::MoveStats(result.moves)
+        }
+    }
 }
@@ -1,11 +1,14 @@
 use crate::{
     app::{
+        agent::Search,
         game_control::GameControl,
         hovered_move::use_hovered_move,
-        utils::{apply_move_callback, slot_to_css},
+        utils::{apply_move_callback, drop_color, slot_to_css},
     },
     core::{
-        game::GameState,
+        cubie::Cubie,
+        game::{GameOutcome, GameState, UndoRecord},
+        line::Line,
         r#move::{Layer, Move, Rotation},
     },
 };
@@ -15,88 +18,105 @@ use yew::prelude::*;
 #[derive(Properties, PartialEq)]
 pub struct CageProps {
     pub game_state: UseStateHandle<GameState>,
-    pub history: UseStateHandle<Vec<GameState>>,
+    pub history: UseStateHandle<Vec<UndoRecord>>,
+    pub drop_color: UseStateHandle<Option<Cubie>>,
+    pub search: UseStateHandle<Search>,
 }
 
 #[function_component(Cage)]
 pub fn cage(props: &CageProps) -> Html {
-    let player_to_move_color = props.game_state.player_to_move.color;
+    let player_to_move_color = drop_color(&props.game_state.player_to_move, *props.drop_color);
     let game_state_handle = props.game_state.clone();
     let history_handle = props.history.clone();
     let (hovered_move, set_hovered_move) = use_hovered_move();
-    let is_hovered_flip = hovered_move
-        .0
-        .as_ref()
-        .map_or(false, |h| h.as_ref() == &Move::Flip);
-
     let won = props.game_state.won();
-    let game_frozen = won.is_some();
+    let outcome = props.game_state.outcome(&props.history);
+    let game_frozen = outcome != GameOutcome::Ongoing;
+    // Lines that decided the game: the winner's lines, or all of them if nobody won
+    let decisive_lines: Vec<Line> = props
+        .game_state
+        .completed_lines()
+        .into_iter()
+        .filter(|(cubie, _)| won.is_none_or(|(winner, _)| winner.colors.contains(*cubie)))
+        .map(|(_, line)| line)
+        .collect();
     let apply_move = apply_move_callback(
         game_state_handle.clone(),
         history_handle.clone(),
         game_frozen,
     );
 
-    let highlight_color = slot_to_css(Some(props.game_state.player_to_move.color));
+    let highlight_color = slot_to_css(Some(player_to_move_color));
     let slot_opacity = if game_frozen { "0.3" } else { "1.0" };
-    let flip_disabled = game_frozen || props.game_state.last_move == Some(Move::Flip);
+    let legal_moves = props.game_state.legal_moves();
+    let is_legal = |m: &Move| !game_frozen && legal_moves.contains(m);
+    let size = props.game_state.cage.size();
+    let grid_style = format!("grid-template-columns: repeat({size}, 60px);");
+
+    // Buttons for layer turns and the flip, enabled when the rules allow the move
+    let move_button = |m: Move, label: &str| {
+        let is_hovered = hovered_move.0.as_ref().is_some_and(|h| h.as_ref() == &m);
+        let disabled = !is_legal(&m);
+        html! {
+            <button
+                class={classes!("control-button", if is_hovered && !disabled { "highlighted" } else { "" })}
+                style={if is_hovered { format!("--highlight-color: {};", highlight_color) } else { String::new() }}
+                onclick={apply_move.reform(move |_| m)}
+                disabled={disabled}
+                onmouseenter={{
+                    let set_hovered_move = set_hovered_move.clone();
+                    let m = Rc::new(m);
+                    move |_| set_hovered_move.emit(Some(m.clone()))
+                }}
+                onmouseleave={{
+                    let set_hovered_move = set_hovered_move.clone();
+                    move |_| set_hovered_move.emit(None)
+                }}
+            >{ label }</button>
+        }
+    };
 
     html! {
         <div class="cage">
-            { for [Layer::Up, Layer::Equator, Layer::Down].iter().enumerate().map(|(z, layer)| {
-                let rotate_cw = Move::RotateLayer { layer: *layer, rotation: Rotation::Clockwise };
-                let rotate_ccw = Move::RotateLayer { layer: *layer, rotation: Rotation::CounterClockwise };
-
-                let is_hovered_cw = hovered_move.0.as_ref().map_or(false, |h| h.as_ref() == &rotate_cw);
-                let is_hovered_ccw = hovered_move.0.as_ref().map_or(false, |h| h.as_ref() == &rotate_ccw);
-
-                let cw_disabled = game_frozen || props.game_state.last_move == Some(rotate_ccw);
-                let ccw_disabled = game_frozen || props.game_state.last_move == Some(rotate_cw);
+            { for (0..size).rev().map(|z| {
+                let layer = Layer(z as u8);
+                let rotate_cw = Move::RotateLayer { layer, rotation: Rotation::Clockwise };
+                let rotate_ccw = Move::RotateLayer { layer, rotation: Rotation::CounterClockwise };
+                let half_turn = Move::RotateLayer { layer, rotation: Rotation::HalfTurn };
+                let is_top = z == size - 1;
 
                 html! {
                     <div class="layer">
-                        <button
-                            class={classes!("control-button", if is_hovered_ccw && !ccw_disabled { "highlighted" } else { "" })}
-                            style={if is_hovered_ccw { format!("--highlight-color: {};", highlight_color) } else { String::new() }}
-                            onclick={apply_move.reform(move |_| rotate_ccw)}
-                            disabled={ccw_disabled}
-                            onmouseenter={{
-                                let set_hovered_move = set_hovered_move.clone();
-                                let rotate_ccw = Rc::new(rotate_ccw.clone());
-                                move |_| set_hovered_move.emit(Some(rotate_ccw.clone()))
-                            }}
-                            onmouseleave={{
-                                let set_hovered_move = set_hovered_move.clone();
-                                move |_| set_hovered_move.emit(None)
-                            }}
-                        >{ "↻" }</button>
-
-                        <div class="grid">
-                            { for (0..9).map(|i| {
-                                let cubie = props.game_state.cage.grid[i / 3][i % 3][2 - z];
+                        { move_button(rotate_cw, "↻") }
+
+                        <div class="grid" style={grid_style.clone()}>
+                            { for (0..size * size).map(|i| {
+                                let (x, y) = (i / size, i % size);
+                                let slot = [x, y, z];
+                                let cubie = props.game_state.cage.get(slot);
                                 let color = slot_to_css(cubie);
 
                                 // Cubie drops are implemented by clicking on top layer slots.
                                 let drop_move = Move::Drop {
-                                    color: player_to_move_color.clone(),
-                                    column: (i / 3, i % 3),
+                                    color: player_to_move_color,
+                                    column: (x, y),
                                 };
-                                let onclick = if z == 0 && i != 4 && cubie.is_none() && !game_frozen {
-                                    Some(apply_move.reform(move |_| drop_move.clone()))
+                                let can_drop = is_top && is_legal(&drop_move);
+                                let onclick = if can_drop {
+                                    Some(apply_move.reform(move |_| drop_move))
                                 } else {
                                     None
                                 };
 
-                                let is_hovered_drop = hovered_move.0.as_ref().map_or(false, |h| h.as_ref() == &drop_move);
+                                let is_hovered_drop = hovered_move.0.as_ref().is_some_and(|h| h.as_ref() == &drop_move);
 
                                 let mut slot_classes = vec!["slot".to_string()];
-                                if i == 4 { slot_classes.push("center-slot".to_string()); }
-                                if is_hovered_drop && z == 0 { slot_classes.push("highlighted".to_string()); }
-                                if let Some((_, line)) = won {
-                                    let slot = [i / 3, i % 3, 2 - z];
-                                    if line.iter().any(|s| s == &slot) {
-                                        slot_classes.push("winning-line".to_string());
-                                    }
+                                if props.game_state.cage.is_blocked(x, y) {
+                                    slot_classes.push("center-slot".to_string());
+                                }
+                                if is_hovered_drop && is_top { slot_classes.push("highlighted".to_string()); }
+                                if decisive_lines.iter().any(|line| line.contains(&slot)) {
+                                    slot_classes.push("winning-line".to_string());
                                 }
 
                                 html! {
@@ -105,16 +125,16 @@ pub fn cage(props: &CageProps) -> Html {
                                         style={format!("--slot-color: {color}; --highlight-color: {highlight_color}; --slot-opacity: {slot_opacity};")}
                                         onclick={onclick}
                                         onmouseenter={
-                                            if z == 0 && i != 4 && cubie.is_none() && !game_frozen {
+                                            if can_drop {
                                                 let set_hovered_move = set_hovered_move.clone();
-                                                let drop_move = Rc::new(drop_move.clone());
+                                                let drop_move = Rc::new(drop_move);
                                                 Some(move |_| set_hovered_move.emit(Some(drop_move.clone())))
                                             } else {
                                                 None
                                             }
                                         }
                                         onmouseleave={
-                                            if z == 0 && i != 4 && cubie.is_none() && !game_frozen {
+                                            if can_drop {
                                                 let set_hovered_move = set_hovered_move.clone();
                                                 Some(move |_| set_hovered_move.emit(None))
                                             } else {
@@ -126,50 +146,25 @@ pub fn cage(props: &CageProps) -> Html {
                             }) }
                         </div>
 
-                        <button
-                            class={classes!("control-button", if is_hovered_cw && !cw_disabled { "highlighted" } else { "" })}
-                            style={if is_hovered_cw { format!("--highlight-color: {};", highlight_color) } else { String::new() }}
-                            onclick={apply_move.reform(move |_| rotate_cw)}
-                            disabled={cw_disabled}
-                            onmouseenter={{
-                                let set_hovered_move = set_hovered_move.clone();
-                                let rotate_cw = Rc::new(rotate_cw.clone());
-                                move |_| set_hovered_move.emit(Some(rotate_cw.clone()))
-                            }}
-                            onmouseleave={{
-                                let set_hovered_move = set_hovered_move.clone();
-                                move |_| set_hovered_move.emit(None)
-                            }}
-                        >{ "↺" }</button>
+                        { move_button(rotate_ccw, "↺") }
+                        { if props.game_state.rules.half_turns { move_button(half_turn, "⟲2") } else { html! {} } }
                     </div>
                 }
             }) }
 
-            <button
-                class={classes!("control-button", if is_hovered_flip && !flip_disabled { "highlighted" } else { "" })}
-                style={if is_hovered_flip { format!("--highlight-color: {};", highlight_color) } else { String::new() }}
-                onclick={apply_move.reform(|_| Move::Flip)}
-                disabled={flip_disabled}
-                onmouseenter={{
-                    let set_hovered_move = set_hovered_move.clone();
-                    let flip = Rc::new(Move::Flip);
-                    move |_| set_hovered_move.emit(Some(flip.clone()))
-                }}
-                onmouseleave={{
-                    let set_hovered_move = set_hovered_move.clone();
-                    move |_| set_hovered_move.emit(None)
-                }}
-            >{ "Flip" }</button>
+            { move_button(Move::Flip, "Flip") }
 
             {
-                if let Some((winner, _)) = won {
-                    html! { <h2 style="text-align: center;">{ format!("{} won!", winner.color) }</h2> }
-                } else {
-                    html! {}
+                match outcome {
+                    GameOutcome::Win(winner) => html! {
+                        <h2 style="text-align: center;">{ format!("{} won!", winner.color()) }</h2>
+                    },
+                    GameOutcome::Draw => html! { <h2 style="text-align: center;">{ "Draw!" }</h2> },
+                    GameOutcome::Ongoing => html! {},
                 }
             }
 
-            <GameControl game_state={props.game_state.clone()} history={props.history.clone()} />
+            <GameControl game_state={props.game_state.clone()} history={props.history.clone()} search={props.search.clone()} />
 
         </div>
     }
@@ -1,7 +1,15 @@
-use crate::{app::utils::RELOAD_FLAG_KEY, core::game::GameState};
-use bincode::{decode_from_slice, encode_to_vec};
+use crate::{
+    app::{agent::Search, utils::RELOAD_FLAG_KEY},
+    core::{
+        game::{GameState, UndoRecord},
+        record::GameRecord,
+        rules::{DoubleLine, RuleSet},
+        shape::Shape,
+    },
+    search::naive::SearchMode,
+};
 use web_sys::{
-    HtmlInputElement, Url, js_sys,
+    HtmlInputElement, HtmlSelectElement, Url, js_sys,
     wasm_bindgen::{JsCast, prelude::Closure},
 };
 use yew::prelude::*;
@@ -9,7 +17,8 @@ use yew::prelude::*;
 #[derive(Properties, PartialEq)]
 pub struct GameControlProps {
     pub game_state: UseStateHandle<GameState>,
-    pub history: UseStateHandle<Vec<GameState>>,
+    pub history: UseStateHandle<Vec<UndoRecord>>,
+    pub search: UseStateHandle<Search>,
 }
 
 #[function_component(GameControl)]
@@ -18,30 +27,187 @@ pub fn game_control(props: &GameControlProps) -> Html {
     let history_handle = props.history.clone();
     let undo = Callback::from(move |_| {
         let mut new_history = (*history_handle).clone();
-        if let Some(prev_state) = new_history.pop() {
+        if let Some(undo) = new_history.pop() {
+            let mut prev_state = (*game_state_handle).clone();
+            prev_state.undo_move(undo);
             game_state_handle.set(prev_state);
             history_handle.set(new_history);
         }
     });
 
+    let num_players = use_state(|| props.game_state.players.len());
+    let set_num_players = {
+        let num_players = num_players.clone();
+        Callback::from(move |e: Event| {
+            let select: HtmlSelectElement = e.target_unchecked_into();
+            if let Ok(n) = select.value().parse() {
+                num_players.set(n);
+            }
+        })
+    };
+
+    // Rules for the next restart
+    let rules = use_state(|| props.game_state.rules);
+    let rule_toggle = |label: &str, get: fn(&RuleSet) -> bool, set: fn(&mut RuleSet, bool)| {
+        let rules = rules.clone();
+        html! {
+            <label>
+                <input
+                    type="checkbox"
+                    checked={get(&rules)}
+                    onchange={{
+                        let rules = rules.clone();
+                        move |e: Event| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            let mut new_rules = *rules;
+                            set(&mut new_rules, input.checked());
+                            rules.set(new_rules);
+                        }
+                    }}
+                />
+                { label }
+            </label>
+        }
+    };
+
+    // Cages to choose from for the next restart
+    let shapes = [
+        ("3x3x3 cage", Shape::STANDARD),
+        ("3x3x3, open center", Shape::STANDARD.open_center()),
+        ("4x4x4, 4 in a row", Shape::new(4, 4).unwrap()),
+        ("4x4x4, 3 in a row", Shape::new(4, 3).unwrap()),
+    ];
+    let set_shape = {
+        let rules = rules.clone();
+        Callback::from(move |e: Event| {
+            let select: HtmlSelectElement = e.target_unchecked_into();
+            if let Some(&(_, shape)) = select
+                .value()
+                .parse()
+                .ok()
+                .and_then(|i: usize| shapes.get(i))
+            {
+                rules.set(RuleSet { shape, ..*rules });
+            }
+        })
+    };
+
+    // Outcomes of a move completing lines of several players
+    let double_lines = [
+        ("Double line: draw", DoubleLine::Draw),
+        ("Double line: mover wins", DoubleLine::MoverWins),
+        ("Double line: mover loses", DoubleLine::MoverLoses),
+    ];
+    let set_double_line = {
+        let rules = rules.clone();
+        Callback::from(move |e: Event| {
+            let select: HtmlSelectElement = e.target_unchecked_into();
+            if let Some(&(_, double_line)) = select
+                .value()
+                .parse()
+                .ok()
+                .and_then(|i: usize| double_lines.get(i))
+            {
+                rules.set(RuleSet {
+                    double_line,
+                    ..*rules
+                });
+            }
+        })
+    };
+
+    // Draws after a number of moves in a row without a drop
+    let move_limits = [
+        ("No move limit", None),
+        ("Draw after 10 moves without a drop", Some(10)),
+        ("Draw after 20 moves without a drop", Some(20)),
+        ("Draw after 50 moves without a drop", Some(50)),
+    ];
+    let set_move_limit = {
+        let rules = rules.clone();
+        Callback::from(move |e: Event| {
+            let select: HtmlSelectElement = e.target_unchecked_into();
+            if let Some(&(_, move_limit)) = select
+                .value()
+                .parse()
+                .ok()
+                .and_then(|i: usize| move_limits.get(i))
+            {
+                rules.set(RuleSet {
+                    move_limit,
+                    ..*rules
+                });
+            }
+        })
+    };
+
+    // Searches evaluating the moves
+    let searches = [
+        (
+            "Evaluate: full search",
+            Search::Naive(SearchMode::OptimalWL),
+        ),
+        (
+            "Evaluate: alpha-beta, 4 moves deep",
+            Search::AlphaBeta { max_depth: 4 },
+        ),
+        (
+            "Evaluate: alpha-beta, 6 moves deep",
+            Search::AlphaBeta { max_depth: 6 },
+        ),
+        (
+            "Evaluate: alpha-beta, 8 moves deep",
+            Search::AlphaBeta { max_depth: 8 },
+        ),
+        (
+            "Evaluate: full search and 1000 playouts",
+            Search::Mcts { iterations: 1000 },
+        ),
+        (
+            "Evaluate: full search and 10000 playouts",
+            Search::Mcts { iterations: 10000 },
+        ),
+    ];
+    let set_search = {
+        let search = props.search.clone();
+        Callback::from(move |e: Event| {
+            let select: HtmlSelectElement = e.target_unchecked_into();
+            if let Some(&(_, new_search)) = select
+                .value()
+                .parse()
+                .ok()
+                .and_then(|i: usize| searches.get(i))
+            {
+                search.set(new_search);
+            }
+        })
+    };
+
     let game_state_handle = props.game_state.clone();
     let history_handle = props.history.clone();
+    let restart_num_players = *num_players;
+    let restart_rules = *rules;
     let restart = Callback::from(move |_| {
-        game_state_handle.set(GameState::new(12, 12));
+        // Split the cubies evenly, so that they can fill all the available slots
+        let slots = restart_rules.shape.num_slots();
+        let cubies = vec![(slots / restart_num_players) as u8; restart_num_players];
+        game_state_handle.set(GameState::with_cubies(&cubies).with_rules(restart_rules));
         history_handle.set(Vec::new());
     });
 
     let game_state_handle = props.game_state.clone();
+    let history_handle = props.history.clone();
     let export = Callback::from(move |_| {
         // Set reload flag in localStorage before export-triggered reload
         if let Some(storage) = web_sys::window().and_then(|w| w.local_storage().ok().flatten()) {
             storage.set_item(RELOAD_FLAG_KEY, "true").ok();
         }
-        let state = &*game_state_handle;
-        let bin = encode_to_vec(state, bincode::config::standard()).unwrap();
-        let uint8_array = js_sys::Uint8Array::from(bin.as_slice());
-        let blob =
-            web_sys::Blob::new_with_u8_array_sequence(&js_sys::Array::of1(&uint8_array)).unwrap();
+        // The game in text form, see `core::record`
+        let mut record = GameRecord::from_history(&game_state_handle, &history_handle);
+        let today = String::from(js_sys::Date::new_0().to_iso_string());
+        record.date = today.get(..10).map(str::to_string);
+        let record = js_sys::JsString::from(record.to_string());
+        let blob = web_sys::Blob::new_with_str_sequence(&js_sys::Array::of1(&record)).unwrap();
         let url = Url::create_object_url_with_blob(&blob).unwrap();
         let window = web_sys::window().unwrap();
         window.open_with_url(&url).unwrap();
@@ -55,7 +221,7 @@ pub fn game_control(props: &GameControlProps) -> Html {
         let document = window.document().unwrap();
         let input = document.create_element("input").unwrap();
         input.set_attribute("type", "file").unwrap();
-        input.set_attribute("accept", ".bin").unwrap();
+        input.set_attribute("accept", ".txt").unwrap();
         let input_html: HtmlInputElement = input.unchecked_into();
         let game_state_handle = game_state_handle.clone();
         let history_handle = history_handle.clone();
@@ -63,31 +229,33 @@ pub fn game_control(props: &GameControlProps) -> Html {
         let input_html_handle = input_html.clone();
         let closure = Closure::wrap(Box::new(move |_: web_sys::Event| {
             let files = input_html_handle.files();
-            if let Some(files) = files {
-                if let Some(file) = files.get(0) {
-                    let fr = web_sys::FileReader::new().unwrap();
-                    let fr_clone = fr.clone();
-                    let game_state_handle = game_state_handle.clone();
-                    let history_handle = history_handle.clone();
-                    let window = window.clone();
-                    let onload = Closure::wrap(Box::new(move |_e: web_sys::Event| {
-                        let result = fr_clone.result().unwrap();
-                        let array = js_sys::Uint8Array::new(&result);
-                        let mut vec = vec![0u8; array.length() as usize];
-                        array.copy_to(&mut vec[..]);
-                        if let Ok((state, _)) =
-                            decode_from_slice::<GameState, _>(&vec, bincode::config::standard())
-                        {
+            if let Some(files) = files
+                && let Some(file) = files.get(0)
+            {
+                let fr = web_sys::FileReader::new().unwrap();
+                let fr_clone = fr.clone();
+                let game_state_handle = game_state_handle.clone();
+                let history_handle = history_handle.clone();
+                let window = window.clone();
+                let onload = Closure::wrap(Box::new(move |_e: web_sys::Event| {
+                    let text = fr_clone.result().unwrap().as_string().unwrap_or_default();
+                    match text
+                        .parse::<GameRecord>()
+                        .and_then(|record| record.replay())
+                    {
+                        Ok((state, history)) => {
                             game_state_handle.set(state);
-                            history_handle.set(Vec::new());
-                        } else {
-                            window.alert_with_message("Failed to import position").ok();
+                            history_handle.set(history);
+                        }
+                        Err(e) => {
+                            let message = format!("Failed to import game: {}", e);
+                            window.alert_with_message(&message).ok();
                         }
-                    }) as Box<dyn FnMut(_)>);
-                    fr.set_onload(Some(onload.as_ref().unchecked_ref()));
-                    fr.read_as_array_buffer(&file).unwrap();
-                    onload.forget();
-                }
+                    }
+                }) as Box<dyn FnMut(_)>);
+                fr.set_onload(Some(onload.as_ref().unchecked_ref()));
+                fr.read_as_text(&file).unwrap();
+                onload.forget();
             }
         }) as Box<dyn FnMut(_)>);
         input_html.set_onchange(Some(closure.as_ref().unchecked_ref()));
@@ -101,8 +269,40 @@ pub fn game_control(props: &GameControlProps) -> Html {
         <div class="game-control">
             <button class="control-button" onclick={undo} disabled={(*props.history).is_empty()}>{ "Undo last move" }</button>
             <button class="control-button" onclick={restart}>{ "Restart the game" }</button>
-            <button class="control-button" onclick={export}>{ "Export position" }</button>
-            <button class="control-button" onclick={import}>{ "Import position" }</button>
+            <select class="control-button" onchange={set_num_players}>
+                { for (2..=4).map(|n| html! {
+                    <option value={n.to_string()} selected={n == *num_players}>
+                        { format!("{} players", n) }
+                    </option>
+                }) }
+            </select>
+            <select class="control-button" onchange={set_shape}>
+                { for shapes.iter().enumerate().map(|(i, (label, shape))| html! {
+                    <option value={i.to_string()} selected={*shape == rules.shape}>{ *label }</option>
+                }) }
+            </select>
+            <select class="control-button" onchange={set_double_line}>
+                { for double_lines.iter().enumerate().map(|(i, (label, double_line))| html! {
+                    <option value={i.to_string()} selected={*double_line == rules.double_line}>{ *label }</option>
+                }) }
+            </select>
+            <select class="control-button" onchange={set_move_limit}>
+                { for move_limits.iter().enumerate().map(|(i, (label, move_limit))| html! {
+                    <option value={i.to_string()} selected={*move_limit == rules.move_limit}>{ *label }</option>
+                }) }
+            </select>
+            <div class="rule-toggles">
+                { rule_toggle("Allow undoing", |r| !r.no_undo, |r, v| r.no_undo = !v) }
+                { rule_toggle("Half turns", |r| r.half_turns, |r, v| r.half_turns = v) }
+                { rule_toggle("Flips", |r| r.flips, |r, v| r.flips = v) }
+            </div>
+            <select class="control-button" onchange={set_search}>
+                { for searches.iter().enumerate().map(|(i, (label, search))| html! {
+                    <option value={i.to_string()} selected={*search == *props.search}>{ *label }</option>
+                }) }
+            </select>
+            <button class="control-button" onclick={export}>{ "Export game" }</button>
+            <button class="control-button" onclick={import}>{ "Import game" }</button>
         </div>
     }
 }
@@ -1,13 +1,19 @@
 use crate::{
     app::{
-        agent::{EvaluationTask, EvaluationTaskSpec},
+        agent::{EvaluationTask, EvaluationTaskSpec, Search, SearchOutput},
         hovered_move::use_hovered_move,
-        utils::{self, apply_move_callback},
+        utils::{self, EvalCache, UndecidedCache, apply_move_callback},
+    },
+    core::{
+        cubie::Cubie,
+        game::{GameOutcome, GameState, Player, UndoRecord},
+    },
+    search::{
+        mcts::MoveStats,
+        naive::{Evaluation, SearchMode},
     },
-    core::game::{GameState, Player},
-    search::naive::{Evaluation, SearchMode},
 };
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 use web_sys::window;
 use yew::{platform::spawn_local, prelude::*, use_effect_with};
 use yew_agent::oneshot::use_oneshot_runner;
@@ -16,8 +22,12 @@ use yew_agent::oneshot::use_oneshot_runner;
 pub struct PlayerPanelProps {
     pub player: Player,
     pub game_state: UseStateHandle<GameState>,
-    pub history: UseStateHandle<Vec<GameState>>,
-    pub eval: Rc<RefCell<HashMap<u64, Evaluation>>>,
+    pub history: UseStateHandle<Vec<UndoRecord>>,
+    pub eval: Rc<RefCell<EvalCache>>,
+    pub undecided: Rc<RefCell<UndecidedCache>>,
+    /// Search evaluating the moves that aren't evaluated yet.
+    pub search: Search,
+    pub drop_color: UseStateHandle<Option<Cubie>>,
 }
 
 fn eval_to_string(eval: Option<&Evaluation>, player_id: u8) -> String {
@@ -25,16 +35,13 @@ fn eval_to_string(eval: Option<&Evaluation>, player_id: u8) -> String {
         return "Calculating...".to_string();
     }
     let Evaluation {
-        score,
+        winner,
         moves_to_wl: moves_to_win,
     } = eval.unwrap();
-    match (score, player_id) {
-        (1, 0) => format!("Win in {}", moves_to_win),
-        (1, 1) => format!("Loss in {}", moves_to_win),
-        (-1, 0) => format!("Loss in {}", moves_to_win),
-        (-1, 1) => format!("Win in {}", moves_to_win),
-        (0, _) => "Draw".to_string(),
-        ev => format!("Unexpected evaluation: {:?}", ev),
+    match winner {
+        Some(winner) if *winner == player_id => format!("Win in {}", moves_to_win),
+        Some(_) => format!("Loss in {}", moves_to_win),
+        None => "Draw".to_string(),
     }
 }
 
@@ -53,58 +60,156 @@ pub fn player_panel(props: &PlayerPanelProps) -> Html {
             .unwrap_or(false)
     });
     let eval = props.eval.clone();
+    let undecided = props.undecided.clone();
+    let search = props.search;
+    // Moves alpha-beta searched as deep as asked without finding a win for either player
+    let undecided_depth = move |undecided: &UndecidedCache, hash| match search {
+        Search::AlphaBeta { max_depth } => undecided.get(hash).filter(|&depth| depth >= max_depth),
+        _ => None,
+    };
+    // Playout statistics of the moves, for the position and number of playouts they were run with
+    let move_stats = use_state(|| None::<(GameState, u64, Vec<MoveStats>)>);
+    let playouts = match search {
+        Search::Mcts { iterations } => Some(iterations),
+        _ => None,
+    };
+    let current_stats = move_stats
+        .as_ref()
+        .filter(|(state, iterations, _)| {
+            *state == *props.game_state && Some(*iterations) == playouts
+        })
+        .map(|(_, _, stats)| stats.clone());
 
-    let is_won = props.game_state.won().is_some();
-    let apply_move = apply_move_callback(props.game_state.clone(), props.history.clone(), is_won);
+    let is_over = props.game_state.outcome(&props.history) != GameOutcome::Ongoing;
+    let apply_move = apply_move_callback(props.game_state.clone(), props.history.clone(), is_over);
 
     let cubies = (0..props.game_state.remaining_cubies[props.player.id as usize]).map(|i| {
         html! {
-            <div class={classes!("cubie-icon", props.player.color.to_string())} key={i} />
+            <div class={classes!("cubie-icon", props.player.color().to_string())} key={i} />
         }
     });
 
-    let highlight_color = utils::slot_to_css(Some(props.player.color));
+    let highlight_color = utils::slot_to_css(Some(props.player.color()));
+
+    // Players owning several colors pick the one they drop next
+    let selected_color = utils::drop_color(&props.player, *props.drop_color);
+    let color_picker = props.player.colors.iter().map(|color| {
+        let drop_color = props.drop_color.clone();
+        let selected = is_turn && color == selected_color;
+        html! {
+            <div
+                class={classes!("cubie-icon", "color-pick", color.to_string(), selected.then_some("selected"))}
+                onclick={move |_| if is_turn { drop_color.set(Some(color)) }}
+            />
+        }
+    });
 
-    let moves = if props.game_state.won().is_some() {
+    let moves = if is_over {
         Vec::new() // Don't show further moves if game is finished
     } else {
-        utils::sort_moves_by_evaluation(props.game_state.legal_moves(), &props.game_state, &eval)
+        // One entry per group of moves leading to symmetric positions
+        // Undecided moves rank as draws
+        utils::sort_moves_by_evaluation(
+            props.game_state.unique_moves(),
+            &props.game_state,
+            |hash| {
+                eval.borrow().get(hash).or_else(|| {
+                    undecided_depth(&undecided.borrow(), hash).map(|_| Evaluation {
+                        winner: None,
+                        moves_to_wl: -1,
+                    })
+                })
+            },
+        )
     };
     let (hovered_move, set_hovered_move) = use_hovered_move();
 
-    // The web worker evaluating missing (non-preloaded) game state evaluations runs with pruning.
+    // The web worker evaluating missing (non-preloaded) game state evaluations runs with pruning,
+    // or with alpha-beta if picked in the game controls, which evaluates a single position.
     // That avoids wasting time and calculating lots of positions we'll never need to see.
     // But it also means we'll need to call it repeatedly for new unevaluated positions.
+    // With playouts picked, the moves are evaluated with pruning first and played out after.
     // We avoid the need to sync multiple workers by only allowing one to run at a time.
     let agent_running = use_state(|| false);
     let eval_task = use_oneshot_runner::<EvaluationTask>();
-    let game_state = props.game_state.clone();
     use_effect_with(
-        (moves.clone(), eval.clone(), agent_running.clone()),
-        move |(moves, eval, agent_running)| {
-            if !*agent_running.clone() {
-                for mv in moves.iter() {
-                    let mut new_state = (*game_state).clone();
-                    new_state.apply_move_normalize(mv.clone()).unwrap();
-                    let hash = new_state.zobrist_hash;
-                    let eval_map = eval.borrow();
-                    if !eval_map.contains_key(&hash) {
-                        agent_running.set(true);
-                        let eval = eval.clone();
-                        let agent_running = agent_running.clone();
-                        spawn_local(async move {
-                            let spec = EvaluationTaskSpec {
-                                state: new_state,
-                                // this is fast by now, no need to give user choice to prune
-                                search_mode: SearchMode::OptimalWL,
-                            };
-                            let new_evals = eval_task.run(spec).await;
-                            eval.borrow_mut().extend(new_evals);
-                            agent_running.set(false);
-                        });
-                        break;
+        (
+            (*props.game_state).clone(),
+            moves.clone(),
+            eval.clone(),
+            undecided.clone(),
+            search,
+            current_stats.is_some(),
+            agent_running.clone(),
+        ),
+        move |(game_state, moves, eval, undecided, search, has_stats, agent_running)| {
+            let search = *search;
+            let eval_search = match search {
+                Search::Mcts { .. } => Search::Naive(SearchMode::OptimalWL),
+                search => search,
+            };
+            let missing = moves.iter().find_map(|group| {
+                let mut new_state = game_state.clone();
+                new_state.apply_move_normalize(group[0]).unwrap();
+                let hash = new_state.zobrist_hash;
+                (eval.borrow().get(hash).is_none()
+                    && undecided_depth(&undecided.borrow(), hash).is_none())
+                .then_some((new_state, hash))
+            });
+            if **agent_running {
+                // This runs again once the running search is done
+            } else if let Some((new_state, hash)) = missing {
+                agent_running.set(true);
+                let eval = eval.clone();
+                let undecided = undecided.clone();
+                let agent_running = agent_running.clone();
+                spawn_local(async move {
+                    let spec = EvaluationTaskSpec {
+                        state: new_state,
+                        search: eval_search,
+                    };
+                    let SearchOutput::Evaluations(new_evals) = eval_task.run(spec).await else {
+                        unreachable!("Evaluating searches return evaluations")
+                    };
+                    // The requested evaluation goes in last, so that the others can't
+                    // evict it and have it requested again
+                    let requested = new_evals.get(&hash).copied();
+                    let mut cache = eval.borrow_mut();
+                    match (eval_search, requested) {
+                        // Positions without a win within the depth may be won later on
+                        (
+                            Search::AlphaBeta { max_depth },
+                            Some(Evaluation { winner: None, .. }),
+                        ) => {
+                            let depth = max_depth.min(u8::MAX as usize) as u8;
+                            undecided.borrow_mut().insert(hash, max_depth, depth);
+                        }
+                        _ => {
+                            utils::cache_evaluations(&mut cache, new_evals);
+                            utils::cache_evaluations(&mut cache, requested.map(|e| (hash, e)));
+                        }
                     }
-                }
+                    agent_running.set(false);
+                });
+            } else if let Search::Mcts { iterations } = search
+                && !moves.is_empty()
+                && !*has_stats
+            {
+                agent_running.set(true);
+                let move_stats = move_stats.clone();
+                let agent_running = agent_running.clone();
+                let state = game_state.clone();
+                spawn_local(async move {
+                    let spec = EvaluationTaskSpec {
+                        state: state.clone(),
+                        search,
+                    };
+                    let SearchOutput::MoveStats(stats) = eval_task.run(spec).await else {
+                        unreachable!("Playouts return move statistics")
+                    };
+                    move_stats.set(Some((state, iterations, stats)));
+                    agent_running.set(false);
+                });
             }
             || ()
         },
@@ -131,6 +236,18 @@ pub fn player_panel(props: &PlayerPanelProps) -> Html {
             <div class="cubies-remaining">
                 { for cubies }
             </div>
+            {
+                if props.player.colors.len() > 1 {
+                    html! {
+                        <>
+                            <p>{ "Drop color:" }</p>
+                            <div class="cubies-remaining">{ for color_picker }</div>
+                        </>
+                    }
+                } else {
+                    html! {}
+                }
+            }
             <label>
                 <input
                     type="checkbox"
@@ -149,22 +266,29 @@ pub fn player_panel(props: &PlayerPanelProps) -> Html {
                 if *move_list_visible && is_turn {
                     html! {
                         <ul class="move-list">
-                            { for moves.iter().map(|mv| {
+                            { for moves.iter().map(|group| {
+                                let mv = group[0];
                                 let mut new_state = (*props.game_state).clone();
-                                new_state.apply_move_normalize(mv.clone()).unwrap();
-                                let eval_map = eval.borrow();
-                                let eval = eval_map.get(&new_state.zobrist_hash);
-                                let eval = eval_to_string(eval, props.game_state.player_to_move.id);
-                                let is_hovered = hovered_move.0.as_ref().map_or(false, |h| h.as_ref() == mv);
-                                let mv = mv.clone();
+                                new_state.apply_move_normalize(mv).unwrap();
+                                let hash = new_state.zobrist_hash;
+                                let mut eval = match (eval.borrow().get(hash), undecided_depth(&undecided.borrow(), hash)) {
+                                    (None, Some(depth)) => format!("No win within {} moves", depth),
+                                    (eval, _) => eval_to_string(eval.as_ref(), props.game_state.player_to_move.id),
+                                };
+                                // Moves of a group lead to symmetric positions, which share their playouts
+                                if let Some(stats) = current_stats.iter().flatten().find(|stats| stats.r#move == mv) {
+                                    eval += &format!(" · {} playouts, {:.0}% won", stats.visits, stats.win_rate * 100.0);
+                                }
+                                let is_hovered = hovered_move.0.as_ref().is_some_and(|h| group.contains(h.as_ref()));
+                                let names: Vec<String> = group.iter().map(|m| m.notation(props.game_state.rules.shape.size()).to_string()).collect();
                                 html! {
                                     <li
                                         class={if is_hovered { "move-highlighted" } else { "" }}
                                         style={if is_hovered { format!("--highlight-color: {};", highlight_color) } else { String::new() }}
-                                        onclick={apply_move.reform(move |_| mv.clone())}
+                                        onclick={apply_move.reform(move |_| mv)}
                                         onmouseenter={ {
                                             let set_hovered_move = set_hovered_move.clone();
-                                            let mv = Rc::new(mv.clone());
+                                            let mv = Rc::new(mv);
                                             move |_| set_hovered_move.emit(Some(mv.clone()))
                                         }}
                                         onmouseleave={ {
@@ -172,7 +296,7 @@ pub fn player_panel(props: &PlayerPanelProps) -> Html {
                                             move |_| set_hovered_move.emit(None)
                                         }}
                                     >
-                                        { format!("{}: {}", mv, eval) }
+                                        { format!("{}: {}", names.join(", "), eval) }
                                     </li>
                                 }
                             })}
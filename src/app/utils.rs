@@ -1,16 +1,45 @@
 use crate::{
-    core::{cubie::Cubie, game::GameState, r#move::Move},
-    search::naive::Evaluation,
+    core::{
+        cubie::Cubie,
+        game::{GameState, Player, UndoRecord},
+        r#move::Move,
+    },
+    search::{naive::Evaluation, table::TranspositionTable},
 };
-use std::{cell::RefCell, collections::HashMap};
 use yew::prelude::*;
 
 pub const STORAGE_KEY: &str = "rubik_cage_position";
 pub const RELOAD_FLAG_KEY: &str = "load_position_on_next_reload";
 
+/// Memory budget of the evaluation cache, which holds the precomputed evaluations with room to
+/// spare for the ones computed by the web worker.
+pub const EVAL_CACHE_MEGABYTES: usize = 32;
+
+/// Evaluations known to the webapp, bounded so that a long session can't exhaust memory.
+pub type EvalCache = TranspositionTable<Evaluation>;
+
+/// Memory budget of the cache of undecided positions.
+pub const UNDECIDED_CACHE_MEGABYTES: usize = 4;
+
+/// Positions in which alpha-beta found no win for either player, with the number of moves it
+/// searched. They may still be won further on, so they are kept apart from the evaluations.
+pub type UndecidedCache = TranspositionTable<usize>;
+
+/// Adds evaluations to the cache. Evaluations with more moves to win or lose took longer to
+/// compute, so they are more valuable to keep.
+pub fn cache_evaluations(
+    cache: &mut EvalCache,
+    evaluations: impl IntoIterator<Item = (u64, Evaluation)>,
+) {
+    for (hash, eval) in evaluations {
+        let depth = eval.moves_to_wl.clamp(0, u8::MAX as isize) as u8;
+        cache.insert(hash, eval, depth);
+    }
+}
+
 pub fn apply_move_callback(
     game_state_handle: UseStateHandle<GameState>,
-    history_handle: UseStateHandle<Vec<GameState>>,
+    history_handle: UseStateHandle<Vec<UndoRecord>>,
     game_frozen: bool,
 ) -> Callback<Move> {
     let game_state_handle = game_state_handle.clone();
@@ -18,9 +47,9 @@ pub fn apply_move_callback(
     Callback::from(move |m: Move| {
         if !game_frozen {
             let mut new_state = (*game_state_handle).clone();
-            if new_state.apply_move(m).is_ok() {
+            if let Ok(undo) = new_state.apply_move(m) {
                 let mut new_history = (*history_handle).clone();
-                new_history.push((*game_state_handle).clone());
+                new_history.push(undo);
                 history_handle.set(new_history);
                 game_state_handle.set(new_state);
             }
@@ -28,117 +57,58 @@ pub fn apply_move_callback(
     })
 }
 
-/// Sort moves by evaluation: wins for player first (shortest moves_to_wl), then draws, then losses
-/// (longest loss first), unknowns last.
+/// Sort groups of equivalent moves (see `GameState::unique_moves`) by the evaluation of their
+/// first move, as given by `evaluation` for the hash of the position it leads to: wins for player
+/// first (shortest moves_to_wl), then draws, then losses (longest loss first), unknowns last.
 pub fn sort_moves_by_evaluation(
-    moves: Vec<Move>,
+    groups: Vec<Vec<Move>>,
     game_state: &GameState,
-    eval: &RefCell<HashMap<u64, Evaluation>>,
-) -> Vec<Move> {
-    let mut moves_with_eval: Vec<(Move, Option<Evaluation>)> = moves
+    evaluation: impl Fn(u64) -> Option<Evaluation>,
+) -> Vec<Vec<Move>> {
+    let mut moves_with_eval: Vec<(Vec<Move>, Option<Evaluation>)> = groups
         .into_iter()
-        .map(|mv| {
+        .map(|group| {
             let mut new_state = game_state.clone();
-            new_state.apply_move_normalize(mv.clone()).unwrap();
-            let eval_map = eval.borrow();
-            let eval = eval_map.get(&new_state.zobrist_hash).cloned();
-            (mv, eval)
+            new_state.apply_move_normalize(group[0]).unwrap();
+            let eval = evaluation(new_state.zobrist_hash);
+            (group, eval)
         })
         .collect();
     let player_id = game_state.player_to_move.id;
-    moves_with_eval.sort_by(|a, b| {
-        let eval_a = &a.1;
-        let eval_b = &b.1;
-        fn class(eval: &Option<Evaluation>, player_id: u8) -> u8 {
-            match eval {
-                Some(Evaluation { score: 1, .. }) if player_id == 0 => 0,
-                Some(Evaluation { score: -1, .. }) if player_id == 1 => 0,
-                Some(Evaluation { score: 0, .. }) => 1,
-                Some(Evaluation { score: -1, .. }) if player_id == 0 => 2,
-                Some(Evaluation { score: 1, .. }) if player_id == 1 => 2,
-                _ => 3,
-            }
+    moves_with_eval.sort_by(|a, b| match (&a.1, &b.1) {
+        (Some(eval_a), Some(eval_b)) if eval_a.is_better_for(player_id, eval_b) => {
+            std::cmp::Ordering::Less
         }
-        let class_a = class(eval_a, player_id);
-        let class_b = class(eval_b, player_id);
-        match class_a.cmp(&class_b) {
-            std::cmp::Ordering::Equal => {
-                match (eval_a, eval_b) {
-                    // Wins: shortest moves_to_wl first
-                    (
-                        Some(Evaluation {
-                            score: 1,
-                            moves_to_wl: ma,
-                        }),
-                        Some(Evaluation {
-                            score: 1,
-                            moves_to_wl: mb,
-                        }),
-                    ) if player_id == 0 => ma.cmp(mb),
-                    (
-                        Some(Evaluation {
-                            score: -1,
-                            moves_to_wl: ma,
-                        }),
-                        Some(Evaluation {
-                            score: -1,
-                            moves_to_wl: mb,
-                        }),
-                    ) if player_id == 1 => ma.cmp(mb),
-                    // Losses: longest moves_to_wl first
-                    (
-                        Some(Evaluation {
-                            score: -1,
-                            moves_to_wl: ma,
-                        }),
-                        Some(Evaluation {
-                            score: -1,
-                            moves_to_wl: mb,
-                        }),
-                    ) if player_id == 0 => mb.cmp(ma),
-                    (
-                        Some(Evaluation {
-                            score: 1,
-                            moves_to_wl: ma,
-                        }),
-                        Some(Evaluation {
-                            score: 1,
-                            moves_to_wl: mb,
-                        }),
-                    ) if player_id == 1 => mb.cmp(ma),
-                    // Draws or unknown: keep original order
-                    _ => std::cmp::Ordering::Equal,
-                }
-            }
-            ord => ord,
+        (Some(eval_a), Some(eval_b)) if eval_b.is_better_for(player_id, eval_a) => {
+            std::cmp::Ordering::Greater
         }
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        // Equally good or unknown: keep original order
+        _ => std::cmp::Ordering::Equal,
     });
-    moves_with_eval.into_iter().map(|(mv, _)| mv).collect()
+    moves_with_eval
+        .into_iter()
+        .map(|(group, _)| group)
+        .collect()
+}
+
+/// Color of the next drop of `player`: the picked one if the player owns it, otherwise their main
+/// color.
+pub fn drop_color(player: &Player, picked: Option<Cubie>) -> Cubie {
+    picked
+        .filter(|&color| player.colors.contains(color))
+        .unwrap_or_else(|| player.color())
 }
 
 pub fn slot_to_css(cubie: Option<Cubie>) -> &'static str {
     match cubie {
         Some(Cubie::Blue) => "var(--cubie-blue)",
         Some(Cubie::Red) => "var(--cubie-red)",
-        _ => "var(--slot-empty)",
-    }
-}
-
-pub fn bytes_to_hex(bytes: &[u8]) -> String {
-    bytes
-        .iter()
-        .map(|b| format!("{:02x}", b))
-        .collect::<String>()
-}
-
-pub fn hex_to_bytes(hex: &str) -> Option<Vec<u8>> {
-    if hex.len() % 2 != 0 {
-        return None;
+        Some(Cubie::Green) => "var(--cubie-green)",
+        Some(Cubie::Yellow) => "var(--cubie-yellow)",
+        Some(Cubie::Orange) => "var(--cubie-orange)",
+        Some(Cubie::White) => "var(--cubie-white)",
+        None => "var(--slot-empty)",
     }
-    Some(
-        (0..hex.len())
-            .step_by(2)
-            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
-            .collect::<Option<Vec<u8>>>()?,
-    )
 }
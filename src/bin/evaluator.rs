@@ -4,10 +4,25 @@
 //! is a bit too sizey, so this is our compromise: load several MB of precomputed evaluations
 //! and compute smaller targeted remainders on the fly in a web worker.
 
-use clap::{Parser, Subcommand};
+use clap::{Args, CommandFactory, Parser, Subcommand, error::ErrorKind};
 use rubik_cage::{
-    core::game::GameState,
-    search::naive::{Evaluation, SearchMode, evaluate, load_eval, save_eval},
+    core::{
+        enumerate::{Layering, enumerate},
+        error::GameError,
+        game::GameState,
+        perft::{divide, perft},
+        rules::{DoubleLine, RuleSet},
+        shape::Shape,
+    },
+    search::{
+        alphabeta,
+        mcts::{self, Budget},
+        naive::{
+            EvalFile, Evaluation, STACK_SIZE, SearchMode, evaluate, evaluate_parallel, load_eval,
+            lookup, save_eval,
+        },
+        retrograde::{self, compare},
+    },
 };
 use std::{collections::HashMap, thread};
 
@@ -34,6 +49,79 @@ enum Commands {
         p2_cubies: u8,
         /// Output file path
         outpath: String,
+        /// Evaluate positions with the colors of the players swapped only once, which roughly
+        /// halves the evaluations of games where both players start with the same cubies
+        #[arg(long)]
+        color_swap: bool,
+        /// Solve the game exactly by retrograde analysis instead of the naive search, which needs
+        /// memory for the whole game graph
+        #[arg(long)]
+        retrograde: bool,
+        /// Naive search mode: full, optimal (pruning branches that can't improve on the fastest
+        /// win found) or pruned (stopping at the first win). Only full evaluates every position
+        #[arg(long, value_parser = parse_search_mode, default_value = "full", conflicts_with = "retrograde")]
+        mode: SearchMode,
+        /// Split the full naive search over this many threads sharing their evaluations
+        #[arg(long, value_parser = parse_threads, default_value_t = 1, conflicts_with = "retrograde")]
+        threads: usize,
+        #[command(flatten)]
+        rules: RuleArgs,
+    },
+    /// Count the positions reachable in the game where players start with `p1_cubies` and
+    /// `p2_cubies` respectively, i.e. the evaluations `evaluate` would compute, layer by layer.
+    /// Example: `evaluator count 12 12 --by ply`
+    Count {
+        /// Number of cubies for player 1
+        p1_cubies: u8,
+        /// Number of cubies for player 2
+        p2_cubies: u8,
+        /// Layers to count the positions in: cubies (placed) or ply
+        #[arg(long, value_parser = parse_layering, default_value = "cubies")]
+        by: Layering,
+        /// Count positions with the colors of the players swapped only once, as `evaluate` does
+        /// with the same flag
+        #[arg(long)]
+        color_swap: bool,
+        #[command(flatten)]
+        rules: RuleArgs,
+    },
+    /// Evaluate a position given in position notation and the moves available in it.
+    /// Example: `evaluator position "....#....,....#....,R...#B... B2/R2 1 - 3"`
+    Position {
+        /// The position, see `core::position` for the format
+        position: String,
+        /// Search this many moves deep with alpha-beta instead of evaluating all positions
+        /// reachable from the position
+        #[arg(long)]
+        depth: Option<usize>,
+        /// Run this many playouts of Monte Carlo tree search instead, which gives win rates for
+        /// positions of any game
+        #[arg(long, conflicts_with = "depth")]
+        playouts: Option<u64>,
+    },
+    /// Count the move sequences of `depth` moves from a position given in position notation, to
+    /// check move generation against known counts.
+    /// Example: `evaluator perft "....#....,....#....,....#.... B12/R12 1 - 3" 4 --divide`
+    Perft {
+        /// The position, see `core::position` for the format
+        position: String,
+        /// Number of moves
+        depth: usize,
+        /// Break the count down by the first move
+        #[arg(long)]
+        divide: bool,
+        /// Normalize the position after each move, which must not change the count
+        #[arg(long)]
+        normalize: bool,
+    },
+    /// Compare two evaluation files of the same game, e.g. of the naive search and of the
+    /// retrograde analysis.
+    /// Example: `evaluator compare eval/naive_5_5.bin eval/retrograde_5_5.bin`
+    Compare {
+        /// First file path
+        first: String,
+        /// Second file path
+        second: String,
     },
     /// Filter an existing evaluation file by minimum moves to win/loss.
     /// Example: `evaluator filter eval/eval_12_12.bin assets/eval.bin 3`
@@ -47,6 +135,100 @@ enum Commands {
     },
 }
 
+/// Deviations from the standard rules of the physical puzzle.
+#[derive(Args)]
+struct RuleArgs {
+    /// Allow undoing the opponent's previous move
+    #[arg(long)]
+    allow_undo: bool,
+    /// Allow turning layers by 180 degrees in one move
+    #[arg(long)]
+    half_turns: bool,
+    /// Disallow flipping the cage
+    #[arg(long)]
+    no_flips: bool,
+    /// Size of the cage
+    #[arg(long, default_value_t = 3)]
+    size: usize,
+    /// Number of same color cubies in a row needed to win, the cage size by default
+    #[arg(long)]
+    line_length: Option<usize>,
+    /// Columns to block instead of the center ones, as x,y pairs
+    #[arg(long, value_parser = parse_column, num_args = 1.., conflicts_with = "open_center")]
+    blocked: Option<Vec<(usize, usize)>>,
+    /// Open the center column for cubies
+    #[arg(long)]
+    open_center: bool,
+    /// Outcome of a move completing lines of several players: draw, mover-wins or mover-loses
+    #[arg(long, value_parser = parse_double_line, default_value = "draw")]
+    double_line: DoubleLine,
+    /// Draw the game after this many moves in a row without a drop
+    #[arg(long)]
+    move_limit: Option<u8>,
+}
+
+fn parse_double_line(s: &str) -> Result<DoubleLine, String> {
+    match s {
+        "draw" => Ok(DoubleLine::Draw),
+        "mover-wins" => Ok(DoubleLine::MoverWins),
+        "mover-loses" => Ok(DoubleLine::MoverLoses),
+        _ => Err("Expected draw, mover-wins or mover-loses".to_string()),
+    }
+}
+
+fn parse_layering(s: &str) -> Result<Layering, String> {
+    match s {
+        "cubies" => Ok(Layering::CubiesPlaced),
+        "ply" => Ok(Layering::Ply),
+        _ => Err("Expected cubies or ply".to_string()),
+    }
+}
+
+fn parse_search_mode(s: &str) -> Result<SearchMode, String> {
+    match s {
+        "full" => Ok(SearchMode::Full),
+        "optimal" => Ok(SearchMode::OptimalWL),
+        "pruned" => Ok(SearchMode::Pruned),
+        _ => Err("Expected full, optimal or pruned".to_string()),
+    }
+}
+
+fn parse_threads(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("Expected at least one thread".to_string()),
+        Ok(threads) => Ok(threads),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_column(s: &str) -> Result<(usize, usize), String> {
+    let (x, y) = s.split_once(',').ok_or("Expected a column as x,y")?;
+    let coordinate = |c: &str| c.trim().parse::<usize>().map_err(|e| e.to_string());
+    Ok((coordinate(x)?, coordinate(y)?))
+}
+
+impl TryFrom<RuleArgs> for RuleSet {
+    type Error = String;
+
+    fn try_from(args: RuleArgs) -> Result<Self, Self::Error> {
+        let mut shape = Shape::new(args.size, args.line_length.unwrap_or(args.size))?;
+        if let Some(columns) = args.blocked {
+            shape = shape.with_blocked_columns(&columns)?;
+        }
+        if args.open_center {
+            shape = shape.open_center();
+        }
+        Ok(RuleSet {
+            no_undo: !args.allow_undo,
+            half_turns: args.half_turns,
+            flips: !args.no_flips,
+            shape,
+            double_line: args.double_line,
+            move_limit: args.move_limit,
+        })
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
     match cli.command {
@@ -54,8 +236,80 @@ fn main() {
             p1_cubies,
             p2_cubies,
             outpath,
+            color_swap,
+            retrograde,
+            mode,
+            threads,
+            rules,
+        } => {
+            let rules = rules.try_into().unwrap_or_else(|e: String| {
+                Cli::command().error(ErrorKind::InvalidValue, e).exit()
+            });
+            if threads > 1 && mode != SearchMode::Full {
+                Cli::command()
+                    .error(ErrorKind::ArgumentConflict, "--threads needs --mode full")
+                    .exit()
+            }
+            let solver = if retrograde {
+                Solver::Retrograde
+            } else {
+                Solver::Naive { mode, threads }
+            };
+            eval(&outpath, p1_cubies, p2_cubies, rules, color_swap, solver);
+        }
+        Commands::Count {
+            p1_cubies,
+            p2_cubies,
+            by,
+            color_swap,
+            rules,
         } => {
-            eval(&outpath, p1_cubies, p2_cubies);
+            let rules = rules.try_into().unwrap_or_else(|e: String| {
+                Cli::command().error(ErrorKind::InvalidValue, e).exit()
+            });
+            count(p1_cubies, p2_cubies, rules, by, color_swap);
+        }
+        Commands::Position {
+            position,
+            depth,
+            playouts,
+        } => {
+            let game = position.parse().unwrap_or_else(|e: GameError| {
+                Cli::command()
+                    .error(ErrorKind::InvalidValue, e.to_string())
+                    .exit()
+            });
+            match (depth, playouts) {
+                (Some(depth), _) => search_position(&game, depth),
+                (_, Some(playouts)) => mcts_position(&game, playouts),
+                _ => eval_position(game),
+            }
+        }
+        Commands::Perft {
+            position,
+            depth,
+            divide: by_move,
+            normalize,
+        } => {
+            let game: GameState = position.parse().unwrap_or_else(|e: GameError| {
+                Cli::command()
+                    .error(ErrorKind::InvalidValue, e.to_string())
+                    .exit()
+            });
+            let total = if by_move {
+                let divided = divide(&game, depth, normalize);
+                let size = game.rules.shape.size();
+                for (m, count) in &divided {
+                    println!("{}: {}", m.notation(size), count);
+                }
+                divided.iter().map(|(_, count)| count).sum()
+            } else {
+                perft(&game, depth, normalize)
+            };
+            println!("Move sequences: {}", total);
+        }
+        Commands::Compare { first, second } => {
+            compare_files(&first, &second);
         }
         Commands::Filter {
             infile,
@@ -67,28 +321,160 @@ fn main() {
     }
 }
 
-/// Evaluate a specific (m, n) game and store the results in a binary file.
-fn eval(file: &str, p1_cubies: u8, p2_cubies: u8) {
-    let game = GameState::new(p1_cubies, p2_cubies);
-    let stack_size = 32 * 1024 * 1024;
-    let evaluated = thread::Builder::new()
-        .stack_size(stack_size)
-        .spawn(move || evaluate(&game, SearchMode::Full))
+/// Evaluate the positions reachable from `game` the search reaches in `mode`, on a thread with
+/// enough stack for the search, or on `threads` of them in full mode.
+fn evaluate_all(game: &GameState, mode: SearchMode, threads: usize) -> HashMap<u64, Evaluation> {
+    let root = game.clone();
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || match mode {
+            SearchMode::Full => evaluate_parallel(&root, threads),
+            _ => evaluate(&root, mode),
+        })
         .unwrap()
         .join()
-        .unwrap();
-    let eval = evaluated[&game.zobrist_hash];
-    let eval_str = match eval.score {
-        1 => format!("Player 1 win in {} moves", eval.moves_to_wl),
-        -1 => format!("Player 2 win in {}", eval.moves_to_wl),
-        0 => "Draw".to_string(),
-        ev => format!("Unexpected evaluation: {:?}", ev),
+        .unwrap()
+}
+
+fn eval_to_string(eval: &Evaluation) -> String {
+    match eval.winner {
+        Some(id) => format!("Player {} win in {} moves", id + 1, eval.moves_to_wl),
+        None => "Draw".to_string(),
+    }
+}
+
+/// How `eval` solves a game.
+enum Solver {
+    Naive { mode: SearchMode, threads: usize },
+    Retrograde,
+}
+
+/// Evaluate a specific (m, n) game and store the results in a binary file.
+fn eval(
+    file: &str,
+    p1_cubies: u8,
+    p2_cubies: u8,
+    rules: RuleSet,
+    color_swap: bool,
+    solver: Solver,
+) {
+    let mut game = GameState::new(p1_cubies, p2_cubies).with_rules(rules);
+    if color_swap {
+        game = game.with_color_swap();
+    }
+    let evaluated = match solver {
+        Solver::Naive { mode, threads } => evaluate_all(&game, mode, threads),
+        Solver::Retrograde => retrograde::evaluate(&game),
     };
+    let eval_str = eval_to_string(&lookup(&evaluated, &game).unwrap());
 
-    println!("Game evaluation: {}", eval_str);
+    println!("Game evaluation ({}): {}", rules, eval_str);
     println!("Number of evaluated states: {}", evaluated.len());
 
-    save_eval(&evaluated, file).unwrap();
+    let eval_file = EvalFile::new(rules, color_swap, evaluated);
+    save_eval(&eval_file, file).unwrap();
+}
+
+/// Count the reachable positions of a specific (m, n) game, printing the count of each layer as
+/// soon as it is known.
+fn count(p1_cubies: u8, p2_cubies: u8, rules: RuleSet, layering: Layering, color_swap: bool) {
+    let mut game = GameState::new(p1_cubies, p2_cubies).with_rules(rules);
+    if color_swap {
+        game = game.with_color_swap();
+    }
+    let name = match layering {
+        Layering::CubiesPlaced => "Cubies placed",
+        Layering::Ply => "Ply",
+    };
+    let (mut current, mut count) = (0, 0);
+    let counts = enumerate(&game, layering, |layer, _| {
+        if layer != current {
+            println!("{} {}: {}", name, current, count);
+            (current, count) = (layer, 0);
+        }
+        count += 1;
+    });
+    println!("{} {}: {}", name, current, count);
+    println!(
+        "Number of reachable states: {}",
+        counts.iter().sum::<usize>()
+    );
+}
+
+/// Evaluate a single position and print the evaluation after each of its moves.
+fn eval_position(game: GameState) {
+    let evaluated = evaluate_all(&game, SearchMode::Full, 1);
+    println!(
+        "Position evaluation: {}",
+        eval_to_string(&evaluated[&game.zobrist_hash])
+    );
+    let size = game.rules.shape.size();
+    for m in game.legal_moves() {
+        let mut child = game.clone();
+        child.apply_move_normalize(m).unwrap();
+        // Children of won positions aren't searched
+        if let Some(eval) = evaluated.get(&child.zobrist_hash) {
+            println!("{}: {}", m.notation(size), eval_to_string(eval));
+        }
+    }
+}
+
+/// Search a single position with alpha-beta and print its evaluation and best move.
+fn search_position(game: &GameState, max_depth: usize) {
+    let result = alphabeta::Engine::new().search(game, max_depth);
+    println!(
+        "Position evaluation: {}",
+        eval_to_string(&result.evaluation)
+    );
+    if let Some(best_move) = result.best_move {
+        println!("Best move: {}", best_move.notation(game.rules.shape.size()));
+    }
+    println!(
+        "Searched {} positions, {} moves deep",
+        result.nodes, result.depth
+    );
+}
+
+/// Search a single position with Monte Carlo tree search and print the statistics of its moves.
+fn mcts_position(game: &GameState, playouts: u64) {
+    let result = mcts::Engine::new(0).search(game, Budget::Iterations(playouts));
+    let size = game.rules.shape.size();
+    for stats in &result.moves {
+        println!(
+            "{}: {} visits, win rate {:.3}",
+            stats.r#move.notation(size),
+            stats.visits,
+            stats.win_rate
+        );
+    }
+    if let Some(best_move) = result.best_move() {
+        println!("Best move: {}", best_move.notation(size));
+    }
+}
+
+/// Compare the evaluations of two files, which must have been computed for the same game.
+fn compare_files(first: &str, second: &str) {
+    let first = load_eval(first).unwrap();
+    let second = load_eval(second).unwrap();
+    if (first.hash_version, first.rules, first.color_swap)
+        != (second.hash_version, second.rules, second.color_swap)
+    {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "The files were computed under different rules or hashing schemes",
+            )
+            .exit()
+    }
+    let comparison = compare(&first.evaluations, &second.evaluations);
+    println!("Same evaluation: {}", comparison.same);
+    println!("Different winner: {}", comparison.different_winner);
+    println!(
+        "Different moves to win/loss: {}",
+        comparison.different_moves
+    );
+    println!("Only in the first file: {}", comparison.only_first);
+    println!("Only in the second file: {}", comparison.only_second);
 }
 
 /// Filter computed evaluations from `file` to retain positions that take long to win (lose), i.e.,
@@ -97,9 +483,14 @@ fn eval(file: &str, p1_cubies: u8, p2_cubies: u8) {
 fn filter(file: &str, out_file: &str, min_moves_to_wl: isize) {
     let eval = load_eval(file).unwrap();
     let filtered: HashMap<u64, Evaluation> = eval
+        .evaluations
         .into_iter()
         .filter(|(_k, v)| v.moves_to_wl >= min_moves_to_wl)
         .collect();
     println!("Filtered number of states: {}", filtered.len());
-    save_eval(&filtered, out_file).unwrap();
+    let eval_file = EvalFile {
+        evaluations: filtered,
+        ..eval
+    };
+    save_eval(&eval_file, out_file).unwrap();
 }
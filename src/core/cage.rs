@@ -1,34 +1,102 @@
 use crate::core::{
-    cubie::Cubie,
-    line::{Line, SLOT_TO_LINES},
+    cubie::{Cubie, NUM_COLORS},
+    error::GameError,
+    line::{Line, Slot, slot_bit},
+    r#move::{Move, Rotation},
+    shape::{MAX_SIZE, Shape},
 };
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, str::FromStr};
+use std::{cmp::Ordering, str::FromStr};
 
-#[derive(
-    Clone, Copy, Debug, Hash, Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize, Encode, Decode,
-)]
+/// Bit z = 0 of every column.
+pub(crate) const BOTTOM: u64 = 0x1111_1111_1111_1111;
+
+/// Cubies are kept in one bitboard per color. Each column of the cage takes `MAX_SIZE`
+/// consecutive bits from the bottom up, and the columns are ordered by x, then y, so the bits
+/// of a board are ordered like the slots of a `[x][y][z]` grid (see `slot_bit`).
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Serialize, Deserialize, Encode, Decode)]
 pub struct Cage {
-    pub grid: [[[Option<Cubie>; 3]; 3]; 3],
+    /// Slots holding each color, indexed by `Cubie as usize`. Slots outside of the shape are
+    /// always empty.
+    pub(crate) colors: [u64; NUM_COLORS],
+    shape: Shape,
 }
 
 impl Cage {
     pub fn new() -> Self {
+        Self::with_shape(Shape::STANDARD)
+    }
+
+    pub fn with_shape(shape: Shape) -> Self {
         Self {
-            grid: [[[None; 3]; 3]; 3],
+            colors: [0; NUM_COLORS],
+            shape,
+        }
+    }
+
+    pub fn shape(&self) -> Shape {
+        self.shape
+    }
+
+    pub fn size(&self) -> usize {
+        self.shape.size()
+    }
+
+    pub fn get(&self, slot: Slot) -> Option<Cubie> {
+        self.cubie_at(slot_bit(slot))
+    }
+
+    pub fn set(&mut self, slot: Slot, cubie: Option<Cubie>) {
+        let bit = slot_bit(slot);
+        for board in self.colors.iter_mut() {
+            *board &= !bit;
+        }
+        if let Some(cubie) = cubie {
+            self.colors[cubie as usize] |= bit;
         }
     }
 
+    fn cubie_at(&self, bit: u64) -> Option<Cubie> {
+        Cubie::ALL
+            .into_iter()
+            .find(|&cubie| self.colors[cubie as usize] & bit != 0)
+    }
+
+    /// Slots holding a cubie of any color.
+    pub(crate) fn occupied(&self) -> u64 {
+        self.colors
+            .iter()
+            .fold(0, |occupied, board| occupied | board)
+    }
+
+    /// Applies the same slot permutation to the boards of all colors.
+    pub(crate) fn map_boards(&mut self, f: impl Fn(u64) -> u64) {
+        for board in self.colors.iter_mut() {
+            *board = f(*board);
+        }
+    }
+
+    /// Whether column (x, y) can't hold cubies.
+    pub fn is_blocked(&self, x: usize, y: usize) -> bool {
+        self.shape.is_blocked(x, y)
+    }
+
+    /// Number of slots available for cubies.
+    pub fn num_slots(&self) -> usize {
+        self.shape.num_slots()
+    }
+
     pub fn draw(&self) {
-        for z in (0..3).rev() {
-            for y in 0..3 {
-                print!("{}", " ".repeat(3 - y));
-                for x in 0..3 {
-                    let cubie = self.grid[x][y][z];
+        let n = self.size();
+        for z in (0..n).rev() {
+            for y in 0..n {
+                print!("{}", " ".repeat(n - y));
+                for x in 0..n {
+                    let cubie = self.get([x, y, z]);
                     match cubie {
                         Some(c) => c.draw(),
-                        None => print!("{}", "▯"),
+                        None => print!("▯"),
                     };
                 }
                 println!();
@@ -36,129 +104,412 @@ impl Cage {
         }
     }
 
-    /// Checks if there are 3 same color cubies in a row, column, or diagonal. Center column is not
-    /// available!
-    pub fn has_line(&self) -> Option<(Cubie, Line)> {
-        // TODO: use incrementally
-        let mut counts: HashMap<(Line, Cubie), u8> = HashMap::new();
-
-        for (slot, lines) in SLOT_TO_LINES.iter() {
-            if let Some(cubie) = self.grid[slot[0]][slot[1]][slot[2]] {
-                for &line in lines {
-                    *counts.entry((line, cubie)).or_insert(0) += 1;
-                    if counts[&(line, cubie)] == 3 {
-                        return Some((cubie, line)); // Found a line with 3 same color cubies
-                    }
+    /// All lines of `line_length` same color cubies in a row, column, or diagonal, by color in
+    /// the order of `Cubie::ALL` and then in the order of the line table. Lines through blocked
+    /// columns don't exist. This scans the whole cage, games keep track of lines incrementally
+    /// with `LineCounts` instead.
+    pub fn completed_lines(&self) -> Vec<(Cubie, Line)> {
+        let line_length = self.shape.line_length() as u32;
+        let mut completed = Vec::new();
+        for cubie in Cubie::ALL {
+            let board = self.colors[cubie as usize];
+            if board.count_ones() < line_length {
+                continue;
+            }
+            for line in self.shape.lines().lines.iter() {
+                if board & line.mask() == line.mask() {
+                    completed.push((cubie, *line));
                 }
             }
         }
+        completed
+    }
 
-        None // No lines found
+    /// Checks if there are `line_length` same color cubies in a row, column, or diagonal.
+    pub fn has_line(&self) -> bool {
+        !self.completed_lines().is_empty()
     }
 
     /// This function is only used in normalizing the representation of the cage.
     fn flip_horizontal(&mut self) {
-        self.grid.swap(0, 2);
+        let size = self.size();
+        self.map_boards(|board| reflect_x(board, size));
     }
 
     /// This function is only used in normalizing the representation of the cage.
     fn rotate_cage(&mut self) {
-        let mut new_grid = [[[None; 3]; 3]; 3];
-        for x in 0..3 {
-            for y in 0..3 {
-                for z in 0..3 {
-                    new_grid[y][2 - x][z] = self.grid[x][y][z];
-                }
-            }
-        }
-        self.grid = new_grid;
+        let size = self.size();
+        self.map_boards(|board| reflect_y(transpose(board), size));
     }
 
     /// Symmetric cages have the same game evaluation, normalize to reduce the search space.
     pub fn normalize(&mut self) -> bool {
-        let mut largest = self.grid;
+        self.normalize_symmetry().reflected
+    }
+
+    /// Same as `normalize`, returning the symmetry that was applied to the cage.
+    pub fn normalize_symmetry(&mut self) -> Symmetry {
+        let mut largest = *self;
+        let mut symmetry = Symmetry::default();
 
-        let mut reflection_happened = false;
         for i in 0..2 {
-            for _ in 0..4 {
+            for j in 0..4 {
                 self.rotate_cage();
-                if self.grid > largest {
-                    largest = self.grid;
-                    if i == 1 {
-                        reflection_happened = true;
-                    }
+                if *self > largest {
+                    largest = *self;
+                    symmetry = Symmetry {
+                        reflected: i == 1,
+                        rotations: (j + 1) % 4,
+                    };
                 }
             }
             self.flip_horizontal();
         }
 
-        self.grid = largest;
+        *self = largest;
 
-        reflection_happened
+        symmetry
     }
-}
 
-impl FromStr for Cage {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut cage = Cage::new();
+    /// Parses a cage of the given shape. Layers are listed top to bottom, each layer row by row
+    /// (x fastest), with '.' for empty slots. Characters in blocked columns are ignored.
+    pub fn parse(s: &str, shape: Shape) -> Result<Self, GameError> {
+        let mut cage = Cage::with_shape(shape);
+        let n = shape.size();
         let mut idx = 0;
 
-        for ch in s.chars() {
+        for (position, ch) in s.char_indices() {
             if ch.is_whitespace() || ch == ',' {
                 continue; // Skip human-readable formatting
             }
+            if idx >= n * n * n {
+                return Err(GameError::Parse {
+                    position,
+                    message: format!("Expected {} slots, got more", n * n * n),
+                });
+            }
 
-            let x = idx % 3;
-            let y = (idx / 3) % 3;
-            let z = 2 - (idx / 9); // The cubies are input top (z=2) to bottom (z=0)
+            let x = idx % n;
+            let y = (idx / n) % n;
+            let z = n - 1 - (idx / (n * n)); // The cubies are input top to bottom
+            idx += 1;
 
-            if (x, y) == (1, 1) {
-                // Center column is unavailable on the puzzle
-                idx += 1;
+            if cage.is_blocked(x, y) {
+                // Blocked columns, like the center column, are unavailable on the puzzle
                 continue;
             }
 
             let cubie = match ch {
                 '.' => None,
-                other => Some(Cubie::from_char(other)?),
+                other => Some(Cubie::from_char(other).ok_or_else(|| GameError::Parse {
+                    position,
+                    message: format!("Invalid color char: {}", other),
+                })?),
             };
 
-            cage.grid[x][y][z] = cubie;
-            idx += 1;
+            cage.set([x, y, z], cubie);
         }
 
-        if idx != 27 {
-            return Err(format!(
-                "Expected 27 non-whitespace, non-comma characters, got {}",
-                idx
-            ));
+        if idx != n * n * n {
+            return Err(GameError::Parse {
+                position: s.len(),
+                message: format!("Expected {} slots, got {}", n * n * n, idx),
+            });
         }
 
         Ok(cage)
     }
 }
 
+/// One of the 8 symmetries of a cage: an optional reflection of x, followed by quarter turns of
+/// the whole cage. Slots, moves and lines of a cage map to the transformed cage with the `apply`
+/// functions, and back with those of the `inverse`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Symmetry {
+    /// Whether column (x, y) is moved to (size - 1 - x, y) first.
+    pub reflected: bool,
+    /// Number of quarter turns moving column (x, y) to (y, size - 1 - x).
+    pub rotations: u8,
+}
+
+/// Number of symmetries of a cage, the length of `Symmetry::ALL`.
+pub const NUM_SYMMETRIES: usize = 8;
+
+impl Symmetry {
+    pub const ALL: [Symmetry; NUM_SYMMETRIES] = {
+        let mut all = [Symmetry {
+            reflected: false,
+            rotations: 0,
+        }; NUM_SYMMETRIES];
+        let mut i = 0;
+        while i < NUM_SYMMETRIES {
+            all[i] = Symmetry {
+                reflected: i >= 4,
+                rotations: (i % 4) as u8,
+            };
+            i += 1;
+        }
+        all
+    };
+
+    /// Position of the symmetry in `ALL`.
+    pub fn index(self) -> usize {
+        self.reflected as usize * 4 + self.rotations as usize
+    }
+
+    /// The symmetry that applies `first`, then `self`.
+    pub fn after(self, first: Symmetry) -> Symmetry {
+        // A reflection turns the quarter turns that come before it the other way
+        let first_rotations = if self.reflected {
+            4 - first.rotations
+        } else {
+            first.rotations
+        };
+        Symmetry {
+            reflected: self.reflected != first.reflected,
+            rotations: (self.rotations + first_rotations) % 4,
+        }
+    }
+
+    /// The symmetry that undoes `self`.
+    pub fn inverse(self) -> Symmetry {
+        // Turning back and reflecting is the same as reflecting and turning further, so
+        // reflections are their own inverse
+        Symmetry {
+            reflected: self.reflected,
+            rotations: if self.reflected {
+                self.rotations
+            } else {
+                (4 - self.rotations) % 4
+            },
+        }
+    }
+
+    /// Where the symmetry moves a slot of a cage of the given size.
+    pub fn apply(self, [x, y, z]: Slot, size: usize) -> Slot {
+        let (mut x, mut y) = if self.reflected {
+            (size - 1 - x, y)
+        } else {
+            (x, y)
+        };
+        for _ in 0..self.rotations {
+            (x, y) = (y, size - 1 - x);
+        }
+        [x, y, z]
+    }
+
+    /// The move that has the same effect on the transformed cage as `move` on the original.
+    pub fn apply_to_move(self, r#move: Move, size: usize) -> Move {
+        match r#move {
+            Move::Drop { color, column } => {
+                let [x, y, _] = self.apply([column.0, column.1, 0], size);
+                Move::Drop {
+                    color,
+                    column: (x, y),
+                }
+            }
+            // Turning the cage around the vertical axis doesn't change layer turns, reflecting it
+            // reverses them
+            Move::RotateLayer { layer, rotation } if self.reflected => Move::RotateLayer {
+                layer,
+                rotation: match rotation {
+                    Rotation::Clockwise => Rotation::CounterClockwise,
+                    Rotation::CounterClockwise => Rotation::Clockwise,
+                    Rotation::HalfTurn => Rotation::HalfTurn,
+                },
+            },
+            // Flips around other horizontal axes are the same as the flip up to symmetry
+            other => other,
+        }
+    }
+
+    /// Where the symmetry moves a line of a cage of the given size.
+    pub fn apply_to_line(self, line: &Line, size: usize) -> Line {
+        let mut slots: Vec<Slot> = line
+            .slots()
+            .iter()
+            .map(|&slot| self.apply(slot, size))
+            .collect();
+        slots.sort();
+        Line::new(&slots)
+    }
+}
+
+/// Swaps the bits of `board` selected by `mask` with the bits `shift` places higher.
+fn delta_swap(board: u64, mask: u64, shift: u32) -> u64 {
+    let t = ((board >> shift) ^ board) & mask;
+    board ^ t ^ (t << shift)
+}
+
+/// Moves column (x, y) to (y, x).
+pub(crate) fn transpose(board: u64) -> u64 {
+    let board = delta_swap(board, 0x0000_0000_ff00_ff00, 24);
+    delta_swap(board, 0x0000_f0f0_0000_f0f0, 12)
+}
+
+/// Moves column (x, y) to (size - 1 - x, y).
+pub(crate) fn reflect_x(board: u64, size: usize) -> u64 {
+    let board = board.rotate_left(32);
+    let board = delta_swap(board, 0x0000_ffff_0000_ffff, 16);
+    board >> ((MAX_SIZE - size) * MAX_SIZE * MAX_SIZE)
+}
+
+/// Moves column (x, y) to (x, size - 1 - y).
+pub(crate) fn reflect_y(board: u64, size: usize) -> u64 {
+    let board = delta_swap(board, 0x00ff_00ff_00ff_00ff, 8);
+    let board = delta_swap(board, 0x0f0f_0f0f_0f0f_0f0f, 4);
+    board >> ((MAX_SIZE - size) * MAX_SIZE)
+}
+
+/// Moves slot z of every column to size - 1 - z.
+pub(crate) fn reflect_z(board: u64, size: usize) -> u64 {
+    let board = delta_swap(board, 0x3333_3333_3333_3333, 2);
+    let board = delta_swap(board, 0x5555_5555_5555_5555, 1);
+    board >> (MAX_SIZE - size)
+}
+
+impl Ord for Cage {
+    /// Cages compare like grids of slots in x, y, z order, with empty slots lowest and cubies
+    /// in `Cubie` order.
+    fn cmp(&self, other: &Self) -> Ordering {
+        let diff = self
+            .colors
+            .iter()
+            .zip(other.colors.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if diff == 0 {
+            return self.shape.cmp(&other.shape);
+        }
+        // The first differing slot decides
+        let bit = diff & diff.wrapping_neg();
+        self.cubie_at(bit).cmp(&other.cubie_at(bit))
+    }
+}
+
+impl PartialOrd for Cage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Default for Cage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes the cage in the format of `Cage::parse`, with '#' in blocked columns.
+impl std::fmt::Display for Cage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let n = self.size();
+        for z in (0..n).rev() {
+            if z != n - 1 {
+                write!(f, ",")?;
+            }
+            for y in 0..n {
+                for x in 0..n {
+                    let c = match self.get([x, y, z]) {
+                        _ if self.is_blocked(x, y) => '#',
+                        Some(cubie) => cubie.to_char(),
+                        None => '.',
+                    };
+                    write!(f, "{}", c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cage {
+    type Err = GameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Cage::parse(s, Shape::STANDARD)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::r#move::Layer;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
 
     #[rustfmt::skip]
     #[test]
     fn test_line_detection() {
-        assert!(Cage::from_str("R........,R........,R........").unwrap().has_line().is_some());
-        assert!(Cage::from_str(".........,....Y....,......BBB").unwrap().has_line().is_some());
-        assert!(Cage::from_str(".O......O,W.GWG.W..,R..Y....Y").unwrap().has_line().is_some());
-        assert!(Cage::from_str("R........,...R.....,......R..").unwrap().has_line().is_some());
-        assert!(Cage::from_str("..O......,.....O...,........O").unwrap().has_line().is_some());
-        assert!(Cage::from_str("R........,O........,R........").unwrap().has_line().is_none());
+        assert!(Cage::from_str("R........,R........,R........").unwrap().has_line());
+        assert!(Cage::from_str(".........,....Y....,......BBB").unwrap().has_line());
+        assert!(Cage::from_str(".O......O,W.GWG.W..,R..Y....Y").unwrap().has_line());
+        assert!(Cage::from_str("R........,...R.....,......R..").unwrap().has_line());
+        assert!(Cage::from_str("..O......,.....O...,........O").unwrap().has_line());
+        assert!(!Cage::from_str("R........,O........,R........").unwrap().has_line());
 
         let cage_full = Cage::from_str("WYRBOGGOB,OGBYRYWBG,ROWBYGOWB").unwrap();
-        assert!(cage_full.has_line().is_none());
+        assert!(!cage_full.has_line());
         cage_full.draw();
     }
 
+    #[test]
+    fn test_completed_lines() {
+        let cage = Cage::from_str("RB.......,RB.......,RB.......").unwrap();
+        let colors: Vec<Cubie> = cage
+            .completed_lines()
+            .iter()
+            .map(|&(cubie, _)| cubie)
+            .collect();
+        assert_eq!(colors.len(), 2);
+        assert!(colors.contains(&Cubie::Red) && colors.contains(&Cubie::Blue));
+
+        // Four in a row hold two lines of three
+        let shape = Shape::new(4, 3).unwrap();
+        let empty = "....,....,....,....";
+        let cage = Cage::parse(
+            &format!("{empty},{empty},{empty},RRRR,....,....,...."),
+            shape,
+        );
+        assert_eq!(cage.unwrap().completed_lines().len(), 2);
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_line_detection_open_center() {
+        let open = Shape::STANDARD.open_center();
+        assert!(!Cage::parse(".........,....Y....,.........", open).unwrap().has_line());
+        assert!(Cage::parse("R........,....R....,........R", open).unwrap().has_line());
+        assert!(Cage::parse("....G....,....G....,....G....", open).unwrap().has_line());
+        // Lines through the center column don't count when it is closed
+        let closed = Shape::STANDARD;
+        assert!(!Cage::parse("R........,....R....,........R", closed).unwrap().has_line());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |s: &str| match Cage::from_str(s) {
+            Err(GameError::Parse { position, .. }) => position,
+            other => panic!("Expected a parse error, got {:?}", other),
+        };
+        assert_eq!(error("R........,.X.......,........."), 11);
+        assert_eq!(error("R........,.........,.........R"), 29);
+        assert_eq!(error("R........,........."), 19);
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_line_detection_4x4x4() {
+        let shape = Shape::new(4, 4).unwrap();
+        let empty = "....,....,....,....";
+        let cage = |s: &str| Cage::parse(s, shape).unwrap();
+        assert!(cage(&format!("{empty}, {empty}, {empty}, RRRR,....,....,....")).has_line());
+        assert!(!cage(&format!("{empty}, {empty}, {empty}, RRR.,....,....,....")).has_line());
+        // The center columns are blocked, so the diagonals through them don't count
+        assert!(!cage(&format!("{empty}, {empty}, {empty}, R...,.R..,..R.,...R")).has_line());
+
+        let shape = Shape::new(4, 3).unwrap();
+        let cage = Cage::parse(&format!("{empty}, {empty}, {empty}, .RRR,....,....,...."), shape);
+        assert!(cage.unwrap().has_line());
+    }
+
     #[test]
     fn test_flip_horizontal() {
         let mut cage = Cage::from_str("R........,G........,B........").unwrap();
@@ -185,14 +536,82 @@ mod tests {
         assert_eq!(cage, rotated);
     }
 
+    #[test]
+    fn test_board_symmetries_match_slots() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for shape in [Shape::STANDARD, Shape::new(4, 4).unwrap()] {
+            let n = shape.size();
+            for _ in 0..100 {
+                let mut cage = Cage::with_shape(shape);
+                for (x, y) in shape.columns() {
+                    for z in 0..rng.random_range(0..=n) {
+                        cage.set([x, y, z], Some(Cubie::ALL[rng.random_range(0..6)]));
+                    }
+                }
+
+                let mut rotated = cage;
+                rotated.rotate_cage();
+                let mut flipped = cage;
+                flipped.flip_horizontal();
+                for (x, y) in shape.columns() {
+                    for z in 0..n {
+                        assert_eq!(rotated.get([y, n - 1 - x, z]), cage.get([x, y, z]));
+                        assert_eq!(flipped.get([n - 1 - x, y, z]), cage.get([x, y, z]));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_symmetry_inverse() {
+        let shape = Shape::new(4, 3).unwrap();
+        let n = shape.size();
+        let drop = Move::Drop {
+            color: Cubie::Red,
+            column: (0, 1),
+        };
+        let turn = Move::RotateLayer {
+            layer: Layer::UP,
+            rotation: Rotation::Clockwise,
+        };
+        for symmetry in Symmetry::ALL {
+            let inverse = symmetry.inverse();
+            assert_eq!(inverse.after(symmetry), Symmetry::default());
+            assert_eq!(symmetry.after(inverse), Symmetry::default());
+            for x in 0..n {
+                for y in 0..n {
+                    let slot = [x, y, 1];
+                    assert_eq!(inverse.apply(symmetry.apply(slot, n), n), slot);
+                }
+            }
+            for r#move in [drop, turn, Move::Flip] {
+                assert_eq!(
+                    inverse.apply_to_move(symmetry.apply_to_move(r#move, n), n),
+                    r#move
+                );
+            }
+            for line in shape.lines().lines.iter() {
+                let image = symmetry.apply_to_line(line, n);
+                assert!(shape.lines().lines.contains(&image));
+                assert_eq!(inverse.apply_to_line(&image, n), *line);
+            }
+        }
+    }
+
     #[test]
     fn test_normalize() {
         // we expect lexicographically largest symmetry
         let mut cage = Cage::from_str("........R,........G,.......YB").unwrap();
         println!("Cage: {:?}", cage);
-        cage.normalize();
+        let original = cage;
+        let symmetry = cage.normalize_symmetry();
         println!("Normalized: {:?}", cage);
         let normalized = Cage::from_str("R........,G........,B..Y.....").unwrap();
         assert_eq!(cage, normalized);
+        // The returned symmetry maps the original cubies onto the normalized ones
+        for slot in [[2, 2, 2], [2, 2, 1], [1, 2, 0], [2, 2, 0]] {
+            assert_eq!(cage.get(symmetry.apply(slot, 3)), original.get(slot));
+        }
     }
 }
@@ -14,16 +14,40 @@ pub enum Cubie {
     Green,
 }
 
+/// Number of cubie colors, the length of `Cubie::ALL`.
+pub const NUM_COLORS: usize = 6;
+
 impl Cubie {
-    pub fn from_char(c: char) -> Result<Self, String> {
+    pub const ALL: [Cubie; NUM_COLORS] = [
+        Cubie::White,
+        Cubie::Yellow,
+        Cubie::Red,
+        Cubie::Orange,
+        Cubie::Blue,
+        Cubie::Green,
+    ];
+
+    pub fn from_char(c: char) -> Option<Self> {
         match c {
-            'R' => Ok(Cubie::Red),
-            'G' => Ok(Cubie::Green),
-            'B' => Ok(Cubie::Blue),
-            'Y' => Ok(Cubie::Yellow),
-            'W' => Ok(Cubie::White),
-            'O' => Ok(Cubie::Orange),
-            _ => Err(format!("Invalid color char: {}", c)),
+            'R' => Some(Cubie::Red),
+            'G' => Some(Cubie::Green),
+            'B' => Some(Cubie::Blue),
+            'Y' => Some(Cubie::Yellow),
+            'W' => Some(Cubie::White),
+            'O' => Some(Cubie::Orange),
+            _ => None,
+        }
+    }
+
+    /// Letter of the color, the inverse of `from_char`.
+    pub fn to_char(self) -> char {
+        match self {
+            Cubie::Red => 'R',
+            Cubie::Green => 'G',
+            Cubie::Blue => 'B',
+            Cubie::Yellow => 'Y',
+            Cubie::White => 'W',
+            Cubie::Orange => 'O',
         }
     }
 
@@ -52,3 +76,58 @@ impl std::fmt::Display for Cubie {
         write!(f, "{}", s)
     }
 }
+
+/// A set of cubie colors, e.g. the colors owned by a player.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct CubieSet(u8);
+
+impl CubieSet {
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn single(cubie: Cubie) -> Self {
+        Self(1 << cubie as u8)
+    }
+
+    pub fn insert(&mut self, cubie: Cubie) {
+        self.0 |= 1 << cubie as u8;
+    }
+
+    pub fn contains(self, cubie: Cubie) -> bool {
+        self.0 & (1 << cubie as u8) != 0
+    }
+
+    pub fn union(self, other: CubieSet) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn is_disjoint(self, other: CubieSet) -> bool {
+        self.0 & other.0 == 0
+    }
+
+    pub fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Colors in the set, in the order of `Cubie::ALL`.
+    pub fn iter(self) -> impl Iterator<Item = Cubie> {
+        Cubie::ALL.into_iter().filter(move |&c| self.contains(c))
+    }
+}
+
+impl FromIterator<Cubie> for CubieSet {
+    fn from_iter<I: IntoIterator<Item = Cubie>>(iter: I) -> Self {
+        let mut set = CubieSet::new();
+        for cubie in iter {
+            set.insert(cubie);
+        }
+        set
+    }
+}
@@ -0,0 +1,106 @@
+//! Breadth-first enumeration of the positions reachable in a game, e.g. to size evaluation tables
+//! before searching. Positions are normalized and told apart by their hash, just like the search
+//! stores them, so the number of positions visited is the number of evaluations a full search
+//! computes. Games that are over aren't continued.
+
+use crate::core::{game::GameState, r#move::Move};
+use std::collections::HashSet;
+
+/// How `enumerate` groups positions into layers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layering {
+    /// By the number of moves needed to reach the position.
+    Ply,
+    /// By the number of cubies dropped since the start. Turns and flips keep a position in its
+    /// layer, so only the hashes of one layer need to be kept to tell which positions were seen.
+    CubiesPlaced,
+}
+
+/// Visits every position reachable from `start` once, layer by layer, calling `visit` with the
+/// index of the layer and the normalized position. Returns the number of positions in each layer.
+pub fn enumerate(
+    start: &GameState,
+    layering: Layering,
+    mut visit: impl FnMut(usize, &GameState),
+) -> Vec<usize> {
+    let mut start = start.clone();
+    start.normalize();
+    let mut seen = HashSet::from([start.zobrist_hash]);
+    let mut layer = vec![start];
+    let mut counts = Vec::new();
+
+    while !layer.is_empty() {
+        let mut next = Vec::new();
+        let mut next_seen = HashSet::new();
+        // Under `Layering::CubiesPlaced` the layer grows while it is visited
+        let mut i = 0;
+        while i < layer.len() {
+            visit(counts.len(), &layer[i]);
+            if layer[i].is_over() {
+                i += 1;
+                continue;
+            }
+            let mut state = layer[i].clone();
+            for m in state.legal_moves() {
+                let undo = state.apply_move_normalize(m).unwrap();
+                let (seen, queue) = match (layering, m) {
+                    (Layering::CubiesPlaced, Move::Drop { .. }) => (&mut next_seen, &mut next),
+                    (Layering::CubiesPlaced, _) => (&mut seen, &mut layer),
+                    (Layering::Ply, _) => (&mut seen, &mut next),
+                };
+                if seen.insert(state.zobrist_hash) {
+                    queue.push(state.clone());
+                }
+                state.undo_move(undo);
+            }
+            i += 1;
+        }
+        counts.push(layer.len());
+        layer = next;
+        if layering == Layering::CubiesPlaced {
+            seen = next_seen;
+        }
+    }
+
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::naive::{SearchMode, evaluate};
+
+    #[test]
+    fn test_counts_match_full_search() {
+        for game in [GameState::new(2, 2), GameState::new(3, 0)] {
+            let evaluated = evaluate(&game, SearchMode::Full);
+            for layering in [Layering::Ply, Layering::CubiesPlaced] {
+                let mut visited = HashSet::new();
+                let counts = enumerate(&game, layering, |_, state| {
+                    assert!(visited.insert(state.zobrist_hash));
+                    assert!(evaluated.contains_key(&state.zobrist_hash));
+                });
+                assert_eq!(counts[0] == 1, layering == Layering::Ply);
+                assert_eq!(counts.iter().sum::<usize>(), evaluated.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_layers() {
+        let game = GameState::new(2, 2);
+        let counts = enumerate(&game, Layering::CubiesPlaced, |layer, state| {
+            let cubies: u8 = state.remaining_cubies.iter().sum();
+            assert_eq!(layer, 4 - cubies as usize);
+        });
+        assert_eq!(counts.len(), 5);
+
+        let mut last_layer = 0;
+        enumerate(&game, Layering::Ply, |layer, _| {
+            assert!(layer == last_layer || layer == last_layer + 1);
+            last_layer = layer;
+        });
+        // The first player needs at least 5 moves to win, the game may continue forever
+        assert!(last_layer >= 5);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::core::{
+    cubie::Cubie,
+    r#move::{Layer, Move, Rotation},
+};
+
+/// Why a move can't be applied or a cage can't be parsed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GameError {
+    /// The column has no empty slot left.
+    ColumnFull { column: (usize, usize) },
+    /// The column is blocked, like the center column of the puzzle.
+    BlockedColumn { column: (usize, usize) },
+    /// The column lies outside of the cage.
+    ColumnOutOfRange { column: (usize, usize) },
+    /// The layer lies outside of the cage.
+    LayerOutOfRange { layer: Layer },
+    /// The player to move doesn't own the color.
+    WrongColor { color: Cubie, player: u8 },
+    /// The player to move has dropped all their cubies.
+    NoCubiesLeft { player: u8 },
+    /// The move would undo the opponent's previous move, which the rules forbid.
+    InvertsLastMove { r#move: Move },
+    /// The rule set doesn't have the move, e.g. a half turn without `half_turns`.
+    NotInRules { r#move: Move },
+    /// Somebody already has a line or the move limit has been reached, no more moves can be
+    /// made.
+    GameOver,
+    /// The input isn't a valid cage. `position` is the byte offset of the offending character,
+    /// or the length of the input if it ended early.
+    Parse { position: usize, message: String },
+}
+
+impl GameError {
+    pub(crate) fn parse(position: usize, message: impl Into<String>) -> Self {
+        GameError::Parse {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for GameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameError::ColumnFull { column: (x, y) } => write!(f, "Column {},{} is full", x, y),
+            GameError::BlockedColumn { column: (x, y) } => {
+                write!(f, "Column {},{} is blocked", x, y)
+            }
+            GameError::ColumnOutOfRange { column: (x, y) } => {
+                write!(f, "Column {},{} is outside of the cage", x, y)
+            }
+            GameError::LayerOutOfRange { layer } => {
+                write!(f, "{} is outside of the cage", layer)
+            }
+            GameError::WrongColor { color, player } => {
+                write!(f, "Player {} can't drop {:?}", player + 1, color)
+            }
+            GameError::NoCubiesLeft { player } => {
+                write!(f, "Player {} has no cubies left", player + 1)
+            }
+            GameError::InvertsLastMove { r#move } => {
+                write!(f, "{} would undo the previous move", describe(r#move))
+            }
+            GameError::NotInRules { r#move } => {
+                write!(f, "{} isn't allowed by the rules", describe(r#move))
+            }
+            GameError::GameOver => write!(f, "The game is already over"),
+            GameError::Parse { position, message } => {
+                write!(f, "{} at position {}", message, position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GameError {}
+
+/// The move in words, since its notation depends on the size of the cage.
+fn describe(r#move: &Move) -> String {
+    match r#move {
+        Move::Drop {
+            color,
+            column: (x, y),
+        } => format!("Dropping {:?} into column {},{}", color, x, y),
+        Move::RotateLayer { layer, rotation } => {
+            let rotation = match rotation {
+                Rotation::Clockwise => "clockwise",
+                Rotation::CounterClockwise => "counterclockwise",
+                Rotation::HalfTurn => "by half a turn",
+            };
+            format!("Turning layer {} {}", layer.z(), rotation)
+        }
+        Move::Flip => "The flip".to_string(),
+    }
+}
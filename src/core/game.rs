@@ -1,67 +1,232 @@
 use crate::core::{
-    cage::Cage,
-    cubie::Cubie,
-    line::Line,
+    cage::{Cage, NUM_SYMMETRIES, Symmetry},
+    cubie::{Cubie, CubieSet, NUM_COLORS},
+    error::GameError,
+    line::{Line, LineCounts, bit_slot, slot_bit},
     r#move::{Layer, Move, Rotation},
+    rules::{DoubleLine, RuleSet},
     zobrist,
 };
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 
+/// Every player owns at least one cubie color, so this is also the maximum number of players.
+pub const MAX_PLAYERS: usize = NUM_COLORS;
+
+/// Hashes kept for a position, see `GameState::symmetric_hashes`: one for each symmetry, then as
+/// many with the colors swapped.
+const NUM_HASHES: usize = 2 * NUM_SYMMETRIES;
+
+/// Colors assigned to single color players in turn order.
+pub const PLAYER_COLORS: [Cubie; MAX_PLAYERS] = [
+    Cubie::Blue,
+    Cubie::Red,
+    Cubie::Green,
+    Cubie::Yellow,
+    Cubie::Orange,
+    Cubie::White,
+];
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode)]
 pub struct Player {
-    pub color: Cubie,
+    /// Colors the player can drop. A line of any of them wins the game for the player.
+    pub colors: CubieSet,
     pub id: u8,
 }
 
-// TODO: enable more than 2 players and more than 1 color per player
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode)]
+impl Player {
+    /// Main color of the player, used to represent the player in the UI.
+    pub fn color(&self) -> Cubie {
+        self.colors.iter().next().expect("Player owns no colors")
+    }
+}
+
+/// What `GameState::undo_move` needs to take back a move, returned when the move is applied.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UndoRecord {
+    /// Cubies before the move. Turns and flips can make any number of cubies fall, so it is
+    /// simpler to keep the whole cage than the changes.
+    colors: [u64; NUM_COLORS],
+    last_move: Option<Move>,
+    moves_without_drop: u8,
+    symmetric_hashes: [u64; NUM_HASHES],
+}
+
+/// How a game stands, see `GameState::outcome`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GameOutcome {
+    Ongoing,
+    Win(Player),
+    Draw,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode)]
 pub struct GameState {
     pub cage: Cage,
-    pub players: [Player; 2],
-    pub remaining_cubies: [u8; 2],
+    /// Players in turn order, `players[i].id == i`.
+    pub players: Vec<Player>,
+    /// Cubies left to drop, indexed by player id. Players with several colors share the stock
+    /// between them.
+    pub remaining_cubies: Vec<u8>,
     pub player_to_move: Player,
+    /// Same for all symmetric positions, see `symmetric_hashes`.
     pub zobrist_hash: u64,
     pub last_move: Option<Move>,
+    /// Moves in a row without a drop. Only counted if `rules.move_limit` is set.
+    pub moves_without_drop: u8,
+    pub rules: RuleSet,
+    /// Kept in sync with `cage`, so that `won` doesn't need to scan the cage.
+    line_counts: LineCounts,
+    /// Hashes of the position seen through each symmetry in `Symmetry::ALL`, followed by those of
+    /// the position with the colors swapped if `color_swap` is set. The smallest one in use is
+    /// `zobrist_hash`, so that symmetric positions hash the same without being normalized.
+    symmetric_hashes: [u64; NUM_HASHES],
+    /// Whether positions hash the same as the ones with the colors swapped, see `with_color_swap`.
+    color_swap: bool,
 }
 
 impl GameState {
     pub fn new(p1_cubies: u8, p2_cubies: u8) -> Self {
-        let players = [
-            Player {
-                color: Cubie::Blue,
-                id: 0,
-            },
-            Player {
-                color: Cubie::Red,
-                id: 1,
-            },
-        ];
+        Self::with_cubies(&[p1_cubies, p2_cubies])
+    }
 
-        Self {
-            cage: Cage::new(),
-            players,
-            remaining_cubies: [p1_cubies, p2_cubies],
+    /// Creates a game for `cubies.len()` players, the i-th of which starts with `cubies[i]`
+    /// cubies. Colors are assigned in the order of `PLAYER_COLORS`.
+    pub fn with_cubies(cubies: &[u8]) -> Self {
+        assert!(
+            cubies.len() <= MAX_PLAYERS,
+            "A game has at most {} players",
+            MAX_PLAYERS
+        );
+        let colors: Vec<CubieSet> = PLAYER_COLORS[..cubies.len()]
+            .iter()
+            .map(|&color| CubieSet::single(color))
+            .collect();
+        Self::with_colors(&colors, cubies)
+    }
+
+    /// Creates a game where the i-th player owns `colors[i]` and starts with `cubies[i]` cubies.
+    /// Players may own several colors, e.g. for team games, but no color can be shared.
+    pub fn with_colors(colors: &[CubieSet], cubies: &[u8]) -> Self {
+        assert!(
+            (1..=MAX_PLAYERS).contains(&colors.len()) && colors.len() == cubies.len(),
+            "A game needs 1 to {} players, each with colors and cubies",
+            MAX_PLAYERS
+        );
+        let mut owned = CubieSet::new();
+        for &player_colors in colors {
+            assert!(
+                !player_colors.is_empty() && player_colors.is_disjoint(owned),
+                "Every player needs their own colors"
+            );
+            owned = owned.union(player_colors);
+        }
+        let players: Vec<Player> = colors
+            .iter()
+            .zip(0..colors.len() as u8)
+            .map(|(&colors, id)| Player { colors, id })
+            .collect();
+
+        let cage = Cage::new();
+        let mut game_state = Self {
+            cage,
             player_to_move: players[0],
+            players,
+            remaining_cubies: cubies.to_vec(),
             zobrist_hash: 0,
             last_move: None,
-        }
+            moves_without_drop: 0,
+            rules: RuleSet::default(),
+            line_counts: LineCounts::new(&cage),
+            symmetric_hashes: [0; NUM_HASHES],
+            color_swap: false,
+        };
+        game_state.rebuild_zobrist_hash();
+        game_state
+    }
+
+    /// Switches the game to a rule set variant. Meant to be called on a freshly created game.
+    pub fn with_rules(mut self, rules: RuleSet) -> Self {
+        self.rules = rules;
+        self.with_cage(Cage::with_shape(rules.shape))
+    }
+
+    /// Replaces the cubies in the cage, e.g. to set up a position.
+    pub fn with_cage(mut self, cage: Cage) -> Self {
+        self.cage = cage;
+        self.line_counts = LineCounts::new(&cage);
+        self.rebuild_zobrist_hash();
+        self
+    }
+
+    /// Passes the turn to the player with the given id, e.g. to set up a position.
+    pub fn with_player_to_move(mut self, id: u8) -> Self {
+        self.player_to_move = self.players[id as usize];
+        self.rebuild_zobrist_hash();
+        self
+    }
+
+    /// Sets the move that led to the position, e.g. to set up a position where the rules forbid
+    /// undoing it.
+    pub fn with_last_move(mut self, r#move: Option<Move>) -> Self {
+        self.last_move = r#move;
+        self.rebuild_zobrist_hash();
+        self
+    }
+
+    /// Sets the number of moves made since the last drop, e.g. to set up a position under a move
+    /// limit.
+    pub fn with_moves_without_drop(mut self, moves: u8) -> Self {
+        self.moves_without_drop = moves;
+        self.rebuild_zobrist_hash();
+        self
+    }
+
+    /// Makes a position hash the same as the one where the two players swapped their colors, their
+    /// stocks and the turn. That position is evaluated the same with the winner swapped, so a
+    /// search only needs to evaluate one of them, see `colors_swapped`. Only for two players with
+    /// one color each.
+    pub fn with_color_swap(mut self) -> Self {
+        assert!(
+            self.players.len() == 2 && self.players.iter().all(|p| p.colors.len() == 1),
+            "Colors can only be swapped between two players with one color each"
+        );
+        self.color_swap = true;
+        self.rebuild_zobrist_hash();
+        self
+    }
+
+    /// Whether `zobrist_hash` is the hash of the position with the colors swapped, i.e. whether
+    /// evaluations stored under it have the winner swapped. Always false without `color_swap`.
+    pub fn colors_swapped(&self) -> bool {
+        self.color_swap
+            && self.symmetric_hashes[..NUM_SYMMETRIES]
+                .iter()
+                .all(|&hash| hash > self.zobrist_hash)
+    }
+
+    /// `zobrist_hash` as without `color_swap`: the same for symmetric positions, but not for those
+    /// with the colors swapped.
+    fn spatial_hash(&self) -> u64 {
+        self.symmetric_hashes[..NUM_SYMMETRIES]
+            .iter()
+            .copied()
+            .min()
+            .unwrap()
     }
 
     pub fn legal_moves(&self) -> Vec<Move> {
         let mut moves = Vec::new();
 
-        // Drops into non-full columns by the player to move. Allowed if the player still has
-        // cubies to drop.
+        // Drops of any owned color into non-full columns by the player to move. Allowed if the
+        // player still has cubies to drop.
         if self.remaining_cubies[self.player_to_move.id as usize] > 0 {
-            for x in 0..3 {
-                for y in 0..3 {
-                    if Cage::is_center(x, y) {
-                        continue;
-                    }
-                    if self.cage.grid[x][y][2].is_none() {
+            let top = self.cage.size() - 1;
+            for color in self.player_to_move.colors.iter() {
+                for (x, y) in self.cage.shape().columns() {
+                    if self.cage.get([x, y, top]).is_none() {
                         moves.push(Move::Drop {
-                            color: self.player_to_move.color,
+                            color,
                             column: (x, y),
                         });
                     }
@@ -70,15 +235,24 @@ impl GameState {
         }
 
         // Flip: allowed if not inverting the previous move
-        if self.last_move != Some(Move::Flip) {
+        if self.rules.flips && !self.inverts_last_move(Move::Flip) {
             moves.push(Move::Flip);
         }
 
         // Rotations: allowed if not inverting the previous move
-        for layer in [Layer::Down, Layer::Equator, Layer::Up] {
-            for rotation in [Rotation::Clockwise, Rotation::CounterClockwise] {
+        let rotations: &[Rotation] = if self.rules.half_turns {
+            &[
+                Rotation::Clockwise,
+                Rotation::CounterClockwise,
+                Rotation::HalfTurn,
+            ]
+        } else {
+            &[Rotation::Clockwise, Rotation::CounterClockwise]
+        };
+        for layer in (0..self.cage.size() as u8).map(Layer) {
+            for &rotation in rotations {
                 let r#move = Move::RotateLayer { layer, rotation };
-                if self.last_move != r#move.inverse() {
+                if !self.inverts_last_move(r#move) {
                     moves.push(r#move);
                 }
             }
@@ -87,111 +261,427 @@ impl GameState {
         moves
     }
 
-    fn advance_player_to_move(&mut self) {
-        self.player_to_move = if self.player_to_move.id == 0 {
-            self.players[1]
+    /// Legal moves grouped by the position they lead to up to symmetry, as told by the position
+    /// hash: moves in the same group lead to symmetric positions, like drops into the four
+    /// corners of the empty cage. The groups and the moves in them keep the order of
+    /// `legal_moves`, and the first move of a group represents it. Positions with the colors
+    /// swapped aren't the same for the player to move, so they don't count as symmetric here even
+    /// with `color_swap`.
+    pub fn unique_moves(&self) -> Vec<Vec<Move>> {
+        let mut game = self.clone();
+        let mut groups: Vec<(u64, Vec<Move>)> = Vec::new();
+        for r#move in self.legal_moves() {
+            let undo = game.apply_move_unchecked(r#move);
+            let hash = game.spatial_hash();
+            game.undo_move(undo);
+            match groups
+                .iter_mut()
+                .find(|(group_hash, _)| *group_hash == hash)
+            {
+                Some((_, moves)) => moves.push(r#move),
+                None => groups.push((hash, vec![r#move])),
+            }
+        }
+        groups.into_iter().map(|(_, moves)| moves).collect()
+    }
+
+    /// Whether `move` would undo the opponent's previous move, if the rules forbid that.
+    fn inverts_last_move(&self, r#move: Move) -> bool {
+        self.forbidden_move() == Some(r#move)
+    }
+
+    /// The move that would undo the previous move, if the rules forbid that. This is the only
+    /// way `last_move` influences the game.
+    pub fn forbidden_move(&self) -> Option<Move> {
+        if self.rules.no_undo {
+            self.last_move.and_then(Move::inverse)
         } else {
-            self.players[0]
-        };
+            None
+        }
     }
 
-    pub fn apply_move(&mut self, r#move: Move) -> Result<(), &'static str> {
-        let current_player = self.player_to_move;
-        self.advance_player_to_move();
-        self.last_move = Some(r#move);
+    /// Sets the last move, keeping its part of the hash up to date.
+    fn set_last_move(&mut self, r#move: Option<Move>) {
+        self.toggle_forbidden_move_keys();
+        self.last_move = r#move;
+        self.toggle_forbidden_move_keys();
+    }
+
+    fn advance_player_to_move(&mut self) {
+        let next = (self.player_to_move.id as usize + 1) % self.players.len();
+        self.player_to_move = self.players[next];
+    }
+
+    fn player_to_move_key(&self, id: u8) -> u64 {
+        zobrist::PLAYER_TO_MOVE[self.players.len()][id as usize]
+    }
+
+    /// Key of `player_to_move` for the hashes of the position and of the one with the colors
+    /// swapped.
+    fn player_to_move_keys(&self) -> (u64, u64) {
+        let id = self.player_to_move.id;
+        (self.player_to_move_key(id), self.player_to_move_key(id ^ 1))
+    }
 
+    /// Checks that the player to move can make `move`, i.e. that it is one of `legal_moves` and
+    /// the game isn't over yet.
+    pub fn check_move(&self, r#move: Move) -> Result<(), GameError> {
+        if self.is_over() {
+            return Err(GameError::GameOver);
+        }
+        let player = self.player_to_move.id;
         match r#move {
             Move::Drop { color, column } => {
-                let z = self.cage.drop(color, column)?;
-                self.zobrist_hash ^= zobrist::POS_COLOR[color as usize][column.0][column.1][z];
-                self.zobrist_hash ^= *zobrist::P2_TO_MOVE;
-                self.remaining_cubies[current_player.id as usize] -= 1;
+                if !self.player_to_move.colors.contains(color) {
+                    return Err(GameError::WrongColor { color, player });
+                }
+                if self.remaining_cubies[player as usize] == 0 {
+                    return Err(GameError::NoCubiesLeft { player });
+                }
+                self.cage.drop_height(column)?;
+            }
+            Move::RotateLayer { layer, rotation } => {
+                if layer.z() >= self.cage.size() {
+                    return Err(GameError::LayerOutOfRange { layer });
+                }
+                if rotation == Rotation::HalfTurn && !self.rules.half_turns {
+                    return Err(GameError::NotInRules { r#move });
+                }
             }
             Move::Flip => {
-                self.cage.flip();
-                self.rebuild_zobrist_hash();
+                if !self.rules.flips {
+                    return Err(GameError::NotInRules { r#move });
+                }
             }
+        }
+        if self.inverts_last_move(r#move) {
+            return Err(GameError::InvertsLastMove { r#move });
+        }
+        Ok(())
+    }
+
+    /// Applies a move, returning what is needed to take it back with `undo_move`. The state is
+    /// left untouched if the move isn't legal.
+    pub fn apply_move(&mut self, r#move: Move) -> Result<UndoRecord, GameError> {
+        self.check_move(r#move)?;
+        Ok(self.apply_move_unchecked(r#move))
+    }
+
+    fn apply_move_unchecked(&mut self, r#move: Move) -> UndoRecord {
+        let undo = UndoRecord {
+            colors: self.cage.colors,
+            last_move: self.last_move,
+            moves_without_drop: self.moves_without_drop,
+            symmetric_hashes: self.symmetric_hashes,
+        };
+        let current_player = self.player_to_move;
+        let (current_key, current_swapped_key) = self.player_to_move_keys();
+        self.advance_player_to_move();
+        self.set_last_move(Some(r#move));
+
+        match r#move {
+            Move::Drop { color, column } => self.drop(color, column, current_player),
+            Move::Flip => self.move_cage(|cage| cage.flip()),
             Move::RotateLayer { layer, rotation } => {
-                self.cage.rotate_layer(layer, rotation);
-                self.rebuild_zobrist_hash();
+                self.move_cage(|cage| cage.rotate_layer(layer, rotation))
             }
         }
+        let (key, swapped_key) = self.player_to_move_keys();
+        self.toggle_keys(current_key ^ key, current_swapped_key ^ swapped_key);
+        if self.rules.move_limit.is_some() {
+            let old_key = self.moves_without_drop_key(self.moves_without_drop);
+            self.moves_without_drop = match r#move {
+                Move::Drop { .. } => 0,
+                _ => self.moves_without_drop.saturating_add(1),
+            };
+            self.toggle_key(old_key ^ self.moves_without_drop_key(self.moves_without_drop));
+        }
+        self.update_zobrist_hash();
 
-        Ok(())
+        undo
     }
 
+    /// Takes back the last move applied, normalized or not, restoring the state before it
+    /// exactly. `undo` must be the record returned for that move.
+    pub fn undo_move(&mut self, undo: UndoRecord) {
+        let mut changed = [0; NUM_COLORS];
+        for ((changed, before), after) in changed.iter_mut().zip(undo.colors).zip(self.cage.colors)
+        {
+            *changed = before ^ after;
+        }
+        self.cage.colors = undo.colors;
+        self.line_counts.update(&self.cage, &changed);
+
+        let previous =
+            (self.player_to_move.id as usize + self.players.len() - 1) % self.players.len();
+        self.player_to_move = self.players[previous];
+        if let Some(Move::Drop { .. }) = self.last_move {
+            self.remaining_cubies[previous] += 1;
+        }
+        self.last_move = undo.last_move;
+        self.moves_without_drop = undo.moves_without_drop;
+        self.symmetric_hashes = undo.symmetric_hashes;
+        self.update_zobrist_hash();
+    }
+
+    /// Drops a cubie of `player` and updates the position hash and line counts for it. The drop
+    /// must have been checked already.
+    fn drop(&mut self, color: Cubie, column: (usize, usize), player: Player) {
+        let z = self.cage.drop(color, column).expect("Drop was checked");
+        let bit = slot_bit([column.0, column.1, z]);
+        self.toggle_slot_keys(color, bit);
+        let mut changed = [0; NUM_COLORS];
+        changed[color as usize] = bit;
+        self.line_counts.update(&self.cage, &changed);
+
+        // With the colors swapped, the stock belongs to the other player
+        let id = player.id as usize;
+        let (keys, swapped_keys) = (
+            &zobrist::REMAINING_CUBIES[id],
+            &zobrist::REMAINING_CUBIES[id ^ 1],
+        );
+        let (old, new) = (self.remaining_cubies[id], self.remaining_cubies[id] - 1);
+        self.remaining_cubies[id] = new;
+        self.toggle_keys(
+            keys[old as usize] ^ keys[new as usize],
+            swapped_keys[old as usize] ^ swapped_keys[new as usize],
+        );
+    }
+
+    /// Applies a layer turn or flip to the cage and updates the hash and line counts for the
+    /// slots that changed, i.e. the turned layer and the cubies that fell down afterwards.
+    fn move_cage(&mut self, f: impl FnOnce(&mut Cage)) {
+        let before = self.cage.colors;
+        f(&mut self.cage);
+        let mut changed = [0; NUM_COLORS];
+        for ((changed, before), after) in changed.iter_mut().zip(before).zip(self.cage.colors) {
+            *changed = before ^ after;
+        }
+        for (cubie, &changed) in Cubie::ALL.into_iter().zip(changed.iter()) {
+            self.toggle_slot_keys(cubie, changed);
+        }
+        self.line_counts.update(&self.cage, &changed);
+    }
+
+    /// Completed lines of all colors, see `Cage::completed_lines`.
+    pub fn completed_lines(&self) -> Vec<(Cubie, Line)> {
+        self.line_counts.completed_lines(&self.cage)
+    }
+
+    /// Whether the game has ended: somebody has a line, which ends the game in a win or, if
+    /// several players got lines at once, possibly in a draw, or the move limit has been reached.
+    /// Repetitions need the history of the game, see `outcome`.
+    pub fn is_over(&self) -> bool {
+        self.line_counts.has_line()
+            || self
+                .rules
+                .move_limit
+                .is_some_and(|limit| self.moves_without_drop >= limit)
+    }
+
+    /// How the game stands, given the undo records of the moves that led to it. Besides the
+    /// endings of `is_over`, the game is drawn once the same position occurred three times.
+    /// Symmetric positions count as the same.
+    pub fn outcome(&self, history: &[UndoRecord]) -> GameOutcome {
+        if let Some((winner, _)) = self.won() {
+            return GameOutcome::Win(winner);
+        }
+        // Positions repeat no matter how many moves were made since the last drop. Swapping the
+        // colors makes another position
+        let position_hash = |hashes: &[u64; NUM_HASHES], moves_without_drop| {
+            let key = self.moves_without_drop_key(moves_without_drop);
+            hashes[..NUM_SYMMETRIES]
+                .iter()
+                .map(|hash| hash ^ key)
+                .min()
+                .unwrap()
+        };
+        let hash = position_hash(&self.symmetric_hashes, self.moves_without_drop);
+        let repetitions = history
+            .iter()
+            .filter(|undo| position_hash(&undo.symmetric_hashes, undo.moves_without_drop) == hash)
+            .count();
+        if self.is_over() || repetitions >= 2 {
+            GameOutcome::Draw
+        } else {
+            GameOutcome::Ongoing
+        }
+    }
+
+    /// The player who won, with one of their lines. If a move completed lines of several players
+    /// at once, `rules.double_line` decides who wins, `None` if nobody does.
     pub fn won(&self) -> Option<(Player, Line)> {
-        if let Some((cubie, line)) = self.cage.has_line() {
-            for player in &self.players {
-                if player.color == cubie {
-                    return Some((*player, line));
-                }
+        let lines = self.completed_lines();
+        // The first line of each player who has one
+        let mut winners: Vec<(Player, Line)> = self
+            .players
+            .iter()
+            .filter_map(|player| {
+                let &(_, line) = lines
+                    .iter()
+                    .find(|(cubie, _)| player.colors.contains(*cubie))?;
+                Some((*player, line))
+            })
+            .collect();
+        if winners.len() > 1 {
+            let mover =
+                (self.player_to_move.id as usize + self.players.len() - 1) % self.players.len();
+            match self.rules.double_line {
+                DoubleLine::Draw => return None,
+                DoubleLine::MoverWins => winners.retain(|(player, _)| player.id as usize == mover),
+                DoubleLine::MoverLoses => winners.retain(|(player, _)| player.id as usize != mover),
             }
         }
-        None
-    }
-
-    pub fn normalize(&mut self) {
-        let reflection_happened = self.cage.normalize();
-        if reflection_happened {
-            self.last_move = match self.last_move {
-                Some(Move::Flip) => Some(Move::Flip),
-                Some(Move::Drop { color, column }) => Some(Move::Drop {
-                    color,
-                    column: (2 - column.0, column.1),
-                }),
-                Some(Move::RotateLayer { layer, rotation }) => Some(Move::RotateLayer {
-                    layer,
-                    rotation: match rotation {
-                        Rotation::Clockwise => Rotation::CounterClockwise,
-                        Rotation::CounterClockwise => Rotation::Clockwise,
-                        Rotation::HalfTurn => Rotation::HalfTurn,
-                    },
-                }),
-                None => None,
-            }
+        match winners[..] {
+            [winner] => Some(winner),
+            _ => None,
         }
-        self.rebuild_zobrist_hash();
     }
 
-    pub fn apply_move_normalize(&mut self, r#move: Move) -> Result<(), &'static str> {
-        let current_player = self.player_to_move;
-        self.advance_player_to_move();
-
-        match r#move {
-            Move::Drop { color, column } => {
-                self.cage.drop(color, column)?;
-                self.remaining_cubies[current_player.id as usize] -= 1;
+    /// Moves the cage into its canonical orientation. The hash is the same for all orientations,
+    /// so it doesn't change. Returns the symmetry that was applied: moves, slots and lines of the
+    /// position before map to the normalized one with it, and back with its inverse.
+    ///
+    /// With `color_swap`, the canonical position may be the one with the colors swapped. The
+    /// colors are left as they are, since swapping them would also swap the players; whether
+    /// the hash is that of the swapped position is told by `colors_swapped`.
+    pub fn normalize(&mut self) -> Symmetry {
+        let symmetry = self.cage.normalize_symmetry();
+        if symmetry != Symmetry::default() {
+            self.line_counts.apply_symmetry(&self.cage, symmetry);
+            // Seeing the new position through a symmetry is seeing the old one through the
+            // symmetry after `symmetry`, with or without the colors swapped
+            let old_hashes = self.symmetric_hashes;
+            for (i, other) in Symmetry::ALL.into_iter().enumerate() {
+                let index = other.after(symmetry).index();
+                self.symmetric_hashes[i] = old_hashes[index];
+                self.symmetric_hashes[NUM_SYMMETRIES + i] = old_hashes[NUM_SYMMETRIES + index];
             }
-            Move::Flip => self.cage.flip(),
-            Move::RotateLayer { layer, rotation } => self.cage.rotate_layer(layer, rotation),
         }
+        let size = self.cage.size();
+        self.last_move = self.last_move.map(|m| symmetry.apply_to_move(m, size));
+        symmetry
+    }
 
+    pub fn apply_move_normalize(&mut self, r#move: Move) -> Result<UndoRecord, GameError> {
+        let undo = self.apply_move(r#move)?;
         self.normalize();
 
-        Ok(())
+        Ok(undo)
     }
 
-    fn rebuild_zobrist_hash(&mut self) {
-        self.zobrist_hash = 0;
-        for x in 0..3 {
-            for y in 0..3 {
-                for z in 0..3 {
-                    if let Some(cubie) = self.cage.grid[x][y][z] {
-                        self.zobrist_hash ^= zobrist::POS_COLOR[cubie as usize][x][y][z];
-                    }
+    /// XORs a key that doesn't depend on the orientation of the cage nor on the colors into all
+    /// hashes.
+    fn toggle_key(&mut self, key: u64) {
+        self.toggle_keys(key, key);
+    }
+
+    /// XORs a key that doesn't depend on the orientation of the cage into the hashes, and its
+    /// counterpart for the position with the colors swapped into the hashes of that position.
+    fn toggle_keys(&mut self, key: u64, swapped_key: u64) {
+        let (hashes, swapped_hashes) = self.symmetric_hashes.split_at_mut(NUM_SYMMETRIES);
+        for hash in hashes {
+            *hash ^= key;
+        }
+        if self.color_swap {
+            for hash in swapped_hashes {
+                *hash ^= swapped_key;
+            }
+        }
+    }
+
+    /// XORs the keys of `cubie` in the slots of `bits` into the hashes, for each orientation.
+    fn toggle_slot_keys(&mut self, cubie: Cubie, mut bits: u64) {
+        let size = self.cage.size();
+        let swapped = self.swapped_color(cubie) as usize;
+        let (hashes, swapped_hashes) = self.symmetric_hashes.split_at_mut(NUM_SYMMETRIES);
+        while bits != 0 {
+            let slot = bit_slot(bits.trailing_zeros());
+            for (hash, symmetry) in hashes.iter_mut().zip(Symmetry::ALL) {
+                let [x, y, z] = symmetry.apply(slot, size);
+                *hash ^= zobrist::POS_COLOR[cubie as usize][x][y][z];
+            }
+            if self.color_swap {
+                for (hash, symmetry) in swapped_hashes.iter_mut().zip(Symmetry::ALL) {
+                    let [x, y, z] = symmetry.apply(slot, size);
+                    *hash ^= zobrist::POS_COLOR[swapped][x][y][z];
                 }
             }
+            bits &= bits - 1;
         }
-        if self.player_to_move.id == 1 {
-            self.zobrist_hash ^= *zobrist::P2_TO_MOVE;
+    }
+
+    /// The color of the other player if `cubie` is one of the two colors swapped by
+    /// `color_swap`, otherwise `cubie`.
+    fn swapped_color(&self, cubie: Cubie) -> Cubie {
+        if !self.color_swap {
+            return cubie;
+        }
+        let [first, second] = [self.players[0].color(), self.players[1].color()];
+        match cubie {
+            _ if cubie == first => second,
+            _ if cubie == second => first,
+            _ => cubie,
+        }
+    }
+
+    /// XORs the keys of the forbidden move into the hashes, for each orientation.
+    fn toggle_forbidden_move_keys(&mut self) {
+        let forbidden = self.forbidden_move();
+        let size = self.cage.size();
+        for (i, symmetry) in Symmetry::ALL.into_iter().enumerate() {
+            let key =
+                zobrist::forbidden_move_key(forbidden.map(|m| symmetry.apply_to_move(m, size)));
+            self.symmetric_hashes[i] ^= key;
+            self.symmetric_hashes[NUM_SYMMETRIES + i] ^= key;
+        }
+    }
+
+    /// Key of the number of moves since the last drop, which only matters under a move limit.
+    fn moves_without_drop_key(&self, moves: u8) -> u64 {
+        match self.rules.move_limit {
+            Some(_) => zobrist::MOVES_WITHOUT_DROP.0[moves as usize],
+            None => 0,
         }
     }
+
+    fn update_zobrist_hash(&mut self) {
+        let in_use = if self.color_swap {
+            NUM_HASHES
+        } else {
+            NUM_SYMMETRIES
+        };
+        self.zobrist_hash = *self.symmetric_hashes[..in_use].iter().min().unwrap();
+    }
+
+    fn rebuild_zobrist_hash(&mut self) {
+        self.symmetric_hashes = [0; NUM_HASHES];
+        let (player_key, swapped_player_key) = self.player_to_move_keys();
+        self.toggle_keys(player_key, swapped_player_key);
+        for id in 0..self.remaining_cubies.len() {
+            let cubies = self.remaining_cubies[id] as usize;
+            self.toggle_keys(
+                zobrist::REMAINING_CUBIES[id][cubies],
+                zobrist::REMAINING_CUBIES[id ^ 1][cubies],
+            );
+        }
+        self.toggle_key(zobrist::rules_key(&self.rules));
+        self.toggle_key(self.moves_without_drop_key(self.moves_without_drop));
+
+        for (cubie, board) in Cubie::ALL.into_iter().zip(self.cage.colors) {
+            self.toggle_slot_keys(cubie, board);
+        }
+        self.toggle_forbidden_move_keys();
+        self.update_zobrist_hash();
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::shape::Shape;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::str::FromStr;
 
     #[test]
     fn test_legal_moves_initial_state() {
@@ -200,17 +690,72 @@ mod tests {
         assert!(legal_moves.len() == 15);
     }
 
+    #[test]
+    fn test_unique_moves() {
+        // Corner and edge drops, each layer turned either way, and the flip
+        let game = GameState::new(4, 4);
+        let groups = game.unique_moves();
+        assert_eq!(groups.len(), 6);
+        assert_eq!(groups.iter().map(Vec::len).sum::<usize>(), 15);
+
+        let mut rng = StdRng::seed_from_u64(16);
+        let mut game = GameState::with_cubies(&[6, 6, 6]);
+        for _ in 0..30 {
+            if game.is_over() {
+                break;
+            }
+            let groups = game.unique_moves();
+            let mut moves: Vec<Move> = groups.iter().flatten().copied().collect();
+            moves.sort_by_key(|m| game.legal_moves().iter().position(|legal| legal == m));
+            assert_eq!(moves, game.legal_moves());
+
+            // Moves of a group lead to the same normalized cage
+            let child = |r#move| {
+                let mut child = game.clone();
+                child.apply_move_normalize(r#move).unwrap();
+                child.cage
+            };
+            let representatives: Vec<Cage> = groups.iter().map(|group| child(group[0])).collect();
+            for (group, representative) in groups.iter().zip(&representatives) {
+                for &r#move in group {
+                    assert_eq!(child(r#move), *representative);
+                }
+            }
+
+            let moves = game.legal_moves();
+            game.apply_move(moves[rng.random_range(0..moves.len())])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unique_moves_with_color_swap() {
+        let mut rng = StdRng::seed_from_u64(18);
+        let mut game = GameState::new(6, 6);
+        let mut swapped_game = game.clone().with_color_swap();
+        for _ in 0..30 {
+            if game.is_over() {
+                break;
+            }
+            assert_eq!(swapped_game.unique_moves(), game.unique_moves());
+            let moves = game.legal_moves();
+            let r#move = moves[rng.random_range(0..moves.len())];
+            game.apply_move(r#move).unwrap();
+            swapped_game.apply_move(r#move).unwrap();
+        }
+    }
+
     #[test]
     fn test_full_column_drop_illegal() {
         let mut game = GameState::new(4, 4);
         for _ in 0..3 {
-            game.cage.drop(game.player_to_move.color, (0, 0)).unwrap();
+            game.cage.drop(game.player_to_move.color(), (0, 0)).unwrap();
         }
 
         let legal_moves = game.legal_moves();
         assert!(legal_moves.len() == 14);
         assert!(!legal_moves.contains(&Move::Drop {
-            color: game.player_to_move.color,
+            color: game.player_to_move.color(),
             column: (0, 0)
         }));
     }
@@ -219,23 +764,119 @@ mod tests {
     fn test_out_of_turn_drop_illegal() {
         let mut game = GameState::new(4, 4);
         game.apply_move(Move::Drop {
-            color: game.players[0].color,
+            color: game.players[0].color(),
             column: (1, 2),
         })
         .unwrap();
 
         // Now it's player 1's turn, so player 0 cannot drop a cubie
         assert!(!game.legal_moves().contains(&Move::Drop {
-            color: game.players[0].color,
+            color: game.players[0].color(),
             column: (1, 2)
         }));
         // Player 1 can
         assert!(game.legal_moves().contains(&Move::Drop {
-            color: game.players[1].color,
+            color: game.players[1].color(),
             column: (1, 2)
         }));
     }
 
+    #[test]
+    fn test_illegal_moves_rejected() {
+        let mut game = GameState::new(1, 4)
+            .with_cage(Cage::from_str(".........,B........,B........").unwrap());
+        let blue = game.players[0].color();
+        let red = game.players[1].color();
+        let drop = |color, column| Move::Drop { color, column };
+        let turn = |layer, rotation| Move::RotateLayer { layer, rotation };
+
+        let before = game.clone();
+        let errors = [
+            (
+                drop(red, (0, 1)),
+                GameError::WrongColor {
+                    color: red,
+                    player: 0,
+                },
+            ),
+            (
+                drop(blue, (1, 1)),
+                GameError::BlockedColumn { column: (1, 1) },
+            ),
+            (
+                drop(blue, (3, 0)),
+                GameError::ColumnOutOfRange { column: (3, 0) },
+            ),
+            (
+                turn(Layer(3), Rotation::Clockwise),
+                GameError::LayerOutOfRange { layer: Layer(3) },
+            ),
+            (
+                turn(Layer::UP, Rotation::HalfTurn),
+                GameError::NotInRules {
+                    r#move: turn(Layer::UP, Rotation::HalfTurn),
+                },
+            ),
+        ];
+        for (m, error) in errors {
+            assert_eq!(game.apply_move(m), Err(error));
+            assert_eq!(game, before);
+        }
+
+        game.apply_move(drop(blue, (0, 1))).unwrap();
+        game.apply_move(Move::Flip).unwrap();
+        assert_eq!(
+            game.apply_move(drop(blue, (0, 2))),
+            Err(GameError::NoCubiesLeft { player: 0 })
+        );
+        assert_eq!(
+            game.apply_move(Move::Flip),
+            Err(GameError::InvertsLastMove { r#move: Move::Flip })
+        );
+
+        let mut game = before;
+        game.apply_move(drop(blue, (0, 0))).unwrap();
+        assert!(game.won().is_some());
+        assert_eq!(game.apply_move(drop(red, (0, 1))), Err(GameError::GameOver));
+        let mut full = GameState::new(1, 1)
+            .with_cage(Cage::from_str("B........,R........,B........").unwrap());
+        assert_eq!(
+            full.apply_move(drop(blue, (0, 0))),
+            Err(GameError::ColumnFull { column: (0, 0) })
+        );
+    }
+
+    #[test]
+    fn test_legal_moves_round_trip_through_notation() {
+        let mut rng = StdRng::seed_from_u64(11);
+        for shape in [Shape::STANDARD, Shape::new(4, 3).unwrap()] {
+            let rules = RuleSet {
+                half_turns: true,
+                shape,
+                ..RuleSet::default()
+            };
+            // The second player owns all other colors, so that drops of every color come up
+            let mut others = CubieSet::new();
+            for cubie in Cubie::ALL.into_iter().filter(|&c| c != Cubie::Blue) {
+                others.insert(cubie);
+            }
+            let colors = [CubieSet::single(Cubie::Blue), others];
+            let mut game = GameState::with_colors(&colors, &[10, 10]).with_rules(rules);
+            for _ in 0..20 {
+                let moves = game.legal_moves();
+                if moves.is_empty() || game.is_over() {
+                    break;
+                }
+                let size = shape.size();
+                for &m in &moves {
+                    assert_eq!(Move::parse(&m.notation(size).to_string(), size), Ok(m));
+                }
+                game.apply_move(moves[rng.random_range(0..moves.len())])
+                    .unwrap();
+            }
+        }
+    }
+
     #[test]
     fn test_inverting_moves_illegal() {
         let mut game = GameState::new(4, 4);
@@ -243,21 +884,116 @@ mod tests {
         assert!(!game.legal_moves().contains(&Move::Flip));
 
         game.apply_move(Move::RotateLayer {
-            layer: Layer::Down,
+            layer: Layer::DOWN,
             rotation: Rotation::Clockwise,
         })
         .unwrap();
         assert!(!game.legal_moves().contains(&Move::RotateLayer {
-            layer: Layer::Down,
+            layer: Layer::DOWN,
             rotation: Rotation::CounterClockwise,
         }));
     }
 
+    #[test]
+    fn test_rule_variants() {
+        let rules = RuleSet {
+            no_undo: false,
+            half_turns: true,
+            flips: false,
+            shape: Shape::STANDARD.open_center(),
+            double_line: DoubleLine::MoverWins,
+            move_limit: Some(30),
+        };
+        let mut game = GameState::new(4, 4).with_rules(rules);
+        // 9 columns, no flip, 3 rotations for each of the 3 layers
+        assert_eq!(game.legal_moves().len(), 18);
+        assert!(game.legal_moves().contains(&Move::Drop {
+            color: game.player_to_move.color(),
+            column: (1, 1)
+        }));
+
+        let half_turn = Move::RotateLayer {
+            layer: Layer::UP,
+            rotation: Rotation::HalfTurn,
+        };
+        game.apply_move(half_turn).unwrap();
+        assert!(game.legal_moves().contains(&half_turn));
+
+        // Rules are part of the hash
+        assert_ne!(
+            GameState::new(4, 4).zobrist_hash,
+            GameState::new(4, 4).with_rules(rules).zobrist_hash
+        );
+        let mover_loses = RuleSet {
+            double_line: DoubleLine::MoverLoses,
+            ..rules
+        };
+        assert_ne!(
+            GameState::new(4, 4).with_rules(rules).zobrist_hash,
+            GameState::new(4, 4).with_rules(mover_loses).zobrist_hash
+        );
+    }
+
+    #[test]
+    fn test_4x4x4_game() {
+        let rules = RuleSet {
+            shape: Shape::new(4, 4).unwrap(),
+            ..RuleSet::default()
+        };
+        let mut game = GameState::new(20, 20).with_rules(rules);
+        // 12 columns, flip, 2 rotations for each of the 4 layers
+        assert_eq!(game.legal_moves().len(), 21);
+
+        let blue = game.players[0].color();
+        let red = game.players[1].color();
+        let drop = |color, column| Move::Drop { color, column };
+        for y in 0..3 {
+            game.apply_move(drop(blue, (0, y))).unwrap();
+            game.apply_move(drop(red, (3, y))).unwrap();
+        }
+        assert!(game.won().is_none());
+        game.apply_move(drop(blue, (0, 3))).unwrap();
+        assert_eq!(game.won().unwrap().0, game.players[0]);
+    }
+
+    #[test]
+    fn test_line_counts_match_full_scan() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let shapes = [
+            Shape::STANDARD,
+            Shape::STANDARD.open_center(),
+            Shape::new(4, 3).unwrap(),
+        ];
+        for shape in shapes {
+            let rules = RuleSet {
+                half_turns: true,
+                shape,
+                ..RuleSet::default()
+            };
+            for normalize in [false, true] {
+                for _ in 0..50 {
+                    let mut game = GameState::with_cubies(&[8, 8, 8]).with_rules(rules);
+                    // Keep playing past wins, lines can be broken up by turns again
+                    for _ in 0..40 {
+                        let moves = game.legal_moves();
+                        game.apply_move_unchecked(moves[rng.random_range(0..moves.len())]);
+                        if normalize {
+                            game.normalize();
+                        }
+                        assert_eq!(game.completed_lines(), game.cage.completed_lines());
+                        assert_eq!(game.is_over(), game.cage.has_line());
+                        assert_eq!(game.line_counts, LineCounts::new(&game.cage));
+                    }
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_no_drops_after_cubies_spent() {
         let mut game = GameState::new(1, 1);
         game.apply_move(Move::Drop {
-            color: game.player_to_move.color,
+            color: game.player_to_move.color(),
             column: (0, 0),
         })
         .unwrap();
@@ -272,11 +1008,183 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_three_player_turn_order() {
+        let mut game = GameState::with_cubies(&[2, 2, 2]);
+        for expected_id in [0, 1, 2, 0] {
+            assert_eq!(game.player_to_move.id, expected_id);
+            let color = game.player_to_move.color();
+            game.apply_move(Move::Drop {
+                color,
+                column: (0, expected_id as usize),
+            })
+            .unwrap();
+        }
+        assert_eq!(game.remaining_cubies, vec![0, 1, 1]);
+
+        let zobrist = game.zobrist_hash;
+        game.rebuild_zobrist_hash();
+        assert_eq!(zobrist, game.zobrist_hash);
+    }
+
+    #[test]
+    fn test_won_by_third_player() {
+        let game = GameState::with_cubies(&[1, 1, 1])
+            .with_cage(Cage::from_str("G........,G........,G........").unwrap());
+        let (winner, _) = game.won().unwrap();
+        assert_eq!(winner.id, 2);
+    }
+
+    #[test]
+    fn test_double_line() {
+        // Red completed both lines with its move, blue is to move
+        let cage = Cage::from_str("RB.......,RB.......,RB.......").unwrap();
+        let outcomes = [
+            (DoubleLine::Draw, None),
+            (DoubleLine::MoverWins, Some(1)),
+            (DoubleLine::MoverLoses, Some(0)),
+        ];
+        for (double_line, winner) in outcomes {
+            let rules = RuleSet {
+                double_line,
+                ..RuleSet::default()
+            };
+            let game = GameState::new(2, 2).with_rules(rules).with_cage(cage);
+            assert_eq!(game.completed_lines().len(), 2);
+            assert!(game.is_over());
+            assert_eq!(game.won().map(|(player, _)| player.id), winner);
+            assert_eq!(game.check_move(Move::Flip), Err(GameError::GameOver));
+        }
+
+        // Only lines of other players: nobody wins if the mover has none
+        let rules = RuleSet {
+            double_line: DoubleLine::MoverWins,
+            ..RuleSet::default()
+        };
+        let game = GameState::with_cubies(&[1, 1, 1])
+            .with_rules(rules)
+            .with_cage(Cage::from_str("BR.......,BR.......,BR.......").unwrap())
+            .with_player_to_move(0);
+        assert!(game.is_over());
+        assert_eq!(game.won(), None);
+    }
+
+    #[test]
+    fn test_threefold_repetition() {
+        let mut game = GameState::new(1, 1);
+        let mut history = Vec::new();
+        let blue = game.players[0].color();
+        history.push(
+            game.apply_move(Move::Drop {
+                color: blue,
+                column: (0, 0),
+            })
+            .unwrap(),
+        );
+        // Turning the empty top layer changes nothing but the player to move, so the positions
+        // after the first, third and fifth turn are the same
+        let up = Move::RotateLayer {
+            layer: Layer::UP,
+            rotation: Rotation::Clockwise,
+        };
+        for _ in 1..=5 {
+            assert_eq!(game.outcome(&history), GameOutcome::Ongoing);
+            history.push(game.apply_move(up).unwrap());
+        }
+        assert_eq!(game.outcome(&history), GameOutcome::Draw);
+
+        // Also under a move limit, which would tell the positions apart by their count
+        let rules = RuleSet {
+            move_limit: Some(20),
+            ..RuleSet::default()
+        };
+        let mut game = GameState::new(1, 1).with_rules(rules);
+        let mut history = Vec::new();
+        history.push(
+            game.apply_move(Move::Drop {
+                color: blue,
+                column: (0, 0),
+            })
+            .unwrap(),
+        );
+        for _ in 1..=5 {
+            assert_eq!(game.outcome(&history), GameOutcome::Ongoing);
+            history.push(game.apply_move(up).unwrap());
+        }
+        assert_eq!(game.outcome(&history), GameOutcome::Draw);
+    }
+
+    #[test]
+    fn test_move_limit() {
+        let rules = RuleSet {
+            move_limit: Some(3),
+            ..RuleSet::default()
+        };
+        let mut game = GameState::new(2, 2).with_rules(rules);
+        let blue = game.players[0].color();
+        let red = game.players[1].color();
+        let turn = |layer| Move::RotateLayer {
+            layer,
+            rotation: Rotation::Clockwise,
+        };
+        game.apply_move(turn(Layer::UP)).unwrap();
+        game.apply_move(turn(Layer::DOWN)).unwrap();
+        assert_eq!(game.moves_without_drop, 2);
+        // Drops reset the count
+        game.apply_move(Move::Drop {
+            color: blue,
+            column: (0, 0),
+        })
+        .unwrap();
+        assert_eq!(game.moves_without_drop, 0);
+
+        let before = game.clone();
+        let mut history = Vec::new();
+        for layer in [Layer::UP, Layer::DOWN, Layer::UP] {
+            assert_eq!(game.outcome(&history), GameOutcome::Ongoing);
+            history.push(game.apply_move(turn(layer)).unwrap());
+        }
+        assert!(game.is_over());
+        assert_eq!(game.outcome(&history), GameOutcome::Draw);
+        let drop = Move::Drop {
+            color: red,
+            column: (0, 1),
+        };
+        assert_eq!(game.apply_move(drop), Err(GameError::GameOver));
+
+        // The count is part of the hash and restored by undoing
+        let hash = game.zobrist_hash;
+        game.rebuild_zobrist_hash();
+        assert_eq!(game.zobrist_hash, hash);
+        for undo in history.into_iter().rev() {
+            game.undo_move(undo);
+        }
+        assert_eq!(game, before);
+    }
+
+    #[test]
+    fn test_team_game() {
+        let blue_green = CubieSet::from_iter([Cubie::Blue, Cubie::Green]);
+        let game = GameState::with_colors(&[blue_green, CubieSet::single(Cubie::Red)], &[4, 4]);
+
+        // 8 columns for each of the two colors, plus 7 layer moves
+        let legal_moves = game.legal_moves();
+        assert_eq!(legal_moves.len(), 23);
+        assert!(legal_moves.contains(&Move::Drop {
+            color: Cubie::Green,
+            column: (0, 0)
+        }));
+
+        let game = game.with_cage(Cage::from_str("G........,G........,G........").unwrap());
+        let (winner, _) = game.won().unwrap();
+        assert_eq!(winner.id, 0);
+    }
+
     #[test]
     fn test_zobrist_single_drop() {
         let mut game = GameState::new(2, 2);
         game.apply_move(Move::Drop {
-            color: game.player_to_move.color,
+            color: game.player_to_move.color(),
             column: (0, 0),
         })
         .unwrap();
@@ -290,24 +1198,29 @@ mod tests {
 
     #[test]
     fn test_zobrist_drop_and_rotate() {
-        let mut game1 = GameState::new(2, 2);
+        // Without the no-undo rule, the last move doesn't matter
+        let rules = RuleSet {
+            no_undo: false,
+            ..RuleSet::default()
+        };
+        let mut game1 = GameState::new(2, 2).with_rules(rules);
         game1
             .apply_move(Move::Drop {
-                color: game1.player_to_move.color,
+                color: game1.player_to_move.color(),
                 column: (0, 0),
             })
             .unwrap();
 
-        let mut game2 = GameState::new(2, 2);
+        let mut game2 = GameState::new(2, 2).with_rules(rules);
         game2
             .apply_move(Move::Drop {
-                color: game2.player_to_move.color,
+                color: game2.player_to_move.color(),
                 column: (2, 0),
             })
             .unwrap();
         game2
             .apply_move(Move::RotateLayer {
-                layer: Layer::Down,
+                layer: Layer::DOWN,
                 rotation: Rotation::CounterClockwise,
             })
             .unwrap();
@@ -318,10 +1231,336 @@ mod tests {
         // Pass the turn to player 1
         game1
             .apply_move(Move::RotateLayer {
-                layer: Layer::Up,
+                layer: Layer::UP,
                 rotation: Rotation::Clockwise,
             })
             .unwrap();
         assert_eq!(game1.zobrist_hash, game2.zobrist_hash);
     }
+
+    #[test]
+    fn test_zobrist_remaining_cubies() {
+        let mut game1 = GameState::new(3, 2);
+        let mut game2 = GameState::new(2, 3);
+        // Same empty cage, same player to move
+        assert_ne!(game1.zobrist_hash, game2.zobrist_hash);
+
+        // Same cage after the drops, but the second player has a cubie more
+        let blue = game1.players[0].color();
+        game1
+            .apply_move(Move::Drop {
+                color: blue,
+                column: (0, 0),
+            })
+            .unwrap();
+        game2
+            .apply_move(Move::Drop {
+                color: blue,
+                column: (0, 0),
+            })
+            .unwrap();
+        assert_eq!(game1.cage, game2.cage);
+        assert_eq!(game1.player_to_move, game2.player_to_move);
+        assert_ne!(game1.zobrist_hash, game2.zobrist_hash);
+
+        let mut game3 = GameState::with_cubies(&[2, 2]);
+        game3
+            .apply_move(Move::Drop {
+                color: blue,
+                column: (0, 0),
+            })
+            .unwrap();
+        assert_ne!(game1.zobrist_hash, game3.zobrist_hash);
+        assert_ne!(game2.zobrist_hash, game3.zobrist_hash);
+    }
+
+    #[test]
+    fn test_zobrist_forbidden_undo() {
+        let turn = |layer, rotation| Move::RotateLayer { layer, rotation };
+        let hash_after = |rules: RuleSet, m: Move| {
+            let mut game = GameState::new(2, 2).with_rules(rules);
+            game.apply_move(m).unwrap();
+            assert_eq!(game.cage, Cage::new());
+            game.zobrist_hash
+        };
+
+        // Turning an empty layer changes nothing but the move the opponent can't play
+        let standard = RuleSet::default();
+        let hashes = [
+            hash_after(standard, turn(Layer::UP, Rotation::Clockwise)),
+            hash_after(standard, turn(Layer::DOWN, Rotation::Clockwise)),
+            hash_after(standard, Move::Flip),
+        ];
+        for i in 0..hashes.len() {
+            for j in 0..i {
+                assert_ne!(hashes[i], hashes[j]);
+            }
+        }
+        // The opposite turn is the same position seen in a mirror
+        assert_eq!(
+            hashes[0],
+            hash_after(standard, turn(Layer::UP, Rotation::CounterClockwise))
+        );
+
+        // Nothing is forbidden if undoing is allowed
+        let undo_allowed = RuleSet {
+            no_undo: false,
+            ..RuleSet::default()
+        };
+        assert_eq!(
+            hash_after(undo_allowed, turn(Layer::UP, Rotation::Clockwise)),
+            hash_after(undo_allowed, Move::Flip)
+        );
+    }
+
+    #[test]
+    fn test_zobrist_incremental_matches_rebuild() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..50 {
+            let mut game = GameState::with_cubies(&[6, 6, 6]);
+            for _ in 0..30 {
+                if game.is_over() {
+                    break;
+                }
+                let moves = game.legal_moves();
+                game.apply_move(moves[rng.random_range(0..moves.len())])
+                    .unwrap();
+                let hash = game.zobrist_hash;
+                game.rebuild_zobrist_hash();
+                assert_eq!(hash, game.zobrist_hash);
+            }
+        }
+    }
+
+    #[test]
+    fn test_zobrist_incremental_matches_rebuild_all_rules() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let shapes = [
+            Shape::STANDARD,
+            Shape::STANDARD.open_center(),
+            Shape::new(4, 3).unwrap(),
+        ];
+        for shape in shapes {
+            for no_undo in [false, true] {
+                let rules = RuleSet {
+                    no_undo,
+                    half_turns: true,
+                    flips: true,
+                    shape,
+                    double_line: DoubleLine::Draw,
+                    move_limit: None,
+                };
+                for _ in 0..20 {
+                    let mut game = GameState::with_cubies(&[8, 8, 8]).with_rules(rules);
+                    for _ in 0..40 {
+                        if game.is_over() {
+                            break;
+                        }
+                        let moves = game.legal_moves();
+                        let m = moves[rng.random_range(0..moves.len())];
+                        let hash = game.zobrist_hash;
+                        if rng.random_bool(0.5) {
+                            game.apply_move_normalize(m).unwrap();
+                        } else {
+                            game.apply_move(m).unwrap();
+                            let before = game.zobrist_hash;
+                            game.normalize();
+                            assert_eq!(before, game.zobrist_hash);
+                        }
+                        assert_ne!(hash, game.zobrist_hash);
+                        let incremental = game.clone();
+                        game.rebuild_zobrist_hash();
+                        assert_eq!(incremental, game);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_zobrist_symmetric_positions() {
+        let mut rng = StdRng::seed_from_u64(9);
+        for _ in 0..50 {
+            let mut game = GameState::with_cubies(&[6, 6]);
+            for _ in 0..rng.random_range(1..12) {
+                if game.is_over() {
+                    break;
+                }
+                let moves = game.legal_moves();
+                game.apply_move(moves[rng.random_range(0..moves.len())])
+                    .unwrap();
+            }
+            let size = game.cage.size();
+            for symmetry in Symmetry::ALL {
+                let mut cage = Cage::new();
+                for x in 0..size {
+                    for y in 0..size {
+                        for z in 0..size {
+                            cage.set(symmetry.apply([x, y, z], size), game.cage.get([x, y, z]));
+                        }
+                    }
+                }
+                let mut image = game.clone();
+                image.last_move = game.last_move.map(|m| symmetry.apply_to_move(m, size));
+                let image = image.with_cage(cage);
+                assert_eq!(game.zobrist_hash, image.zobrist_hash);
+            }
+        }
+    }
+
+    #[test]
+    fn test_zobrist_color_swap() {
+        let mut rng = StdRng::seed_from_u64(12);
+        for _ in 0..50 {
+            let mut game = GameState::new(6, 6).with_color_swap();
+            for _ in 0..rng.random_range(1..12) {
+                if game.is_over() {
+                    break;
+                }
+                let moves = game.legal_moves();
+                game.apply_move_normalize(moves[rng.random_range(0..moves.len())])
+                    .unwrap();
+                let incremental = game.clone();
+                game.rebuild_zobrist_hash();
+                assert_eq!(incremental, game);
+            }
+            let mut cage = game.cage;
+            cage.colors.swap(Cubie::Blue as usize, Cubie::Red as usize);
+            let swapped = GameState::new(game.remaining_cubies[1], game.remaining_cubies[0])
+                .with_cage(cage)
+                .with_player_to_move(game.player_to_move.id ^ 1)
+                .with_last_move(game.last_move)
+                .with_color_swap();
+            assert_eq!(game.zobrist_hash, swapped.zobrist_hash);
+            assert_ne!(game.colors_swapped(), swapped.colors_swapped());
+
+            // Without color swaps, the positions differ
+            let plain = |game: &GameState| {
+                let mut game = game.clone();
+                game.color_swap = false;
+                game.rebuild_zobrist_hash();
+                game
+            };
+            assert_ne!(plain(&game).zobrist_hash, plain(&swapped).zobrist_hash);
+            assert_eq!(
+                plain(&game).zobrist_hash,
+                *game.symmetric_hashes[..NUM_SYMMETRIES]
+                    .iter()
+                    .min()
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_undo_move_restores_state() {
+        let mut rng = StdRng::seed_from_u64(10);
+        for shape in [Shape::STANDARD, Shape::new(4, 3).unwrap()] {
+            let rules = RuleSet {
+                half_turns: true,
+                shape,
+                ..RuleSet::default()
+            };
+            for _ in 0..20 {
+                let mut game = GameState::with_cubies(&[8, 8, 8]).with_rules(rules);
+                let mut history = Vec::new();
+                for _ in 0..30 {
+                    let moves = game.legal_moves();
+                    if moves.is_empty() || game.is_over() {
+                        break;
+                    }
+                    let m = moves[rng.random_range(0..moves.len())];
+                    let undo = if rng.random_bool(0.5) {
+                        game.apply_move_normalize(m).unwrap()
+                    } else {
+                        game.apply_move(m).unwrap()
+                    };
+                    history.push((game.clone(), undo));
+                }
+                // Take everything back, checking each position on the way
+                while let Some((after, undo)) = history.pop() {
+                    assert_eq!(game, after);
+                    game.undo_move(undo);
+                }
+                assert_eq!(game, GameState::with_cubies(&[8, 8, 8]).with_rules(rules));
+            }
+        }
+    }
+
+    #[test]
+    fn test_normalize_maps_moves() {
+        let mut rng = StdRng::seed_from_u64(17);
+        let rules = RuleSet {
+            half_turns: true,
+            ..RuleSet::default()
+        };
+        for _ in 0..50 {
+            let mut game = GameState::new(6, 6).with_rules(rules);
+            for _ in 0..rng.random_range(1..12) {
+                if game.is_over() {
+                    break;
+                }
+                let moves = game.legal_moves();
+                game.apply_move(moves[rng.random_range(0..moves.len())])
+                    .unwrap();
+            }
+            let mut normalized = game.clone();
+            let symmetry = normalized.normalize();
+            let size = game.cage.size();
+            assert_eq!(
+                normalized.last_move,
+                game.last_move.map(|m| symmetry.apply_to_move(m, size))
+            );
+            for (cubie, line) in game.completed_lines() {
+                let image = symmetry.apply_to_line(&line, size);
+                assert!(normalized.completed_lines().contains(&(cubie, image)));
+            }
+
+            // A move of the normalized position shown on the real board has the same effect
+            for r#move in normalized.legal_moves() {
+                let real = symmetry.inverse().apply_to_move(r#move, size);
+                let mut child = game.clone();
+                child.apply_move(real).unwrap();
+                let mut normalized_child = normalized.clone();
+                normalized_child.apply_move(r#move).unwrap();
+                assert_eq!(child.zobrist_hash, normalized_child.zobrist_hash);
+                child.normalize();
+                normalized_child.normalize();
+                assert_eq!(child.cage, normalized_child.cage);
+            }
+        }
+    }
+
+    #[test]
+    fn test_normalize_keeps_forbidden_undo() {
+        let mut game = GameState::new(2, 2);
+        let blue = game.players[0].color();
+        game.apply_move_normalize(Move::Drop {
+            color: blue,
+            column: (0, 1),
+        })
+        .unwrap();
+        let turn = Move::RotateLayer {
+            layer: Layer::DOWN,
+            rotation: Rotation::Clockwise,
+        };
+        game.apply_move_normalize(turn).unwrap();
+        // The turn is undone by the opposite turn, in whatever orientation the cage ended up
+        let forbidden = game.forbidden_move().unwrap();
+        assert_eq!(
+            game.apply_move(forbidden),
+            Err(GameError::InvertsLastMove { r#move: forbidden })
+        );
+        let mut undone = game.clone();
+        undone.apply_move_unchecked(forbidden);
+        undone.normalize();
+        let mut before = GameState::new(2, 2);
+        before
+            .apply_move_normalize(Move::Drop {
+                color: blue,
+                column: (0, 1),
+            })
+            .unwrap();
+        assert_eq!(undone.cage, before.cage);
+    }
 }
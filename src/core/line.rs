@@ -1,54 +1,245 @@
-use std::{collections::HashMap, sync::LazyLock};
+use crate::core::{
+    cage::{Cage, NUM_SYMMETRIES, Symmetry},
+    cubie::{Cubie, NUM_COLORS},
+    shape::{MAX_SIZE, Shape},
+};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
 
 pub type Slot = [usize; 3];
-pub type Line = [Slot; 3];
-
-// TODO: macro?
-pub const LINES: [Line; 28] = [
-    // Horizontal lines
-    [[0, 0, 0], [1, 0, 0], [2, 0, 0]],
-    [[0, 0, 0], [0, 1, 0], [0, 2, 0]],
-    [[2, 0, 0], [2, 1, 0], [2, 2, 0]],
-    [[0, 2, 0], [1, 2, 0], [2, 2, 0]],
-    [[0, 0, 1], [1, 0, 1], [2, 0, 1]],
-    [[0, 0, 1], [0, 1, 1], [0, 2, 1]],
-    [[2, 0, 1], [2, 1, 1], [2, 2, 1]],
-    [[0, 2, 1], [1, 2, 1], [2, 2, 1]],
-    [[0, 0, 2], [1, 0, 2], [2, 0, 2]],
-    [[0, 0, 2], [0, 1, 2], [0, 2, 2]],
-    [[2, 0, 2], [2, 1, 2], [2, 2, 2]],
-    [[0, 2, 2], [1, 2, 2], [2, 2, 2]],
-    // Vertical lines
-    [[0, 0, 0], [0, 0, 1], [0, 0, 2]],
-    [[1, 0, 0], [1, 0, 1], [1, 0, 2]],
-    [[2, 0, 0], [2, 0, 1], [2, 0, 2]],
-    [[0, 1, 0], [0, 1, 1], [0, 1, 2]],
-    [[2, 1, 0], [2, 1, 1], [2, 1, 2]],
-    [[0, 2, 0], [0, 2, 1], [0, 2, 2]],
-    [[1, 2, 0], [1, 2, 1], [1, 2, 2]],
-    [[2, 2, 0], [2, 2, 1], [2, 2, 2]],
-    // Diagonal lines
-    [[0, 0, 0], [0, 1, 1], [0, 2, 2]],
-    [[0, 0, 0], [1, 0, 1], [2, 0, 2]],
-    [[0, 2, 0], [0, 1, 1], [0, 0, 2]],
-    [[0, 2, 0], [1, 2, 1], [2, 2, 2]],
-    [[2, 0, 0], [2, 1, 1], [2, 2, 2]],
-    [[2, 0, 0], [1, 0, 1], [0, 0, 2]],
-    [[2, 2, 0], [2, 1, 1], [2, 0, 2]],
-    [[2, 2, 0], [1, 2, 1], [0, 2, 2]],
-];
-
-pub static SLOT_TO_LINES: LazyLock<HashMap<Slot, Vec<Line>>> = LazyLock::new(|| {
-    let mut slot_to_lines: HashMap<Slot, Vec<Line>> = HashMap::new();
-
-    for line in LINES.iter() {
-        for &slot in line {
-            slot_to_lines.entry(slot).or_default().push(*line);
+
+/// Bit of a slot in the bitboards of a cage.
+pub fn slot_bit([x, y, z]: Slot) -> u64 {
+    1 << ((x * MAX_SIZE + y) * MAX_SIZE + z)
+}
+
+/// Slot of a bit index in the bitboards of a cage, the inverse of `slot_bit`.
+pub fn bit_slot(index: u32) -> Slot {
+    let index = index as usize;
+    [
+        index / (MAX_SIZE * MAX_SIZE),
+        (index / MAX_SIZE) % MAX_SIZE,
+        index % MAX_SIZE,
+    ]
+}
+
+/// Slots of a winning line, in increasing order.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Line {
+    slots: [Slot; MAX_SIZE],
+    len: u8,
+    mask: u64,
+}
+
+impl Line {
+    pub fn new(slots: &[Slot]) -> Self {
+        let mut line = Line {
+            slots: [[0; 3]; MAX_SIZE],
+            len: slots.len() as u8,
+            mask: slots.iter().fold(0, |mask, &slot| mask | slot_bit(slot)),
+        };
+        line.slots[..slots.len()].copy_from_slice(slots);
+        line
+    }
+
+    /// Bits of the slots of the line, see `slot_bit`.
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    pub fn slots(&self) -> &[Slot] {
+        &self.slots[..self.len as usize]
+    }
+
+    pub fn contains(&self, slot: &Slot) -> bool {
+        self.slots().contains(slot)
+    }
+}
+
+/// Winning lines of a cage shape.
+pub struct LineTable {
+    pub lines: Vec<Line>,
+    pub slot_to_lines: HashMap<Slot, Vec<Line>>,
+    /// Positions of the lines through each slot in `lines`, indexed by the bit of the slot.
+    slot_line_indices: Vec<Vec<usize>>,
+    /// For each symmetry in `Symmetry::ALL`, the position of the image of each line.
+    symmetric_lines: [Vec<usize>; NUM_SYMMETRIES],
+}
+
+impl LineTable {
+    fn new(shape: Shape) -> Self {
+        let size = shape.size() as isize;
+        let length = shape.line_length() as isize;
+        let mut lines = Vec::new();
+
+        // Directions with the first nonzero coordinate positive, so each line is found only once
+        let directions = (0..27)
+            .map(|i| [i / 9 - 1, (i / 3) % 3 - 1, i % 3 - 1])
+            .filter(|d: &[isize; 3]| d.iter().find(|&&c| c != 0).is_some_and(|&c| c > 0));
+        for d in directions {
+            for start in 0..size.pow(3) {
+                let start = [start / (size * size), (start / size) % size, start % size];
+                let slots: Option<Vec<Slot>> = (0..length)
+                    .map(|k| {
+                        let slot = [0, 1, 2].map(|i| start[i] + k * d[i]);
+                        let in_cage = slot.iter().all(|c| (0..size).contains(c));
+                        let slot = slot.map(|c| c as usize);
+                        (in_cage && !shape.is_blocked(slot[0], slot[1])).then_some(slot)
+                    })
+                    .collect();
+                if let Some(slots) = slots {
+                    lines.push(Line::new(&slots));
+                }
+            }
+        }
+
+        let mut slot_to_lines: HashMap<Slot, Vec<Line>> = HashMap::new();
+        for line in lines.iter() {
+            for &slot in line.slots() {
+                slot_to_lines.entry(slot).or_default().push(*line);
+            }
+        }
+
+        let mut slot_line_indices = vec![Vec::new(); MAX_SIZE.pow(3)];
+        for (i, line) in lines.iter().enumerate() {
+            for &slot in line.slots() {
+                slot_line_indices[slot_bit(slot).trailing_zeros() as usize].push(i);
+            }
+        }
+
+        let index: HashMap<Line, usize> = lines.iter().enumerate().map(|(i, l)| (*l, i)).collect();
+        let symmetric_lines = Symmetry::ALL.map(|symmetry| {
+            lines
+                .iter()
+                .map(|line| index[&symmetry.apply_to_line(line, shape.size())])
+                .collect()
+        });
+
+        Self {
+            lines,
+            slot_to_lines,
+            slot_line_indices,
+            symmetric_lines,
+        }
+    }
+}
+
+static STANDARD_LINE_TABLE: LazyLock<LineTable> = LazyLock::new(|| LineTable::new(Shape::STANDARD));
+
+/// Line tables are generated once per shape and live for the rest of the program.
+static LINE_TABLES: LazyLock<Mutex<HashMap<Shape, &'static LineTable>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn line_table(shape: Shape) -> &'static LineTable {
+    // Skip the lock for the common case
+    if shape == Shape::STANDARD {
+        return &STANDARD_LINE_TABLE;
+    }
+    let mut tables = LINE_TABLES.lock().unwrap();
+    tables
+        .entry(shape)
+        .or_insert_with(|| Box::leak(Box::new(LineTable::new(shape))))
+}
+
+/// Number of cubies of each color on each line of a cage, kept up to date move by move so that
+/// completed lines are known without scanning the cage.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct LineCounts {
+    /// Indexed by the position of the line in its `LineTable` and by `Cubie as usize`.
+    counts: Vec<[u8; NUM_COLORS]>,
+    /// Number of lines filled by a single color.
+    completed: usize,
+}
+
+impl LineCounts {
+    pub fn new(cage: &Cage) -> Self {
+        let mut counts = Self {
+            counts: vec![[0; NUM_COLORS]; cage.shape().lines().lines.len()],
+            completed: 0,
+        };
+        counts.recount_all(cage);
+        counts
+    }
+
+    /// Recounts the lines through the slots in `changed[color]` for each color, after these
+    /// slots have been changed in `cage`.
+    pub fn update(&mut self, cage: &Cage, changed: &[u64; NUM_COLORS]) {
+        let line_length = cage.shape().line_length() as u8;
+        let table = cage.shape().lines();
+        for (color, &changed) in changed.iter().enumerate() {
+            let mut bits = changed;
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                // Lines through several changed slots are recounted to the same count again
+                for &i in &table.slot_line_indices[bit] {
+                    let count = &mut self.counts[i][color];
+                    let was_complete = *count == line_length;
+                    *count = (cage.colors[color] & table.lines[i].mask()).count_ones() as u8;
+                    match (was_complete, *count == line_length) {
+                        (false, true) => self.completed += 1,
+                        (true, false) => self.completed -= 1,
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Recounts every line, e.g. after the cage has been moved as a whole.
+    pub fn recount_all(&mut self, cage: &Cage) {
+        let line_length = cage.shape().line_length() as u8;
+        self.completed = 0;
+        for (counts, line) in self
+            .counts
+            .iter_mut()
+            .zip(cage.shape().lines().lines.iter())
+        {
+            for (count, board) in counts.iter_mut().zip(cage.colors) {
+                *count = (board & line.mask()).count_ones() as u8;
+                if *count == line_length {
+                    self.completed += 1;
+                }
+            }
+        }
+    }
+
+    /// Moves the counts along with the lines after `symmetry` has been applied to the cage.
+    pub(crate) fn apply_symmetry(&mut self, cage: &Cage, symmetry: Symmetry) {
+        let images = &cage.shape().lines().symmetric_lines[symmetry.index()];
+        let mut counts = vec![[0; NUM_COLORS]; self.counts.len()];
+        for (&image, &count) in images.iter().zip(self.counts.iter()) {
+            counts[image] = count;
         }
+        self.counts = counts;
     }
 
-    slot_to_lines
-});
+    /// Same as `Cage::has_line`, without scanning.
+    pub fn has_line(&self) -> bool {
+        self.completed > 0
+    }
+
+    /// Same as `Cage::completed_lines`, but only scans the counts if some line is complete.
+    pub fn completed_lines(&self, cage: &Cage) -> Vec<(Cubie, Line)> {
+        if self.completed == 0 {
+            return Vec::new();
+        }
+        let line_length = cage.shape().line_length() as u8;
+        let lines = &cage.shape().lines().lines;
+        Cubie::ALL
+            .into_iter()
+            .flat_map(|cubie| {
+                (0..lines.len())
+                    .filter(move |&i| self.counts[i][cubie as usize] == line_length)
+                    .map(move |i| (cubie, lines[i]))
+            })
+            .collect()
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -57,29 +248,45 @@ mod tests {
 
     #[test]
     fn test_draw_lines() {
-        for line in LINES.iter() {
-            println!("Line {:?}:", line);
+        for line in Shape::STANDARD.lines().lines.iter() {
+            println!("Line {:?}:", line.slots());
 
             let mut cage = Cage::new();
-            for &point in line {
-                cage.grid[point[0]][point[1]][point[2]] = Some(Cubie::Red);
+            for &point in line.slots() {
+                cage.set(point, Some(Cubie::Red));
             }
             cage.draw();
             println!();
         }
     }
 
+    #[test]
+    fn test_line_counts() {
+        assert_eq!(Shape::STANDARD.lines().lines.len(), 28);
+        assert_eq!(Shape::STANDARD.open_center().lines().lines.len(), 49);
+        assert_eq!(
+            Shape::new(4, 4).unwrap().open_center().lines().lines.len(),
+            76
+        );
+        // Lines of 3 in a 4x4x4 cage can start at two positions along each row
+        assert_eq!(
+            Shape::new(4, 3).unwrap().open_center().lines().lines.len(),
+            224
+        );
+    }
+
     #[test]
     fn test_slot_to_lines() {
-        let lines_from_0 = SLOT_TO_LINES.get(&[0, 0, 0]).unwrap();
+        let mut lines_from_0 = Shape::STANDARD.lines().slot_to_lines[&[0, 0, 0]].clone();
+        lines_from_0.sort();
         assert_eq!(
             lines_from_0,
-            &vec![
-                [[0, 0, 0], [1, 0, 0], [2, 0, 0]],
-                [[0, 0, 0], [0, 1, 0], [0, 2, 0]],
-                [[0, 0, 0], [0, 0, 1], [0, 0, 2]],
-                [[0, 0, 0], [0, 1, 1], [0, 2, 2]],
-                [[0, 0, 0], [1, 0, 1], [2, 0, 2]]
+            vec![
+                Line::new(&[[0, 0, 0], [0, 0, 1], [0, 0, 2]]),
+                Line::new(&[[0, 0, 0], [0, 1, 0], [0, 2, 0]]),
+                Line::new(&[[0, 0, 0], [0, 1, 1], [0, 2, 2]]),
+                Line::new(&[[0, 0, 0], [1, 0, 0], [2, 0, 0]]),
+                Line::new(&[[0, 0, 0], [1, 0, 1], [2, 0, 2]]),
             ]
         )
     }
@@ -1,6 +1,13 @@
 pub mod cage;
 pub mod cubie;
+pub mod enumerate;
+pub mod error;
 pub mod game;
 pub mod line;
 pub mod r#move;
+pub mod perft;
+pub mod position;
+pub mod record;
+pub mod rules;
+pub mod shape;
 pub mod zobrist;
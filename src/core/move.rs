@@ -1,26 +1,73 @@
 #![allow(dead_code)]
-use crate::core::{cage::Cage, cubie::Cubie};
-use serde::{Deserialize, Serialize};
+use crate::core::{
+    cage::{BOTTOM, Cage, reflect_x, reflect_y, reflect_z, transpose},
+    cubie::Cubie,
+    error::GameError,
+    line::slot_bit,
+    shape::MAX_SIZE,
+};
 use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::{ops::RangeInclusive, str::CharIndices};
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-#[derive(Serialize, Deserialize, Encode, Decode)]
+/// Direction of a layer turn, as seen from above.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode)]
 pub enum Rotation {
     Clockwise,
     CounterClockwise,
     HalfTurn,
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-#[derive(Serialize, Deserialize, Encode, Decode)]
-pub enum Layer {
-    Down,
-    Equator,
-    Up,
+/// Horizontal layer of the cage, counted from the bottom.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct Layer(pub u8);
+
+impl Layer {
+    /// Layers of the 3x3x3 puzzle, see `top` for other cages.
+    pub const DOWN: Layer = Layer(0);
+    pub const EQUATOR: Layer = Layer(1);
+    pub const UP: Layer = Layer(2);
+
+    /// The top layer of a cage of `size`.
+    pub fn top(size: usize) -> Self {
+        Layer(size as u8 - 1)
+    }
+
+    pub fn z(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Letter of the layer in a cage of `size`: `D` for the bottom, `U` for the top and `E` for
+    /// the middle of odd sized cages. Other layers have none.
+    fn letter(self, size: usize) -> Option<char> {
+        match self.z() {
+            0 => Some('D'),
+            z if z + 1 == size => Some('U'),
+            z if 2 * z + 1 == size => Some('E'),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Layer {}", self.0)
+    }
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-#[derive(Serialize, Deserialize, Encode, Decode)]
+/// A move of a player. Moves are written in a compact notation:
+///
+/// - A drop is the letter of the color followed by the column, x as a letter and y as a number:
+///   `Ra1` drops red into column (0, 0), `Bc2` drops blue into column (2, 1).
+/// - A layer turn is `D` or `U` for the bottom and top layers, `E` for the middle layer of odd
+///   sized cages, or `L` and the height for the other layers, e.g. `L1` in a 4x4x4 cage. On its
+///   own it is a clockwise turn as seen from above, followed by `'` it is counterclockwise and
+///   followed by `2` it is a half turn: `U`, `D'`, `E2`, `L2'`.
+/// - The flip is `F`.
+///
+/// The letters of the layers depend on the size of the cage, see `Move::notation` and
+/// `Move::parse`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode)]
 pub enum Move {
     Drop {
         color: Cubie,
@@ -53,113 +100,191 @@ impl Move {
     }
 }
 
-impl std::fmt::Display for Move {
+/// A move written in the notation of a cage of a given size, see `Move::notation`.
+pub struct Notation {
+    r#move: Move,
+    size: usize,
+}
+
+impl std::fmt::Display for Notation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Move::Drop { column, .. } => write!(f, "Drop at {},{}", column.0, column.1),
+        match &self.r#move {
+            Move::Drop {
+                color,
+                column: (x, y),
+            } => {
+                let x = char::from(b'a' + *x as u8);
+                write!(f, "{}{}{}", color.to_char(), x, y + 1)
+            }
             Move::RotateLayer { layer, rotation } => {
-                let rotation = match rotation {
-                    // TODO: proper fix
-                    Rotation::Clockwise => "CCW",
-                    Rotation::CounterClockwise => "CW",
-                    Rotation::HalfTurn => "HT",
+                match layer.letter(self.size) {
+                    Some(letter) => write!(f, "{}", letter)?,
+                    None => write!(f, "L{}", layer.z())?,
+                }
+                match rotation {
+                    Rotation::Clockwise => Ok(()),
+                    Rotation::CounterClockwise => write!(f, "'"),
+                    Rotation::HalfTurn => write!(f, "2"),
+                }
+            }
+            Move::Flip => write!(f, "F"),
+        }
+    }
+}
+
+impl Move {
+    /// The move in the notation of a cage of `size`.
+    pub fn notation(self, size: usize) -> Notation {
+        Notation { r#move: self, size }
+    }
+
+    /// Parses a move in the notation of a cage of `size`, the inverse of `notation`.
+    pub fn parse(s: &str, size: usize) -> Result<Self, GameError> {
+        let mut chars = s.char_indices();
+        let r#move = match chars.next() {
+            None => return Err(GameError::parse(0, "Expected a move")),
+            Some((_, 'F')) => Move::Flip,
+            Some((_, first @ ('D' | 'E' | 'U' | 'L'))) => {
+                let layer = match first {
+                    'D' => Layer::DOWN,
+                    'U' => Layer::top(size),
+                    'E' if size % 2 == 1 => Layer((size / 2) as u8),
+                    'E' => return Err(GameError::parse(0, "Expected D, U or L in an even cage")),
+                    _ => {
+                        let layer = Layer(read_char(
+                            &mut chars,
+                            s.len(),
+                            '0'..=max_char('0'),
+                            "Expected the layer height",
+                        )?);
+                        // Each layer has a single notation
+                        if let Some(letter) = layer.letter(size) {
+                            return Err(GameError::parse(0, format!("Expected {}", letter)));
+                        }
+                        layer
+                    }
                 };
-                write!(f, "Rotate {:?} {}", layer, rotation)
+                let rotation = match chars.next() {
+                    None => Rotation::Clockwise,
+                    Some((_, '\'')) => Rotation::CounterClockwise,
+                    Some((_, '2')) => Rotation::HalfTurn,
+                    Some((position, _)) => {
+                        return Err(GameError::parse(position, "Expected ' or 2"));
+                    }
+                };
+                Move::RotateLayer { layer, rotation }
+            }
+            Some((_, first)) => {
+                let color = Cubie::from_char(first)
+                    .ok_or_else(|| GameError::parse(0, "Expected a move"))?;
+                let x = read_char(
+                    &mut chars,
+                    s.len(),
+                    'a'..=max_char('a'),
+                    "Expected x as a letter",
+                )?;
+                let y = read_char(
+                    &mut chars,
+                    s.len(),
+                    '1'..=max_char('1'),
+                    "Expected y as a number",
+                )?;
+                Move::Drop {
+                    color,
+                    column: (x as usize, y as usize),
+                }
             }
-            Move::Flip => write!(f, "Flip"),
+        };
+
+        match chars.next() {
+            Some((position, _)) => Err(GameError::parse(position, "Expected the end of the move")),
+            None => Ok(r#move),
         }
     }
 }
 
-impl Cage {
-    pub fn is_center(x: usize, y: usize) -> bool {
-        x == 1 && y == 1
+/// The character for the last coordinate of the largest cages, counting from `first`.
+fn max_char(first: char) -> char {
+    char::from(first as u8 + MAX_SIZE as u8 - 1)
+}
+
+/// Reads the next character of a move of length `len` if it is in `range`, returning its distance
+/// from the start of the range.
+fn read_char(
+    chars: &mut CharIndices,
+    len: usize,
+    range: RangeInclusive<char>,
+    message: &str,
+) -> Result<u8, GameError> {
+    match chars.next() {
+        Some((_, c)) if range.contains(&c) => Ok(c as u8 - *range.start() as u8),
+        Some((position, _)) => Err(GameError::parse(position, message)),
+        None => Err(GameError::parse(len, message)),
     }
+}
 
-    pub fn drop(&mut self, color: Cubie, (x, y): (usize, usize)) -> Result<usize, &'static str> {
-        if Self::is_center(x, y) {
-            return Err("Cannot drop into the center column");
+impl Cage {
+    /// Height at which a cubie dropped into the column would land.
+    pub fn drop_height(&self, (x, y): (usize, usize)) -> Result<usize, GameError> {
+        let size = self.size();
+        let column = (x, y);
+        if x >= size || y >= size {
+            return Err(GameError::ColumnOutOfRange { column });
         }
-        if x >= 3 || y >= 3 {
-            return Err("Invalid column coordinates");
+        if self.is_blocked(x, y) {
+            return Err(GameError::BlockedColumn { column });
         }
 
         // Find lowest empty slot in column (x, y)
-        for z in 0..3 {
-            if self.grid[x][y][z].is_none() {
-                self.grid[x][y][z] = Some(color);
-                return Ok(z);
-            }
+        let bottom = slot_bit([x, y, 0]);
+        let z = (self.occupied() >> bottom.trailing_zeros()).trailing_ones() as usize;
+        if z >= size {
+            return Err(GameError::ColumnFull { column });
         }
+        Ok(z)
+    }
 
-        Err("Column is full")
+    pub fn drop(&mut self, color: Cubie, column: (usize, usize)) -> Result<usize, GameError> {
+        let z = self.drop_height(column)?;
+        self.colors[color as usize] |= slot_bit([column.0, column.1, z]);
+        Ok(z)
     }
 
     pub fn apply_gravity(&mut self) {
-        for x in 0..3 {
-            for y in 0..3 {
-                if Self::is_center(x, y) {
-                    continue;
-                }
-
-                let cubies: Vec<Cubie> = (0..3).filter_map(|z| self.grid[x][y][z]).collect();
-
-                // Refill column from bottom up
-                for z in 0..3 {
-                    self.grid[x][y][z] = cubies.get(z).copied();
-                }
+        loop {
+            let occupied = self.occupied();
+            // Cubies with an empty slot below them move down by one, until nothing moves
+            let falling = occupied & !(occupied << 1) & !BOTTOM;
+            if falling == 0 {
+                break;
             }
+            self.map_boards(|board| {
+                let moving = board & falling;
+                board ^ moving ^ (moving >> 1)
+            });
         }
     }
 
     pub fn rotate_layer(&mut self, layer: Layer, rotation: Rotation) {
-        let z = match layer {
-            Layer::Down => 0,
-            Layer::Equator => 1,
-            Layer::Up => 2,
-        };
+        let size = self.size();
+        let layer_mask = BOTTOM << layer.z();
 
-        let mut rotated = [[None; 3]; 3];
-
-        for x in 0..3 {
-            for y in 0..3 {
-                let val = self.grid[x][y][z];
-                let (rx, ry) = match rotation {
-                    Rotation::Clockwise => (2 - y, x),
-                    Rotation::CounterClockwise => (y, 2 - x),
-                    Rotation::HalfTurn => (2 - x, 2 - y),
-                };
-                rotated[rx][ry] = val;
-            }
-        }
-
-        for x in 0..3 {
-            for y in 0..3 {
-                self.grid[x][y][z] = rotated[x][y];
-            }
-        }
+        self.map_boards(|board| {
+            let rotated = match rotation {
+                Rotation::Clockwise => reflect_y(transpose(board & layer_mask), size),
+                Rotation::CounterClockwise => reflect_x(transpose(board & layer_mask), size),
+                Rotation::HalfTurn => reflect_x(reflect_y(board & layer_mask, size), size),
+            };
+            board & !layer_mask | rotated
+        });
 
         // Apply gravity after rotation
         self.apply_gravity();
     }
 
     pub fn flip(&mut self) {
-        let mut new_grid = [[[None; 3]; 3]; 3];
-
-        for x in 0..3 {
-            for y in 0..3 {
-                for z in 0..3 {
-                    if Self::is_center(x, y) {
-                        continue;
-                    }
-                    let new_y = 2 - y;
-                    let new_z = 2 - z;
-                    new_grid[x][new_y][new_z] = self.grid[x][y][z];
-                }
-            }
-        }
-
-        self.grid = new_grid;
+        let size = self.size();
+        self.map_boards(|board| reflect_z(reflect_y(board, size), size));
         self.apply_gravity();
     }
 }
@@ -167,6 +292,7 @@ impl Cage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::shape::Shape;
     use std::str::FromStr;
 
     #[test]
@@ -190,13 +316,84 @@ mod tests {
     #[test]
     fn test_rotate_layer() {
         let mut cage = Cage::from_str("O........,W........,Y........").unwrap();
-        cage.rotate_layer(Layer::Down, Rotation::Clockwise);
+        cage.rotate_layer(Layer::DOWN, Rotation::Clockwise);
 
-        let expected = Cage::from_str(".........,O........,W.Y......").unwrap();
+        // Clockwise as seen from above, with x going down and y going right
+        let expected = Cage::from_str(".........,O........,W.....Y..").unwrap();
         assert_eq!(cage, expected);
         cage.draw();
     }
 
+    #[test]
+    fn test_rotate_layer_4x4x4() {
+        let shape = Shape::new(4, 4).unwrap();
+        let empty = "....,....,....,....";
+        let cage = |down: &str| Cage::parse(&format!("{empty}, {empty}, {empty}, {down}"), shape);
+
+        let mut rotated = cage("R...,....,....,...B").unwrap();
+        rotated.rotate_layer(Layer::DOWN, Rotation::Clockwise);
+        assert_eq!(rotated, cage("...B,....,....,R...").unwrap());
+
+        rotated.flip();
+        assert_eq!(rotated, cage("R...,....,....,...B").unwrap());
+    }
+
+    #[test]
+    fn test_notation() {
+        let drop = |color, column| Move::Drop { color, column };
+        let turn = |layer, rotation| Move::RotateLayer { layer, rotation };
+        let examples = [
+            ("Ra1", drop(Cubie::Red, (0, 0))),
+            ("Bc2", drop(Cubie::Blue, (2, 1))),
+            ("U", turn(Layer::UP, Rotation::Clockwise)),
+            ("D'", turn(Layer::DOWN, Rotation::CounterClockwise)),
+            ("E2", turn(Layer::EQUATOR, Rotation::HalfTurn)),
+            ("F", Move::Flip),
+        ];
+        for (text, m) in examples {
+            assert_eq!(m.notation(3).to_string(), text);
+            assert_eq!(Move::parse(text, 3), Ok(m));
+        }
+        let m = turn(Layer(2), Rotation::CounterClockwise);
+        assert_eq!(m.notation(4).to_string(), "L2'");
+        assert_eq!(Move::parse("L2'", 4), Ok(m));
+
+        let error_position = |s: &str| match Move::parse(s, 3) {
+            Err(GameError::Parse { position, .. }) => position,
+            other => panic!("Expected a parse error, got {:?}", other),
+        };
+        assert_eq!(error_position(""), 0);
+        assert_eq!(error_position("X"), 0);
+        assert_eq!(error_position("Ra"), 2);
+        assert_eq!(error_position("R1a"), 1);
+        assert_eq!(error_position("U3"), 1);
+        assert_eq!(error_position("F'"), 1);
+        assert_eq!(error_position("Ra12"), 3);
+        // Layers with a letter have no number
+        assert_eq!(error_position("L2"), 0);
+        assert!(Move::parse("E", 4).is_err());
+    }
+
+    #[test]
+    fn test_layer_notation_round_trip() {
+        let turn = |layer, rotation| Move::RotateLayer { layer, rotation };
+        for size in 2..=MAX_SIZE {
+            let top = turn(Layer::top(size), Rotation::Clockwise);
+            assert_eq!(Move::parse("U", size), Ok(top));
+            assert_eq!(top.notation(size).to_string(), "U");
+            for z in 0..MAX_SIZE as u8 {
+                for rotation in [
+                    Rotation::Clockwise,
+                    Rotation::CounterClockwise,
+                    Rotation::HalfTurn,
+                ] {
+                    let m = turn(Layer(z), rotation);
+                    assert_eq!(Move::parse(&m.notation(size).to_string(), size), Ok(m));
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_flip() {
         let mut cage = Cage::from_str("R........,GB.......,YWO......").unwrap();
@@ -0,0 +1,140 @@
+//! Move generation checks in the spirit of chess perft: counting the move sequences of a given
+//! length exercises `legal_moves`, `apply_move`, gravity, `undo_move` and `normalize` together.
+//! The counts don't depend on the orientation of the cage, so they are the same with and without
+//! normalizing, and the known counts in the tests catch changes to any of them.
+
+use crate::core::{
+    game::{GameState, UndoRecord},
+    r#move::Move,
+};
+
+/// Number of move sequences of `depth` moves from `game_state`, i.e. of leaves of the game tree
+/// at that depth. Games that end earlier don't count. With `normalize` the moves are applied with
+/// `apply_move_normalize`, which must give the same count.
+pub fn perft(game_state: &GameState, depth: usize, normalize: bool) -> u64 {
+    let mut game_state = game_state.clone();
+    count_leaves(&mut game_state, depth, normalize)
+}
+
+/// The count of `perft` broken down by the first move, in the order of `legal_moves`.
+pub fn divide(game_state: &GameState, depth: usize, normalize: bool) -> Vec<(Move, u64)> {
+    let mut game_state = game_state.clone();
+    if depth == 0 || game_state.is_over() {
+        return Vec::new();
+    }
+    game_state
+        .legal_moves()
+        .into_iter()
+        .map(|m| {
+            let undo = apply(&mut game_state, m, normalize);
+            let count = count_leaves(&mut game_state, depth - 1, normalize);
+            game_state.undo_move(undo);
+            (m, count)
+        })
+        .collect()
+}
+
+fn count_leaves(game_state: &mut GameState, depth: usize, normalize: bool) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    if game_state.is_over() {
+        return 0;
+    }
+    let moves = game_state.legal_moves();
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+    moves
+        .into_iter()
+        .map(|m| {
+            let undo = apply(game_state, m, normalize);
+            let count = count_leaves(game_state, depth - 1, normalize);
+            game_state.undo_move(undo);
+            count
+        })
+        .sum()
+}
+
+fn apply(game_state: &mut GameState, r#move: Move, normalize: bool) -> UndoRecord {
+    if normalize {
+        game_state.apply_move_normalize(r#move).unwrap()
+    } else {
+        game_state.apply_move(r#move).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{rules::RuleSet, shape::Shape};
+
+    /// Counts for depths 0 to 4, checked with and without normalizing.
+    fn check_counts(game: &GameState, expected: [u64; 5]) {
+        for normalize in [false, true] {
+            let counts: Vec<u64> = (0..5).map(|depth| perft(game, depth, normalize)).collect();
+            assert_eq!(counts, expected, "normalize: {}", normalize);
+        }
+    }
+
+    #[test]
+    fn test_perft_standard() {
+        check_counts(&GameState::new(12, 12), [1, 15, 218, 3172, 46144]);
+    }
+
+    #[test]
+    fn test_perft_rule_variants() {
+        let half_turns = RuleSet {
+            half_turns: true,
+            ..RuleSet::default()
+        };
+        check_counts(
+            &GameState::new(12, 12).with_rules(half_turns),
+            [1, 18, 314, 5482, 95698],
+        );
+
+        let no_flips_allow_undo = RuleSet {
+            no_undo: false,
+            flips: false,
+            ..RuleSet::default()
+        };
+        check_counts(
+            &GameState::new(12, 12).with_rules(no_flips_allow_undo),
+            [1, 14, 196, 2744, 38408],
+        );
+
+        let open_4x4x4 = RuleSet {
+            shape: Shape::new(4, 3).unwrap().open_center(),
+            ..RuleSet::default()
+        };
+        check_counts(
+            &GameState::new(12, 12).with_rules(open_4x4x4),
+            [1, 25, 616, 15184, 374272],
+        );
+    }
+
+    #[test]
+    fn test_perft_positions() {
+        // Blue can complete a line at once, which ends the game
+        let game: GameState = "....#....,....#....,BB..#RR.. B3/R3 1 - 3".parse().unwrap();
+        check_counts(&game, [1, 15, 203, 2922, 38885]);
+
+        // Turns make cubies fall, and the last turn can't be undone
+        let game: GameState = "....#....,B...#R...,RB..#BR.. B2/R2 2 U' 3"
+            .parse()
+            .unwrap();
+        check_counts(&game, [1, 14, 187, 2523, 30518]);
+    }
+
+    #[test]
+    fn test_divide() {
+        let game = GameState::new(12, 12);
+        for normalize in [false, true] {
+            let divided = divide(&game, 3, normalize);
+            assert_eq!(divided.len(), 15);
+            let total: u64 = divided.iter().map(|(_, count)| count).sum();
+            assert_eq!(total, perft(&game, 3, normalize));
+        }
+        assert!(divide(&game, 0, false).is_empty());
+    }
+}
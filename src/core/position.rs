@@ -0,0 +1,392 @@
+//! Text form of a whole game, in the spirit of chess FEN. A position is five or six fields
+//! separated by single spaces:
+//!
+//! 1. The cage as written by its `Display`: layers top to bottom separated by ',', each layer row
+//!    by row (x fastest), with color letters for cubies, '.' for empty slots and '#' for blocked
+//!    columns. The size and the blocked columns of the cage follow from it.
+//! 2. The players in turn order separated by '/', each as the letters of their colors followed by
+//!    the number of cubies they have left, e.g. `B12/R12`, or `BG6/RY6` for a team game.
+//! 3. The player to move, counting from 1.
+//! 4. The last move in move notation, '-' if there was none.
+//! 5. The number of cubies in a row needed to win, followed by the deviations from the rules of
+//!    the puzzle: 'u' if undoing is allowed, 'h' for half turns, 'n' for no flips, and 'w' or 'l'
+//!    if a move completing lines of several players wins or loses for the mover instead of drawing.
+//!    A move limit follows 'm', e.g. `3m20` if the game is drawn after 20 moves without a drop.
+//! 6. Under a move limit only, the number of moves made since the last drop.
+//!
+//! The standard `(12, 12)` game starts at `....#....,....#....,....#.... B12/R12 1 - 3`.
+
+use crate::core::{
+    cage::Cage,
+    cubie::{Cubie, CubieSet},
+    error::GameError,
+    game::{GameState, MAX_PLAYERS},
+    r#move::Move,
+    rules::{DoubleLine, RuleSet},
+    shape::Shape,
+};
+use std::str::FromStr;
+
+impl std::fmt::Display for GameState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.cage)?;
+        write_players(f, self)?;
+        write!(f, " {} ", self.player_to_move.id + 1)?;
+        match self.last_move {
+            Some(r#move) => write!(f, "{}", r#move.notation(self.rules.shape.size()))?,
+            None => write!(f, "-")?,
+        }
+        write!(f, " ")?;
+        write_rules(f, &self.rules)?;
+        if self.rules.move_limit.is_some() {
+            write!(f, " {}", self.moves_without_drop)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the players field of a position.
+pub(crate) fn write_players(f: &mut std::fmt::Formatter<'_>, game: &GameState) -> std::fmt::Result {
+    for player in &game.players {
+        if player.id != 0 {
+            write!(f, "/")?;
+        }
+        for color in player.colors.iter() {
+            write!(f, "{}", color.to_char())?;
+        }
+        write!(f, "{}", game.remaining_cubies[player.id as usize])?;
+    }
+    Ok(())
+}
+
+/// Writes the rules field of a position.
+pub(crate) fn write_rules(f: &mut std::fmt::Formatter<'_>, rules: &RuleSet) -> std::fmt::Result {
+    write!(f, "{}", rules.shape.line_length())?;
+    if !rules.no_undo {
+        write!(f, "u")?;
+    }
+    if rules.half_turns {
+        write!(f, "h")?;
+    }
+    if !rules.flips {
+        write!(f, "n")?;
+    }
+    match rules.double_line {
+        DoubleLine::Draw => {}
+        DoubleLine::MoverWins => write!(f, "w")?,
+        DoubleLine::MoverLoses => write!(f, "l")?,
+    }
+    if let Some(limit) = rules.move_limit {
+        write!(f, "m{}", limit)?;
+    }
+    Ok(())
+}
+
+impl FromStr for GameState {
+    type Err = GameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Fields along with their offsets in `s`
+        let fields: Vec<(usize, &str)> = s
+            .split(' ')
+            .scan(0, |offset, field| {
+                let start = *offset;
+                *offset += field.len() + 1;
+                Some((start, field))
+            })
+            .collect();
+        let (cage, players, to_move, last_move, rules, moves_without_drop) = match fields[..] {
+            [cage, players, to_move, last_move, rules] => {
+                (cage, players, to_move, last_move, rules, None)
+            }
+            [cage, players, to_move, last_move, rules, moves] => {
+                (cage, players, to_move, last_move, rules, Some(moves))
+            }
+            _ => return Err(GameError::parse(s.len(), "Expected 5 or 6 fields")),
+        };
+
+        let (line_length, rules) = parse_rules(rules)?;
+        let cage = parse_cage(cage, line_length)?;
+        let (colors, cubies) = parse_players(players)?;
+        let to_move = match to_move.1.parse::<u8>() {
+            Ok(n) if (1..=colors.len()).contains(&(n as usize)) => n - 1,
+            _ => return Err(GameError::parse(to_move.0, "Expected the player to move")),
+        };
+        let last_move = match last_move.1 {
+            "-" => None,
+            text => {
+                Some(Move::parse(text, cage.shape().size()).map_err(|e| offset(e, last_move.0))?)
+            }
+        };
+        let moves_without_drop = match (rules.move_limit, moves_without_drop) {
+            (None, None) => 0,
+            (Some(_), Some((start, field))) => field
+                .parse()
+                .map_err(|_| GameError::parse(start, "Expected the moves without a drop"))?,
+            (Some(_), None) => {
+                return Err(GameError::parse(
+                    s.len(),
+                    "Expected the moves without a drop",
+                ));
+            }
+            (None, Some((start, _))) => {
+                return Err(GameError::parse(
+                    start - 1,
+                    "Expected no field after the rules",
+                ));
+            }
+        };
+
+        let rules = RuleSet {
+            shape: cage.shape(),
+            ..rules
+        };
+        Ok(GameState::with_colors(&colors, &cubies)
+            .with_rules(rules)
+            .with_cage(cage)
+            .with_player_to_move(to_move)
+            .with_last_move(last_move)
+            .with_moves_without_drop(moves_without_drop))
+    }
+}
+
+/// Moves the position of a parse error of a field to the position in the whole string.
+pub(crate) fn offset(error: GameError, by: usize) -> GameError {
+    match error {
+        GameError::Parse { position, message } => GameError::Parse {
+            position: position + by,
+            message,
+        },
+        other => other,
+    }
+}
+
+/// Parses the rules field into the line length and the rules, which get their shape from the
+/// cage.
+pub(crate) fn parse_rules((start, field): (usize, &str)) -> Result<(usize, RuleSet), GameError> {
+    let digits = field.chars().take_while(char::is_ascii_digit).count();
+    let line_length = field[..digits]
+        .parse()
+        .map_err(|_| GameError::parse(start, "Expected the line length"))?;
+    let mut rules = RuleSet::default();
+    let mut i = digits;
+    while let Some(c) = field[i..].chars().next() {
+        let position = start + i;
+        i += c.len_utf8();
+        match c {
+            'u' if rules.no_undo => rules.no_undo = false,
+            'h' if !rules.half_turns => rules.half_turns = true,
+            'n' if rules.flips => rules.flips = false,
+            'w' if rules.double_line == DoubleLine::Draw => {
+                rules.double_line = DoubleLine::MoverWins
+            }
+            'l' if rules.double_line == DoubleLine::Draw => {
+                rules.double_line = DoubleLine::MoverLoses
+            }
+            'm' if rules.move_limit.is_none() => {
+                let limit_digits = field[i..].chars().take_while(char::is_ascii_digit).count();
+                let limit = field[i..i + limit_digits]
+                    .parse()
+                    .map_err(|_| GameError::parse(start + i, "Expected the move limit"))?;
+                rules.move_limit = Some(limit);
+                i += limit_digits;
+            }
+            _ => return Err(GameError::parse(position, "Expected u, h, n, w, l or m")),
+        }
+    }
+    Ok((line_length, rules))
+}
+
+fn parse_cage((start, field): (usize, &str), line_length: usize) -> Result<Cage, GameError> {
+    let layers: Vec<&str> = field.split(',').collect();
+    let size = layers.len();
+    if layers.iter().any(|layer| layer.len() != size * size) {
+        let message = format!("Expected {} layers of {} slots", size, size * size);
+        return Err(GameError::parse(start, message));
+    }
+
+    // Blocked columns are marked in every layer
+    let blocked: Vec<(usize, usize)> = layers[0]
+        .bytes()
+        .enumerate()
+        .filter(|&(_, c)| c == b'#')
+        .map(|(i, _)| (i % size, i / size))
+        .collect();
+    let shape = Shape::new(size, line_length)
+        .and_then(|shape| shape.with_blocked_columns(&blocked))
+        .map_err(|e| GameError::parse(start, e))?;
+    for (z, layer) in layers.iter().enumerate() {
+        for (i, c) in layer.bytes().enumerate() {
+            if (c == b'#') != shape.is_blocked(i % size, i / size) {
+                let position = start + z * (size * size + 1) + i;
+                return Err(GameError::parse(
+                    position,
+                    "Expected '#' in blocked columns only",
+                ));
+            }
+        }
+    }
+
+    Cage::parse(field, shape).map_err(|e| offset(e, start))
+}
+
+/// Parses the players field into the colors and the remaining cubies of each player.
+pub(crate) fn parse_players(
+    (start, field): (usize, &str),
+) -> Result<(Vec<CubieSet>, Vec<u8>), GameError> {
+    let mut colors = Vec::new();
+    let mut cubies = Vec::new();
+    let mut owned = CubieSet::new();
+    let mut player_start = start;
+    for player in field.split('/') {
+        if colors.len() == MAX_PLAYERS {
+            let message = format!("Expected at most {} players", MAX_PLAYERS);
+            return Err(GameError::parse(player_start - 1, message));
+        }
+        let letters = player.chars().take_while(char::is_ascii_uppercase).count();
+        let mut player_colors = CubieSet::new();
+        for (i, c) in player[..letters].char_indices() {
+            match Cubie::from_char(c) {
+                Some(cubie) if !owned.contains(cubie) => {
+                    player_colors.insert(cubie);
+                    owned.insert(cubie);
+                }
+                _ => {
+                    let message = "Expected a color of no other player";
+                    return Err(GameError::parse(player_start + i, message));
+                }
+            }
+        }
+        if player_colors.is_empty() {
+            return Err(GameError::parse(
+                player_start,
+                "Expected the player's colors",
+            ));
+        }
+        let remaining = player[letters..].parse().map_err(|_| {
+            GameError::parse(player_start + letters, "Expected the number of cubies left")
+        })?;
+
+        colors.push(player_colors);
+        cubies.push(remaining);
+        player_start += player.len() + 1;
+    }
+    Ok((colors, cubies))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::r#move::{Layer, Rotation};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_start_position() {
+        let game = GameState::new(12, 12);
+        let text = "....#....,....#....,....#.... B12/R12 1 - 3";
+        assert_eq!(game.to_string(), text);
+        assert_eq!(GameState::from_str(text), Ok(game));
+
+        let rules = RuleSet {
+            no_undo: false,
+            half_turns: true,
+            flips: false,
+            shape: Shape::new(4, 3).unwrap(),
+            double_line: DoubleLine::MoverLoses,
+            move_limit: Some(20),
+        };
+        let game = GameState::with_cubies(&[4, 4, 4]).with_rules(rules);
+        let layer = ".....##..##.....";
+        let text = format!("{layer},{layer},{layer},{layer} B4/R4/G4 1 - 3uhnlm20 0");
+        assert_eq!(game.to_string(), text);
+        assert_eq!(GameState::from_str(&text), Ok(game));
+    }
+
+    #[test]
+    fn test_position() {
+        let text = "R...#....,RB..#....,BR..#.... B4/R3 1 U' 3";
+        let game = GameState::from_str(text).unwrap();
+        assert_eq!(game.cage.get([0, 0, 2]), Some(Cubie::Red));
+        assert_eq!(game.cage.get([1, 0, 0]), Some(Cubie::Red));
+        assert_eq!(game.remaining_cubies, [4, 3]);
+        assert_eq!(game.player_to_move.id, 0);
+        let forbidden = Move::RotateLayer {
+            layer: Layer::UP,
+            rotation: Rotation::Clockwise,
+        };
+        assert_eq!(game.forbidden_move(), Some(forbidden));
+        assert_eq!(game.to_string(), text);
+    }
+
+    #[test]
+    fn test_random_positions_round_trip() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let team = [
+            CubieSet::single(Cubie::Blue).union(CubieSet::single(Cubie::Green)),
+            CubieSet::single(Cubie::Red).union(CubieSet::single(Cubie::Yellow)),
+        ];
+        let games = [
+            GameState::new(8, 8),
+            GameState::with_cubies(&[6, 6, 6]).with_rules(RuleSet {
+                half_turns: true,
+                shape: Shape::STANDARD.open_center(),
+                double_line: DoubleLine::MoverWins,
+                ..RuleSet::default()
+            }),
+            GameState::with_colors(&team, &[10, 10]).with_rules(RuleSet {
+                no_undo: false,
+                move_limit: Some(12),
+                shape: Shape::new(4, 3).unwrap(),
+                ..RuleSet::default()
+            }),
+        ];
+        for start in games {
+            for _ in 0..20 {
+                let mut game = start.clone();
+                for _ in 0..rng.random_range(0..20) {
+                    let moves = game.legal_moves();
+                    if moves.is_empty() || game.is_over() {
+                        break;
+                    }
+                    game.apply_move(moves[rng.random_range(0..moves.len())])
+                        .unwrap();
+                }
+                assert_eq!(GameState::from_str(&game.to_string()), Ok(game));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let errors = [
+            // Missing field
+            ("....#....,....#....,....#.... B12/R12 1 -", 41),
+            // Short layer
+            ("....#....,....#....,....#... B12/R12 1 - 3", 0),
+            // Blocked column not marked in every layer
+            ("....#....,....#....,......... B12/R12 1 - 3", 24),
+            ("....#....,....#....,...X#.... B12/R12 1 - 3", 23),
+            // Shared color
+            ("....#....,....#....,....#.... B12/B12 1 - 3", 34),
+            ("....#....,....#....,....#.... B12/R 1 - 3", 35),
+            ("....#....,....#....,....#.... B12/R12 3 - 3", 38),
+            ("....#....,....#....,....#.... B12/R12 1 Rz9 3", 41),
+            ("....#....,....#....,....#.... B12/R12 1 - 3x", 43),
+            // Conflicting double line rules
+            ("....#....,....#....,....#.... B12/R12 1 - 3wl", 44),
+            ("....#....,....#....,....#.... B12/R12 1 - 3m", 44),
+            // Moves without a drop need a move limit and the other way around
+            ("....#....,....#....,....#.... B12/R12 1 - 3 0", 43),
+            ("....#....,....#....,....#.... B12/R12 1 - 3m20", 46),
+            ("....#....,....#....,....#.... B12/R12 1 - 3m20 x", 47),
+            // Line longer than the cage
+            ("....#....,....#....,....#.... B12/R12 1 - 5", 0),
+        ];
+        for (text, expected) in errors {
+            match GameState::from_str(text) {
+                Err(GameError::Parse { position, .. }) => assert_eq!(position, expected, "{text}"),
+                other => panic!("Expected a parse error for {text}, got {:?}", other),
+            }
+        }
+    }
+}
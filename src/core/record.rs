@@ -0,0 +1,513 @@
+//! Record of a played game, in the spirit of chess PGN. A record starts with header lines of the
+//! form `[Name "value"]`:
+//!
+//! - `Date`: when the game was played, e.g. `2025-06-01`. Optional.
+//! - `Players`: the players in turn order with their colors and starting cubies, as in the
+//!   players field of a position, e.g. `B12/R12`.
+//! - `Rules`: the size of the cage, the rules field of a position and, unless the center columns
+//!   are blocked, the blocked columns in drop notation or '-' for none, e.g. `3 3`, `3 3uh -` or
+//!   `4 3 a1,a4,d1,d4`.
+//! - `Position`: the starting position, if the game didn't start with an empty cage. It takes
+//!   precedence over `Players` and `Rules`, which are written for readers all the same.
+//! - `Result`: the player who won, counting from 1, `1/2-1/2` for a draw or `*` if the game isn't
+//!   over. It follows from the moves, so it is only checked against them when read.
+//!
+//! Other headers are ignored. After the headers come the moves in move notation, numbered by
+//! round: `1. Ba1 Rc2 2. U F`. A move can be followed by a comment in braces and an evaluation of
+//! the position after it in brackets, `[P1 5]` if player 1 wins in 5 moves or `[draw]`.
+
+use crate::{
+    core::{
+        error::GameError,
+        game::{GameOutcome, GameState, UndoRecord},
+        r#move::Move,
+        position::{offset, parse_players, parse_rules, write_players, write_rules},
+        rules::RuleSet,
+        shape::Shape,
+    },
+    search::naive::Evaluation,
+};
+use std::str::FromStr;
+
+/// A move of a game record, with optional annotations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedMove {
+    pub r#move: Move,
+    /// Free text without braces.
+    pub comment: Option<String>,
+    /// Evaluation of the position after the move.
+    pub evaluation: Option<Evaluation>,
+}
+
+impl RecordedMove {
+    pub fn new(r#move: Move) -> Self {
+        Self {
+            r#move,
+            comment: None,
+            evaluation: None,
+        }
+    }
+}
+
+/// A game from its starting position, see the module documentation for the text form.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GameRecord {
+    /// Position the game started from, with the players, their starting cubies and the rules.
+    pub start: GameState,
+    pub moves: Vec<RecordedMove>,
+    /// When the game was played, e.g. `2025-06-01`.
+    pub date: Option<String>,
+}
+
+impl GameRecord {
+    pub fn new(start: GameState) -> Self {
+        Self {
+            start,
+            moves: Vec::new(),
+            date: None,
+        }
+    }
+
+    /// Record of the game that led to `game`, given the undo records of its moves in the order
+    /// they were played. The moves must have been applied without normalizing.
+    pub fn from_history(game: &GameState, history: &[UndoRecord]) -> Self {
+        let mut start = game.clone();
+        let mut moves = Vec::new();
+        for &undo in history.iter().rev() {
+            let r#move = start.last_move.expect("Undo record without a move");
+            moves.push(RecordedMove::new(r#move));
+            start.undo_move(undo);
+        }
+        moves.reverse();
+        Self {
+            start,
+            moves,
+            date: None,
+        }
+    }
+
+    /// Plays the moves from the start, returning the final position and the undo records of the
+    /// moves.
+    pub fn replay(&self) -> Result<(GameState, Vec<UndoRecord>), GameError> {
+        let mut game = self.start.clone();
+        let history = self
+            .moves
+            .iter()
+            .map(|m| game.apply_move(m.r#move))
+            .collect::<Result<_, _>>()?;
+        Ok((game, history))
+    }
+}
+
+impl std::fmt::Display for GameRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(date) = &self.date {
+            writeln!(f, "[Date \"{}\"]", date)?;
+        }
+        write!(f, "[Players \"")?;
+        write_players(f, &self.start)?;
+        writeln!(f, "\"]")?;
+
+        let shape = self.start.rules.shape;
+        write!(f, "[Rules \"{} ", shape.size())?;
+        write_rules(f, &self.start.rules)?;
+        if Shape::new(shape.size(), shape.line_length()) != Ok(shape) {
+            let columns: Vec<String> = shape
+                .blocked_columns()
+                .map(|(x, y)| format!("{}{}", char::from(b'a' + x as u8), y + 1))
+                .collect();
+            match columns.is_empty() {
+                true => write!(f, " -")?,
+                false => write!(f, " {}", columns.join(","))?,
+            }
+        }
+        writeln!(f, "\"]")?;
+
+        let players = self.start.players.clone();
+        let start_of_game = GameState::with_colors(
+            &players.iter().map(|p| p.colors).collect::<Vec<_>>(),
+            &self.start.remaining_cubies,
+        )
+        .with_rules(self.start.rules);
+        if self.start != start_of_game {
+            writeln!(f, "[Position \"{}\"]", self.start)?;
+        }
+
+        let outcome = self
+            .replay()
+            .ok()
+            .map(|(game, history)| game.outcome(&history));
+        writeln!(
+            f,
+            "[Result \"{}\"]",
+            result_token(outcome.unwrap_or(GameOutcome::Ongoing))
+        )?;
+
+        // One round per line
+        let size = self.start.rules.shape.size();
+        let mut player = self.start.player_to_move.id;
+        let mut round = 1;
+        for (i, recorded) in self.moves.iter().enumerate() {
+            if i == 0 || player == 0 {
+                if i != 0 {
+                    round += 1;
+                }
+                write!(f, "\n{}.", round)?;
+            }
+            write!(f, " {}", recorded.r#move.notation(size))?;
+            if let Some(comment) = &recorded.comment {
+                write!(f, " {{{}}}", comment)?;
+            }
+            match recorded.evaluation {
+                Some(Evaluation {
+                    winner: Some(id),
+                    moves_to_wl,
+                }) => write!(f, " [P{} {}]", id + 1, moves_to_wl)?,
+                Some(_) => write!(f, " [draw]")?,
+                None => {}
+            }
+            player = (player + 1) % players.len() as u8;
+        }
+        writeln!(f)
+    }
+}
+
+impl FromStr for GameRecord {
+    type Err = GameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut date = None;
+        let mut players = None;
+        let mut rules = None;
+        let mut position = None;
+        let mut result = None;
+
+        // Header lines up to the first other non-empty line, which starts the moves
+        let mut moves_start = s.len();
+        let mut line_start = 0;
+        for line in s.split_inclusive('\n') {
+            let header = line.trim_end();
+            if header.starts_with('[') {
+                let (name, value) = parse_header(line_start, header)?;
+                match name {
+                    "Date" => date = Some(value.1.to_string()),
+                    "Players" => players = Some(parse_players(value)?),
+                    "Rules" => rules = Some(parse_record_rules(value)?),
+                    "Position" => {
+                        let game = GameState::from_str(value.1).map_err(|e| offset(e, value.0))?;
+                        position = Some(game);
+                    }
+                    "Result" => result = Some(value),
+                    _ => {}
+                }
+            } else if !header.is_empty() {
+                moves_start = line_start;
+                break;
+            }
+            line_start += line.len();
+        }
+
+        let start = match (position, players) {
+            (Some(game), _) => game,
+            (None, Some((colors, cubies))) => {
+                GameState::with_colors(&colors, &cubies).with_rules(rules.unwrap_or_default())
+            }
+            (None, None) => {
+                let message = "Expected a Players or Position header";
+                return Err(GameError::parse(moves_start, message));
+            }
+        };
+
+        let mut record = GameRecord {
+            date,
+            ..GameRecord::new(start.clone())
+        };
+        let mut game = start;
+        let mut history = Vec::new();
+        let mut position = moves_start;
+        loop {
+            let rest = &s[position..];
+            let trimmed = rest.trim_start();
+            position += rest.len() - trimmed.len();
+            let Some(first) = trimmed.chars().next() else {
+                break;
+            };
+
+            // Annotations belong to the move before them
+            if first == '{' || first == '[' {
+                let close = if first == '{' { '}' } else { ']' };
+                let Some(end) = trimmed.find(close) else {
+                    let message = format!("Expected '{}'", close);
+                    return Err(GameError::parse(s.len(), message));
+                };
+                let Some(recorded) = record.moves.last_mut() else {
+                    return Err(GameError::parse(position, "Expected a move"));
+                };
+                let text = &trimmed[1..end];
+                if first == '{' {
+                    recorded.comment = Some(text.to_string());
+                } else {
+                    recorded.evaluation = Some(parse_evaluation(position + 1, text)?);
+                }
+                position += end + 1;
+                continue;
+            }
+
+            let len = trimmed
+                .find(|c: char| c.is_whitespace() || c == '{' || c == '[')
+                .unwrap_or(trimmed.len());
+            let token = &trimmed[..len];
+            let is_round = token
+                .strip_suffix('.')
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+            if !is_round {
+                let r#move =
+                    Move::parse(token, game.rules.shape.size()).map_err(|e| offset(e, position))?;
+                let undo = game
+                    .apply_move(r#move)
+                    .map_err(|e| GameError::parse(position, e.to_string()))?;
+                history.push(undo);
+                record.moves.push(RecordedMove::new(r#move));
+            }
+            position += len;
+        }
+
+        // Records of unfinished games may be written with any result
+        if let Some((start, token)) = result
+            && token != "*"
+        {
+            let expected = result_token(game.outcome(&history));
+            if token != expected {
+                let message = format!("Expected the result of the moves, {}", expected);
+                return Err(GameError::parse(start, message));
+            }
+        }
+        Ok(record)
+    }
+}
+
+/// Value of the `Result` header for `outcome`.
+fn result_token(outcome: GameOutcome) -> String {
+    match outcome {
+        GameOutcome::Win(player) => (player.id + 1).to_string(),
+        GameOutcome::Draw => "1/2-1/2".to_string(),
+        GameOutcome::Ongoing => "*".to_string(),
+    }
+}
+
+/// Splits a header line starting at `start` into its name and its value along with the offset
+/// of the value.
+fn parse_header(start: usize, line: &str) -> Result<(&str, (usize, &str)), GameError> {
+    let expected = || GameError::parse(start, "Expected a header like [Name \"value\"]");
+    let inner = line
+        .strip_prefix('[')
+        .and_then(|line| line.strip_suffix(']'))
+        .ok_or_else(expected)?;
+    let (name, quoted) = inner.split_once(' ').ok_or_else(expected)?;
+    let value = quoted
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(expected)?;
+    Ok((name, (start + name.len() + 3, value)))
+}
+
+/// Parses the `Rules` header: the size of the cage, the rules field of a position and optionally
+/// the blocked columns.
+fn parse_record_rules((start, value): (usize, &str)) -> Result<RuleSet, GameError> {
+    let fields: Vec<&str> = value.split(' ').collect();
+    if !(2..=3).contains(&fields.len()) {
+        return Err(GameError::parse(start, "Expected the size and the rules"));
+    }
+    let size = fields[0]
+        .parse()
+        .map_err(|_| GameError::parse(start, "Expected the size of the cage"))?;
+    let rules_start = start + fields[0].len() + 1;
+    let (line_length, rules) = parse_rules((rules_start, fields[1]))?;
+    let mut shape = Shape::new(size, line_length).map_err(|e| GameError::parse(start, e))?;
+
+    if let Some(&blocked) = fields.get(2) {
+        let blocked_start = rules_start + fields[1].len() + 1;
+        let mut columns = Vec::new();
+        if blocked != "-" {
+            let mut column_start = blocked_start;
+            for column in blocked.split(',') {
+                columns.push(parse_column(column_start, column)?);
+                column_start += column.len() + 1;
+            }
+        }
+        shape = shape
+            .with_blocked_columns(&columns)
+            .map_err(|e| GameError::parse(blocked_start, e))?;
+    }
+    Ok(RuleSet { shape, ..rules })
+}
+
+/// Parses a column in drop notation without the color, e.g. `a1`.
+fn parse_column(start: usize, text: &str) -> Result<(usize, usize), GameError> {
+    let bytes = text.as_bytes();
+    match bytes {
+        [x @ b'a'..=b'z', y @ b'1'..=b'9'] => Ok(((x - b'a') as usize, (y - b'1') as usize)),
+        _ => Err(GameError::parse(start, "Expected a column like a1")),
+    }
+}
+
+/// Parses an evaluation in brackets, `P1 5` or `draw`.
+fn parse_evaluation(start: usize, text: &str) -> Result<Evaluation, GameError> {
+    if text == "draw" {
+        return Ok(Evaluation {
+            winner: None,
+            moves_to_wl: -1,
+        });
+    }
+    let expected = || GameError::parse(start, "Expected an evaluation like P1 5 or draw");
+    let (player, moves) = text
+        .strip_prefix('P')
+        .and_then(|text| text.split_once(' '))
+        .ok_or_else(expected)?;
+    let winner = match player.parse::<u8>() {
+        Ok(n) if n >= 1 => n - 1,
+        _ => return Err(expected()),
+    };
+    let moves_to_wl = moves.parse().map_err(|_| expected())?;
+    Ok(Evaluation {
+        winner: Some(winner),
+        moves_to_wl,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cubie::{Cubie, CubieSet};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_record() {
+        let text = "\
+[Date \"2025-06-01\"]
+[Players \"B4/R4\"]
+[Rules \"3 3\"]
+[Result \"1\"]
+
+1. Ba1 {corner} Rb1 [P1 3]
+2. Ba2 Rc1 [draw]
+3. Ba3
+";
+        let record = GameRecord::from_str(text).unwrap();
+        assert_eq!(record.date.as_deref(), Some("2025-06-01"));
+        assert_eq!(record.start, GameState::new(4, 4));
+        assert_eq!(record.moves.len(), 5);
+        assert_eq!(record.moves[0].comment.as_deref(), Some("corner"));
+        assert_eq!(
+            record.moves[1].evaluation,
+            Some(Evaluation {
+                winner: Some(0),
+                moves_to_wl: 3
+            })
+        );
+        assert_eq!(record.moves[3].evaluation.unwrap().winner, None);
+        let (game, history) = record.replay().unwrap();
+        assert_eq!(game.won().map(|(player, _)| player.id), Some(0));
+        assert_eq!(history.len(), 5);
+        assert_eq!(record.to_string(), text);
+    }
+
+    #[test]
+    fn test_drawn_game() {
+        let rules = RuleSet {
+            move_limit: Some(2),
+            ..RuleSet::default()
+        };
+        let mut game = GameState::new(4, 4).with_rules(rules);
+        let history = vec![
+            game.apply_move(Move::parse("Ba1", 3).unwrap()).unwrap(),
+            game.apply_move(Move::parse("U", 3).unwrap()).unwrap(),
+            game.apply_move(Move::parse("D", 3).unwrap()).unwrap(),
+        ];
+        assert_eq!(game.outcome(&history), GameOutcome::Draw);
+
+        let record = GameRecord::from_history(&game, &history);
+        let text = record.to_string();
+        assert!(text.contains("[Result \"1/2-1/2\"]"));
+        let parsed = GameRecord::from_str(&text).unwrap();
+        assert_eq!(parsed, record);
+        let (replayed, replayed_history) = parsed.replay().unwrap();
+        assert_eq!(replayed.outcome(&replayed_history), GameOutcome::Draw);
+
+        // Claiming a win instead
+        let text = text.replace("1/2-1/2", "2");
+        assert!(GameRecord::from_str(&text).is_err());
+    }
+
+    #[test]
+    fn test_random_records_round_trip() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let team = [
+            CubieSet::single(Cubie::Blue).union(CubieSet::single(Cubie::Green)),
+            CubieSet::single(Cubie::Red).union(CubieSet::single(Cubie::Yellow)),
+        ];
+        let starts = [
+            GameState::new(8, 8),
+            GameState::with_cubies(&[6, 6, 6]).with_rules(RuleSet {
+                half_turns: true,
+                shape: Shape::STANDARD.open_center(),
+                ..RuleSet::default()
+            }),
+            GameState::with_colors(&team, &[10, 10]).with_rules(RuleSet {
+                no_undo: false,
+                flips: false,
+                shape: Shape::new(4, 3).unwrap(),
+                ..RuleSet::default()
+            }),
+            GameState::from_str("....#....,B...#....,R...#.... B3/R3 2 Ba1 3").unwrap(),
+        ];
+        for start in starts {
+            for _ in 0..20 {
+                let mut game = start.clone();
+                let mut history = Vec::new();
+                for _ in 0..rng.random_range(0..20) {
+                    let moves = game.legal_moves();
+                    if moves.is_empty() || game.is_over() {
+                        break;
+                    }
+                    let r#move = moves[rng.random_range(0..moves.len())];
+                    history.push(game.apply_move(r#move).unwrap());
+                }
+
+                let record = GameRecord::from_history(&game, &history);
+                assert_eq!(record.start, start);
+                let parsed = GameRecord::from_str(&record.to_string()).unwrap();
+                assert_eq!(parsed, record);
+                assert_eq!(parsed.replay(), Ok((game, history)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let errors = [
+            // Missing players
+            ("[Rules \"3 3\"]\n\n1. Ba1", 15),
+            ("[Players B12/R12]", 0),
+            ("[Players \"B12/R\"]", 15),
+            ("[Players \"B12/R12\"]\n[Rules \"3 3x\"]", 31),
+            ("[Players \"B12/R12\"]\n[Rules \"4 3 a1,b5\"]", 32),
+            // Asymmetric blocked columns
+            ("[Players \"B12/R12\"]\n[Rules \"3 3 a1\"]", 32),
+            // Red moves first
+            ("[Players \"B12/R12\"]\n\n1. Ra1", 24),
+            ("[Players \"B12/R12\"]\n\n1. Ba1 Rz1", 29),
+            ("[Players \"B12/R12\"]\n\n{comment} 1. Ba1", 21),
+            ("[Players \"B12/R12\"]\n\n1. Ba1 {comment", 36),
+            ("[Players \"B12/R12\"]\n\n1. Ba1 [P0 3]", 29),
+            // The game isn't over
+            ("[Players \"B12/R12\"]\n[Result \"1/2-1/2\"]\n\n1. Ba1", 29),
+        ];
+        for (text, expected) in errors {
+            match GameRecord::from_str(text) {
+                Err(GameError::Parse { position, .. }) => assert_eq!(position, expected, "{text}"),
+                other => panic!("Expected a parse error for {text}, got {:?}", other),
+            }
+        }
+    }
+}
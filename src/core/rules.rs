@@ -0,0 +1,92 @@
+use crate::core::shape::Shape;
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// Rules of a game variant. The default is the rule set of the physical puzzle.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode)]
+pub struct RuleSet {
+    /// A player cannot undo the opponent's immediate previous move.
+    pub no_undo: bool,
+    /// Layers can be turned by 180 degrees in a single move.
+    pub half_turns: bool,
+    /// The cage can be flipped upside down.
+    pub flips: bool,
+    /// Size, line length and blocked columns of the cage.
+    pub shape: Shape,
+    /// Outcome of a move that completes lines of several players at once.
+    pub double_line: DoubleLine,
+    /// The game is drawn after this many moves in a row without a drop, if set.
+    pub move_limit: Option<u8>,
+}
+
+/// How a layer turn or flip that completes lines of several players at once is adjudicated.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize, Encode, Decode,
+)]
+pub enum DoubleLine {
+    /// Nobody wins.
+    #[default]
+    Draw,
+    /// The player who made the move wins if they have a line, the lines of the others don't
+    /// count.
+    MoverWins,
+    /// The lines of the player who made the move don't count, an opponent with a line wins.
+    MoverLoses,
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self {
+            no_undo: true,
+            half_turns: false,
+            flips: true,
+            shape: Shape::STANDARD,
+            double_line: DoubleLine::Draw,
+            move_limit: None,
+        }
+    }
+}
+
+impl std::fmt::Display for RuleSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let default = RuleSet::default();
+        let mut changes = Vec::new();
+        if self.no_undo != default.no_undo {
+            changes.push(if self.no_undo {
+                "no undo"
+            } else {
+                "undo allowed"
+            });
+        }
+        if self.half_turns != default.half_turns {
+            changes.push(if self.half_turns {
+                "half turns"
+            } else {
+                "no half turns"
+            });
+        }
+        if self.flips != default.flips {
+            changes.push(if self.flips { "flips" } else { "no flips" });
+        }
+        match self.double_line {
+            DoubleLine::Draw => {}
+            DoubleLine::MoverWins => changes.push("double lines win"),
+            DoubleLine::MoverLoses => changes.push("double lines lose"),
+        }
+        let move_limit = self
+            .move_limit
+            .map(|limit| format!("draw after {} moves without a drop", limit));
+        if let Some(move_limit) = &move_limit {
+            changes.push(move_limit);
+        }
+        let shape = self.shape.to_string();
+        if self.shape != default.shape {
+            changes.push(&shape);
+        }
+        if changes.is_empty() {
+            write!(f, "standard rules")
+        } else {
+            write!(f, "{}", changes.join(", "))
+        }
+    }
+}
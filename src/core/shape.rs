@@ -0,0 +1,183 @@
+use crate::core::line::{self, LineTable};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// Largest supported cage size, so that cages fit into fixed size arrays.
+pub const MAX_SIZE: usize = 4;
+
+/// Geometry of a cage: a `size`x`size`x`size` cube of slots where `line_length` same color
+/// cubies in a row win, and some columns are blocked, like the center column of the puzzle.
+#[derive(
+    Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct Shape {
+    size: u8,
+    line_length: u8,
+    /// Bit `x * size + y` is set if column (x, y) is blocked.
+    blocked_columns: u16,
+}
+
+impl Shape {
+    /// The physical puzzle: 3x3x3 with the center column blocked.
+    pub const STANDARD: Shape = Shape {
+        size: 3,
+        line_length: 3,
+        blocked_columns: 1 << 4,
+    };
+
+    /// A cage with the center columns blocked, like on the puzzle: one column for odd sizes,
+    /// the middle 2x2 columns for even sizes. Those would be all columns of a 2x2x2 cage, so it
+    /// keeps them open.
+    pub fn new(size: usize, line_length: usize) -> Result<Self, String> {
+        if !(2..=MAX_SIZE).contains(&size) {
+            return Err(format!("Cage size must be between 2 and {}", MAX_SIZE));
+        }
+        if !(2..=size).contains(&line_length) {
+            return Err(format!("Line length must be between 2 and {}", size));
+        }
+        let center = if size == 2 {
+            Vec::new()
+        } else if size % 2 == 1 {
+            vec![(size / 2, size / 2)]
+        } else {
+            let (a, b) = (size / 2 - 1, size / 2);
+            vec![(a, a), (a, b), (b, a), (b, b)]
+        };
+        Self {
+            size: size as u8,
+            line_length: line_length as u8,
+            blocked_columns: 0,
+        }
+        .with_blocked_columns(&center)
+    }
+
+    /// The same cage with exactly `columns` blocked. The blocked columns must look the same from
+    /// all sides, as layer turns and symmetries would otherwise move cubies into them.
+    pub fn with_blocked_columns(self, columns: &[(usize, usize)]) -> Result<Self, String> {
+        let mut shape = Self {
+            blocked_columns: 0,
+            ..self
+        };
+        for &(x, y) in columns {
+            if x >= shape.size() || y >= shape.size() {
+                return Err(format!("Column {},{} is outside of the cage", x, y));
+            }
+            shape.blocked_columns |= 1 << (x * shape.size() + y);
+        }
+        if shape.columns().next().is_none() {
+            return Err("At least one column must be open".to_string());
+        }
+
+        let n = shape.size() - 1;
+        for (x, y) in shape.all_columns() {
+            let blocked = shape.is_blocked(x, y);
+            if blocked != shape.is_blocked(y, n - x) || blocked != shape.is_blocked(n - x, y) {
+                return Err("Blocked columns must be symmetric".to_string());
+            }
+        }
+
+        Ok(shape)
+    }
+
+    /// The same cage with no blocked columns.
+    pub fn open_center(self) -> Self {
+        Self {
+            blocked_columns: 0,
+            ..self
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Number of same color cubies in a row needed to win.
+    pub fn line_length(&self) -> usize {
+        self.line_length as usize
+    }
+
+    pub fn is_blocked(&self, x: usize, y: usize) -> bool {
+        self.blocked_columns & (1 << (x * self.size() + y)) != 0
+    }
+
+    fn all_columns(&self) -> impl Iterator<Item = (usize, usize)> + use<> {
+        let size = self.size();
+        (0..size).flat_map(move |x| (0..size).map(move |y| (x, y)))
+    }
+
+    /// Columns that can hold cubies.
+    pub fn columns(&self) -> impl Iterator<Item = (usize, usize)> + use<> {
+        let shape = *self;
+        self.all_columns()
+            .filter(move |&(x, y)| !shape.is_blocked(x, y))
+    }
+
+    /// Columns that can't hold cubies.
+    pub fn blocked_columns(&self) -> impl Iterator<Item = (usize, usize)> + use<> {
+        let shape = *self;
+        self.all_columns()
+            .filter(move |&(x, y)| shape.is_blocked(x, y))
+    }
+
+    /// Number of slots available for cubies.
+    pub fn num_slots(&self) -> usize {
+        self.columns().count() * self.size()
+    }
+
+    /// Winning lines of this cage, which avoid the blocked columns.
+    pub fn lines(&self) -> &'static LineTable {
+        line::line_table(*self)
+    }
+}
+
+impl Default for Shape {
+    fn default() -> Self {
+        Self::STANDARD
+    }
+}
+
+impl std::fmt::Display for Shape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{0}x{0}x{0}, {1} in a row", self.size, self.line_length)?;
+        match self.blocked_columns().count() {
+            0 => write!(f, ", open center"),
+            1 => write!(f, ", 1 blocked column"),
+            n => write!(f, ", {} blocked columns", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standard_shape() {
+        assert_eq!(Shape::new(3, 3).unwrap(), Shape::STANDARD);
+        assert_eq!(Shape::STANDARD.num_slots(), 24);
+        assert_eq!(Shape::STANDARD.open_center().num_slots(), 27);
+        assert_eq!(Shape::new(4, 4).unwrap().num_slots(), 48);
+    }
+
+    #[test]
+    fn test_2x2x2_shape() {
+        let shape = Shape::new(2, 2).unwrap();
+        assert_eq!(shape.num_slots(), 8);
+        assert!(
+            shape
+                .with_blocked_columns(&[(0, 0), (0, 1), (1, 0), (1, 1)])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_asymmetric_blocked_columns() {
+        let shape = Shape::new(4, 3).unwrap();
+        assert!(shape.with_blocked_columns(&[(0, 0)]).is_err());
+        assert!(
+            shape
+                .with_blocked_columns(&[(0, 0), (0, 3), (3, 0), (3, 3)])
+                .is_ok()
+        );
+    }
+}
@@ -1,25 +1,137 @@
+use crate::core::{
+    cubie::NUM_COLORS,
+    game::MAX_PLAYERS,
+    r#move::{Move, Rotation},
+    rules::{DoubleLine, RuleSet},
+    shape::{MAX_SIZE, Shape},
+};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use std::sync::LazyLock;
 
-pub static POS_COLOR: LazyLock<[[[[u64; 3]; 3]; 3]; 6]> = LazyLock::new(|| {
-    let mut rng = StdRng::seed_from_u64(0x12345678);
+/// Version of the hashing scheme, bumped whenever the hash of a position or the meaning of the
+/// rules it is hashed with changes so that stale evaluation files are recognized.
+pub const HASH_VERSION: u32 = 5;
 
-    let mut table = [[[[0; 3]; 3]; 3]; 6];
-    for x in 0..3 {
-        for y in 0..3 {
-            for z in 0..3 {
-                for color in 0..6 {
-                    table[color][x][y][z] = rng.random::<u64>();
+/// Keys for each color in each slot. The keys of the 3x3x3 slots are drawn first to keep the
+/// hashes of the precomputed evaluations valid.
+pub static POS_COLOR: LazyLock<[[[[u64; MAX_SIZE]; MAX_SIZE]; MAX_SIZE]; NUM_COLORS]> =
+    LazyLock::new(|| {
+        let mut rng = StdRng::seed_from_u64(0x12345678);
+
+        let mut table = [[[[0; MAX_SIZE]; MAX_SIZE]; MAX_SIZE]; NUM_COLORS];
+        for size in [3, MAX_SIZE] {
+            for x in 0..size {
+                for y in 0..size {
+                    for z in 0..size {
+                        if size > 3 && x < 3 && y < 3 && z < 3 {
+                            continue;
+                        }
+                        for keys in &mut table {
+                            keys[x][y][z] = rng.random::<u64>();
+                        }
+                    }
                 }
             }
         }
+
+        table
+    });
+
+/// Side to move keys, indexed by the number of players and the id of the player to move. The
+/// first player to move has no key in the two player game, so an empty cage hashes to 0.
+pub static PLAYER_TO_MOVE: LazyLock<[[u64; MAX_PLAYERS]; MAX_PLAYERS + 1]> = LazyLock::new(|| {
+    let mut rng = StdRng::seed_from_u64(0x87654321);
+
+    let mut table = [[0; MAX_PLAYERS]; MAX_PLAYERS + 1];
+    // Drawn first to keep the two player hashes of the precomputed evaluations valid
+    table[2][1] = rng.random::<u64>();
+    for (num_players, keys) in table.iter_mut().enumerate().skip(1) {
+        if num_players == 2 {
+            continue;
+        }
+        for key in &mut keys[..num_players] {
+            *key = rng.random::<u64>();
+        }
     }
 
     table
 });
 
-pub static P2_TO_MOVE: LazyLock<u64> = LazyLock::new(|| {
-    let mut rng = StdRng::seed_from_u64(0x87654321);
-    rng.random::<u64>()
+/// Keys for the number of cubies each player has left to drop, indexed by player id and count.
+pub static REMAINING_CUBIES: LazyLock<[[u64; 256]; MAX_PLAYERS]> = LazyLock::new(|| {
+    let mut rng = StdRng::seed_from_u64(0x11223344);
+    [(); MAX_PLAYERS].map(|_| [(); 256].map(|_| rng.random::<u64>()))
 });
+
+/// Keys for the number of moves since the last drop, only hashed if the rules limit it. Indexed
+/// by the count for the position and by the limit for the rules.
+pub static MOVES_WITHOUT_DROP: LazyLock<([u64; 256], [u64; 256])> = LazyLock::new(|| {
+    let mut rng = StdRng::seed_from_u64(0x99aabbcc);
+    let counts = [(); 256].map(|_| rng.random::<u64>());
+    let limits = [(); 256].map(|_| rng.random::<u64>());
+    (counts, limits)
+});
+
+/// Keys for the move that would undo the previous move, indexed by layer and rotation. The flip
+/// has the extra key at the end.
+static FORBIDDEN_MOVE: LazyLock<([[u64; 3]; MAX_SIZE], u64)> = LazyLock::new(|| {
+    let mut rng = StdRng::seed_from_u64(0x55667788);
+    let rotations = [(); MAX_SIZE].map(|_| [(); 3].map(|_| rng.random::<u64>()));
+    (rotations, rng.random::<u64>())
+});
+
+/// Key for the move that the player to move may not play, see `GameState::forbidden_move`.
+pub fn forbidden_move_key(forbidden: Option<Move>) -> u64 {
+    match forbidden {
+        None | Some(Move::Drop { .. }) => 0,
+        Some(Move::RotateLayer { layer, rotation }) => {
+            let rotation = match rotation {
+                Rotation::Clockwise => 0,
+                Rotation::CounterClockwise => 1,
+                Rotation::HalfTurn => 2,
+            };
+            FORBIDDEN_MOVE.0[layer.z()][rotation]
+        }
+        Some(Move::Flip) => FORBIDDEN_MOVE.1,
+    }
+}
+
+static RULE_KEYS: LazyLock<[u64; 5]> = LazyLock::new(|| {
+    let mut rng = StdRng::seed_from_u64(0x24681357);
+    [(); 5].map(|_| rng.random::<u64>())
+});
+
+/// Key for each rule that differs from the default rule set, so that evaluations of different
+/// variants never mix. The default rules have no key.
+pub fn rules_key(rules: &RuleSet) -> u64 {
+    let default = RuleSet::default();
+    let key = [
+        rules.no_undo != default.no_undo,
+        rules.half_turns != default.half_turns,
+        rules.flips != default.flips,
+        rules.double_line == DoubleLine::MoverWins,
+        rules.double_line == DoubleLine::MoverLoses,
+    ]
+    .iter()
+    .zip(RULE_KEYS.iter())
+    .filter(|(changed, _)| **changed)
+    .fold(shape_key(rules.shape), |key, (_, rule_key)| key ^ rule_key);
+    match rules.move_limit {
+        Some(limit) => key ^ MOVES_WITHOUT_DROP.1[limit as usize],
+        None => key,
+    }
+}
+
+/// Key of a cage shape, derived from its size, line length and blocked columns. The standard
+/// shape has no key.
+fn shape_key(shape: Shape) -> u64 {
+    if shape == Shape::STANDARD {
+        return 0;
+    }
+    let blocked = shape
+        .blocked_columns()
+        .fold(0u64, |bits, (x, y)| bits | 1 << (x * shape.size() + y));
+    let seed = (shape.size() as u64) << 32 | (shape.line_length() as u64) << 16 | blocked;
+    StdRng::seed_from_u64(seed ^ 0x13572468).random::<u64>()
+}
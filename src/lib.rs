@@ -3,64 +3,93 @@ pub mod core;
 pub mod search;
 
 use crate::{
-    app::utils::{self, RELOAD_FLAG_KEY, STORAGE_KEY},
-    core::game::GameState,
-    search::naive::Evaluation,
+    app::{
+        agent::Search,
+        utils::{self, EvalCache, RELOAD_FLAG_KEY, STORAGE_KEY, UndecidedCache},
+    },
+    core::{
+        game::{GameState, Player},
+        record::GameRecord,
+    },
+    search::naive::{EvalFile, SearchMode},
 };
 use app::{
     agent::EvaluationTask, cage::Cage, hovered_move::HoveredMoveProvider, player::PlayerPanel,
 };
-use std::collections::HashMap;
 use web_sys::window;
 use yew::prelude::*;
 use yew_agent::oneshot::OneshotProvider;
 
 #[function_component(App)]
 pub fn app() -> Html {
-    let game_state = use_state(|| {
-        if let Some(storage) = window().and_then(|w| w.local_storage().ok().flatten()) {
-            if let Ok(Some(flag)) = storage.get_item(RELOAD_FLAG_KEY) {
-                if flag == "true" {
-                    storage.remove_item(RELOAD_FLAG_KEY).ok();
-                    if let Ok(Some(hex)) = storage.get_item(STORAGE_KEY) {
-                        if let Some(bytes) = utils::hex_to_bytes(&hex) {
-                            if let Ok((state, _)) = bincode::decode_from_slice::<GameState, _>(
-                                &bytes,
-                                bincode::config::standard(),
-                            ) {
-                                return state;
-                            }
-                        }
-                    }
-                }
+    // The game saved before an export-triggered reload, with its history
+    let saved = use_memo((), |_| {
+        if let Some(storage) = window().and_then(|w| w.local_storage().ok().flatten())
+            && let Ok(Some(flag)) = storage.get_item(RELOAD_FLAG_KEY)
+            && flag == "true"
+        {
+            storage.remove_item(RELOAD_FLAG_KEY).ok();
+            if let Ok(Some(record)) = storage.get_item(STORAGE_KEY)
+                && let Ok(record) = record.parse::<GameRecord>()
+                && let Ok(saved) = record.replay()
+            {
+                return saved;
             }
         }
-        GameState::new(12, 12)
+        (GameState::new(12, 12), Vec::new())
     });
-    let history = use_state(|| Vec::new());
+    let game_state = use_state(|| saved.0.clone());
+    let history = use_state(|| saved.1.clone());
+    // Color picked for the next drop, for players owning several colors
+    let drop_color = use_state(|| None);
+    // Search evaluating the moves, picked in the game controls
+    let search = use_state(|| Search::Naive(SearchMode::OptimalWL));
+    let undecided =
+        use_mut_ref(|| UndecidedCache::with_megabytes(utils::UNDECIDED_CACHE_MEGABYTES));
 
     // Load precomputed evaluations for hardest-to-compute positions.
-    // Evaluations for further positions will be calculated on the fly when needed.
+    // Evaluations for further positions will be calculated on the fly when needed. The rule set
+    // is part of the position hash, so evaluations of different variants never mix. Files from
+    // an older hashing scheme would only give wrong lookups, they are skipped until regenerated.
+    // So are files computed with color swaps, as the webapp's positions are hashed without them.
     let eval = use_mut_ref(|| {
         const EVAL_BIN: &[u8] = include_bytes!("../assets/eval.bin");
         let config = bincode::config::standard();
-        let (map, _len): (HashMap<u64, Evaluation>, usize) =
+        let (eval_file, _len): (EvalFile, usize) =
             bincode::decode_from_slice(EVAL_BIN, config).unwrap();
-        map
+        let mut cache = EvalCache::with_megabytes(utils::EVAL_CACHE_MEGABYTES);
+        if eval_file.is_current() && !eval_file.color_swap {
+            utils::cache_evaluations(&mut cache, eval_file.evaluations);
+        }
+        cache
     });
 
-    // Save game state to LocalStorage on any change
-    {
-        let game_state = game_state.clone();
-        use_effect_with(game_state.clone(), move |gs| {
-            let bin = bincode::encode_to_vec(&**gs, bincode::config::standard()).unwrap();
-            let hex = utils::bytes_to_hex(&bin);
+    // Save the game record to LocalStorage on any change
+    use_effect_with(
+        (game_state.clone(), history.clone()),
+        move |(gs, history)| {
             if let Some(storage) = window().and_then(|w| w.local_storage().ok().flatten()) {
-                storage.set_item(STORAGE_KEY, &hex).ok();
+                let record = GameRecord::from_history(gs, history);
+                storage.set_item(STORAGE_KEY, &record.to_string()).ok();
             }
             || ()
-        });
-    }
+        },
+    );
+
+    let player_panel = |player: &Player| {
+        html! {
+            <PlayerPanel
+                key={player.id}
+                game_state={game_state.clone()}
+                player={*player}
+                history={history.clone()}
+                eval={eval.clone()}
+                undecided={undecided.clone()}
+                search={*search}
+                drop_color={drop_color.clone()}
+            />
+        }
+    };
 
     html! {
         <div class="app">
@@ -75,19 +104,19 @@ pub fn app() -> Html {
             <OneshotProvider<EvaluationTask> path="/rubik-cage/worker.js">
                 <HoveredMoveProvider>
                     <div class="game-area">
-                        <PlayerPanel
-                            game_state={game_state.clone()}
-                            player={game_state.players[0]}
-                            history={history.clone()}
-                            eval={eval.clone()}
-                        />
-                        <Cage game_state={game_state.clone()} history={history.clone()} />
-                        <PlayerPanel
+                        // Players alternate between the columns left and right of the cage
+                        <div class="player-column">
+                            { for game_state.players.iter().step_by(2).map(player_panel) }
+                        </div>
+                        <Cage
                             game_state={game_state.clone()}
-                            player={game_state.players[1]}
                             history={history.clone()}
-                            eval={eval.clone()}
+                            drop_color={drop_color.clone()}
+                            search={search.clone()}
                         />
+                        <div class="player-column">
+                            { for game_state.players.iter().skip(1).step_by(2).map(player_panel) }
+                        </div>
                     </div>
                 </HoveredMoveProvider>
             </OneshotProvider<EvaluationTask>>
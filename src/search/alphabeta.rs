@@ -0,0 +1,336 @@
+//! Negamax search with alpha-beta pruning, a transposition table and iterative deepening. Unlike
+//! `naive::evaluate`, it evaluates a single position and only as deep as asked, so it answers
+//! quickly for positions whose game graph is too big to search exhaustively.
+//!
+//! Scores are from the point of view of the player to move: `WIN` minus the number of moves from
+//! the root of the search for a win, its negation for a loss and 0 for a draw. Positions whose
+//! outcome isn't decided within the depth of the search score as draws. Cycles need no special
+//! care since the depth bounds the search; the wins and losses found are the same as those of
+//! `retrograde::evaluate` up to that depth.
+
+use crate::{
+    core::{game::GameState, r#move::Move},
+    search::{naive::Evaluation, table::TranspositionTable},
+};
+
+const WIN: i32 = 1_000_000;
+/// Scores beyond this are wins, the ones below its negation losses.
+const WIN_THRESHOLD: i32 = WIN - 10_000;
+
+/// How the score of a table entry relates to the true score of the position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Bound {
+    Exact,
+    /// The search failed high: the score is at least the one stored.
+    Lower,
+    /// The search failed low: the score is at most the one stored.
+    Upper,
+}
+
+/// Result of searching a position `depth` moves deep. Win and loss scores count the moves from
+/// the position itself rather than from the root of the search.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    depth: usize,
+    score: i32,
+    bound: Bound,
+    /// Searched first when the position is searched again. Positions sharing a hash may be
+    /// oriented differently, so the move is only a hint.
+    best_move: Option<Move>,
+}
+
+/// Outcome of `Engine::search`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SearchResult {
+    /// Evaluation of the position, a draw if neither player can force a win within `depth`
+    /// moves.
+    pub evaluation: Evaluation,
+    /// Best move found in the orientation of the searched position, `None` if the game is over.
+    pub best_move: Option<Move>,
+    /// Depth of the last iteration, which may stop short of the maximum once the game is decided.
+    pub depth: usize,
+    /// Number of positions searched in all iterations.
+    pub nodes: u64,
+}
+
+/// Keeps the transposition table between searches, so that searching positions of the same game
+/// one after the other reuses earlier results.
+pub struct Engine {
+    table: TranspositionTable<Entry>,
+    nodes: u64,
+    /// Best move of the root of the last iteration, in the orientation of the searched position.
+    root_move: Option<Move>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    /// Memory budget of the transposition table of `new`.
+    pub const DEFAULT_MEGABYTES: usize = 16;
+
+    pub fn new() -> Self {
+        Self::with_megabytes(Self::DEFAULT_MEGABYTES)
+    }
+
+    /// An engine whose transposition table takes up about `megabytes` of memory.
+    pub fn with_megabytes(megabytes: usize) -> Self {
+        Self {
+            table: TranspositionTable::with_megabytes(megabytes),
+            nodes: 0,
+            root_move: None,
+        }
+    }
+
+    /// Searches `game_state` one move deeper at a time until the game is decided or `max_depth`
+    /// moves deep. Only for two players.
+    pub fn search(&mut self, game_state: &GameState, max_depth: usize) -> SearchResult {
+        assert!(
+            game_state.players.len() == 2,
+            "Alpha-beta search needs two players"
+        );
+        let mut game_state = game_state.clone();
+        let player_id = game_state.player_to_move.id;
+        self.nodes = 0;
+        self.table.new_search();
+        let mut result = SearchResult {
+            evaluation: Evaluation {
+                winner: None,
+                moves_to_wl: -1,
+            },
+            best_move: None,
+            depth: 0,
+            nodes: 0,
+        };
+
+        for depth in 1..=max_depth {
+            let score = self.negamax(&mut game_state, depth, 0, -WIN, WIN);
+            result.depth = depth;
+            result.best_move = self.root_move;
+            result.evaluation = match score {
+                _ if score > WIN_THRESHOLD => Evaluation {
+                    winner: Some(player_id),
+                    moves_to_wl: (WIN - score) as isize,
+                },
+                _ if score < -WIN_THRESHOLD => Evaluation {
+                    winner: Some(player_id ^ 1),
+                    moves_to_wl: (WIN + score) as isize,
+                },
+                _ => Evaluation {
+                    winner: None,
+                    moves_to_wl: -1,
+                },
+            };
+            if result.evaluation.winner.is_some() || game_state.is_over() {
+                break;
+            }
+        }
+        result.nodes = self.nodes;
+        result
+    }
+
+    /// Score of `game_state` searched `depth` moves deep, `ply` moves from the root of the search,
+    /// within the window `alpha..beta`. Scores outside the window are bounds of the true score.
+    fn negamax(
+        &mut self,
+        game_state: &mut GameState,
+        depth: usize,
+        ply: i32,
+        mut alpha: i32,
+        beta: i32,
+    ) -> i32 {
+        self.nodes += 1;
+        if game_state.is_over() {
+            return match game_state.won() {
+                Some((winner, _)) if winner.id == game_state.player_to_move.id => WIN - ply,
+                Some(_) => -(WIN - ply),
+                None => 0,
+            };
+        }
+        if depth == 0 {
+            return 0;
+        }
+
+        let hash = game_state.zobrist_hash;
+        let mut hint = None;
+        if let Some(entry) = self.table.get(hash) {
+            hint = entry.best_move;
+            // The root is searched for its best move, which the entry may have for a symmetric
+            // position
+            if ply > 0 && entry.depth >= depth {
+                let score = from_table(entry.score, ply);
+                match entry.bound {
+                    Bound::Exact => return score,
+                    Bound::Lower if score >= beta => return score,
+                    Bound::Upper if score <= alpha => return score,
+                    _ => {}
+                }
+            }
+        }
+
+        let mut moves = game_state.legal_moves();
+        if let Some(i) = hint.and_then(|hint| moves.iter().position(|&m| m == hint)) {
+            moves[..=i].rotate_right(1);
+        }
+
+        let original_alpha = alpha;
+        let mut best: Option<(i32, Move)> = None;
+        for m in moves {
+            let undo = game_state.apply_move_normalize(m).unwrap();
+            let score = -self.negamax(game_state, depth - 1, ply + 1, -beta, -alpha);
+            game_state.undo_move(undo);
+            if best.is_none_or(|(best, _)| score > best) {
+                best = Some((score, m));
+            }
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                break;
+            }
+        }
+        // Without moves, nobody can win
+        let (score, best_move) = best.map_or((0, None), |(score, m)| (score, Some(m)));
+        if ply == 0 {
+            self.root_move = best_move;
+        }
+
+        let bound = if score <= original_alpha {
+            Bound::Upper
+        } else if score >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        let entry = Entry {
+            depth,
+            score: to_table(score, ply),
+            bound,
+            best_move,
+        };
+        self.table
+            .insert(hash, entry, depth.min(u8::MAX as usize) as u8);
+        score
+    }
+}
+
+/// Converts a score counting moves from the root to one counting moves from the position.
+fn to_table(score: i32, ply: i32) -> i32 {
+    match score {
+        _ if score > WIN_THRESHOLD => score + ply,
+        _ if score < -WIN_THRESHOLD => score - ply,
+        _ => score,
+    }
+}
+
+/// The inverse of `to_table`.
+fn from_table(score: i32, ply: i32) -> i32 {
+    match score {
+        _ if score > WIN_THRESHOLD => score - ply,
+        _ if score < -WIN_THRESHOLD => score + ply,
+        _ => score,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::cage::{Cage, Symmetry},
+        search::{naive::lookup, retrograde},
+    };
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_3_0_game_won_by_p1() {
+        let game = GameState::new(3, 0);
+        let result = Engine::new().search(&game, 10);
+        assert_eq!(
+            result.evaluation,
+            Evaluation {
+                winner: Some(0),
+                moves_to_wl: 5
+            }
+        );
+        assert_eq!(result.depth, 5);
+
+        // The best move keeps the win as fast
+        let mut child = game.clone();
+        child.apply_move(result.best_move.unwrap()).unwrap();
+        let child_result = Engine::new().search(&child, 10);
+        assert_eq!(child_result.evaluation.moves_to_wl, 4);
+    }
+
+    #[test]
+    fn test_reused_on_mirror_image() {
+        // Blue can complete a line at once, with another drop in the mirror image
+        let game: GameState = "....#....,....#....,BB..#RR.. B3/R3 1 - 3".parse().unwrap();
+        let reflection = Symmetry {
+            reflected: true,
+            rotations: 0,
+        };
+        let size = game.cage.size();
+        let mut cage = Cage::with_shape(game.cage.shape());
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    cage.set(reflection.apply([x, y, z], size), game.cage.get([x, y, z]));
+                }
+            }
+        }
+        let mirrored = game.clone().with_cage(cage);
+        assert_eq!(mirrored.zobrist_hash, game.zobrist_hash);
+
+        let mut engine = Engine::new();
+        for state in [&game, &mirrored, &game] {
+            let result = engine.search(state, 3);
+            let mut child = state.clone();
+            child.apply_move(result.best_move.unwrap()).unwrap();
+            assert_eq!(child.won().unwrap().0.id, 0);
+        }
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let game = GameState::new(3, 0);
+        let result = Engine::new().search(&game, 4);
+        assert_eq!(result.evaluation.winner, None);
+        assert_eq!(result.depth, 4);
+        assert!(result.best_move.is_some());
+    }
+
+    #[test]
+    fn test_agrees_with_retrograde() {
+        let mut rng = StdRng::seed_from_u64(22);
+        let game = GameState::new(2, 2);
+        let exact = retrograde::evaluate(&game);
+        let max_depth = 6;
+        let mut engine = Engine::new();
+        for _ in 0..30 {
+            let mut state = game.clone();
+            for _ in 0..rng.random_range(0..8) {
+                if state.is_over() {
+                    break;
+                }
+                let moves = state.legal_moves();
+                state
+                    .apply_move_normalize(moves[rng.random_range(0..moves.len())])
+                    .unwrap();
+            }
+            let expected = lookup(&exact, &state).unwrap();
+            let result = engine.search(&state, max_depth);
+            if expected.winner.is_some() && expected.moves_to_wl <= max_depth as isize {
+                assert_eq!(result.evaluation, expected);
+            } else {
+                // Wins may be found beyond the depth through the table, but never wrong ones
+                assert!(
+                    result
+                        .evaluation
+                        .winner
+                        .is_none_or(|w| Some(w) == expected.winner)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! Monte Carlo tree search with UCT selection, an anytime engine for games whose graph is out of
+//! reach of the exhaustive searches, e.g. with more players, bigger cages or more cubies. Each
+//! iteration walks down the tree, adds one position to it and plays the game out from there, then
+//! credits the result to the positions on the way.
+//!
+//! The tree is keyed by the hashes of the normalized positions, so positions reached by different
+//! move orders or symmetric moves share their statistics. Positions already on the way down are
+//! skipped, which keeps the walk out of cycles.
+
+use crate::core::{game::GameState, r#move::Move};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+/// Moves after which a playout counts as a draw, since turns alone can go on forever.
+const MAX_PLAYOUT_MOVES: usize = 200;
+
+/// How the moves of a playout are chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rollout {
+    Random,
+    /// A move completing a line of the player to move if there is one, a random move otherwise.
+    /// Slower per playout, but the results are closer to those of stronger play.
+    Greedy,
+}
+
+/// When `Engine::search` stops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Budget {
+    Iterations(u64),
+    /// Needs a clock, which `std::time::Instant` doesn't provide in the browser: the webapp
+    /// should use `Iterations`.
+    Time(Duration),
+}
+
+/// Statistics of a position, in the frame of the colors its hash is stored for, see
+/// `naive::lookup`.
+#[derive(Clone, Debug)]
+struct Node {
+    visits: u32,
+    /// Total result of the playouts through the position for each player: 1 for a win and an
+    /// equal share for a draw.
+    rewards: Vec<f64>,
+}
+
+/// Statistics of a move of the searched position.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MoveStats {
+    pub r#move: Move,
+    /// Playouts through the position the move leads to, including those reaching it by other
+    /// moves.
+    pub visits: u32,
+    /// Average result of these playouts for the player to move in the searched position, 0 if
+    /// there were none.
+    pub win_rate: f64,
+}
+
+/// Outcome of `Engine::search`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    /// All legal moves of the searched position, in the order of `legal_moves`.
+    pub moves: Vec<MoveStats>,
+    pub iterations: u64,
+}
+
+impl SearchResult {
+    /// The move with the most visits, `None` if the game is over.
+    pub fn best_move(&self) -> Option<Move> {
+        self.moves
+            .iter()
+            .max_by_key(|stats| stats.visits)
+            .map(|stats| stats.r#move)
+    }
+}
+
+/// Keeps the tree between searches, so that searching positions of the same game one after the
+/// other reuses earlier playouts.
+pub struct Engine {
+    tree: HashMap<u64, Node>,
+    rng: StdRng,
+    rollout: Rollout,
+    exploration: f64,
+}
+
+impl Engine {
+    /// An engine with greedy playouts whose random choices follow from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            tree: HashMap::new(),
+            rng: StdRng::seed_from_u64(seed),
+            rollout: Rollout::Greedy,
+            exploration: std::f64::consts::SQRT_2,
+        }
+    }
+
+    pub fn with_rollout(mut self, rollout: Rollout) -> Self {
+        self.rollout = rollout;
+        self
+    }
+
+    /// How much UCT favors moves visited less over moves that did well so far.
+    pub fn with_exploration(mut self, exploration: f64) -> Self {
+        self.exploration = exploration;
+        self
+    }
+
+    /// Number of positions in the tree.
+    pub fn tree_size(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Runs playouts from `game_state` until the budget is spent. Any number of players.
+    pub fn search(&mut self, game_state: &GameState, budget: Budget) -> SearchResult {
+        let mut root = game_state.clone();
+        root.normalize();
+        // Only time budgets read the clock
+        let start = match budget {
+            Budget::Time(_) => Some(Instant::now()),
+            Budget::Iterations(_) => None,
+        };
+        let mut iterations = 0;
+        while !game_state.is_over()
+            && match budget {
+                Budget::Iterations(limit) => iterations < limit,
+                Budget::Time(limit) => start.is_some_and(|start| start.elapsed() < limit),
+            }
+        {
+            self.iterate(&root);
+            iterations += 1;
+        }
+
+        let player_id = game_state.player_to_move.id;
+        let moves = if game_state.is_over() {
+            Vec::new()
+        } else {
+            game_state.legal_moves()
+        };
+        let moves = moves
+            .into_iter()
+            .map(|m| {
+                let mut child = game_state.clone();
+                child.apply_move_normalize(m).unwrap();
+                let (visits, win_rate) = match self.tree.get(&child.zobrist_hash) {
+                    Some(node) if node.visits > 0 => (
+                        node.visits,
+                        node.rewards[frame(&child, player_id)] / node.visits as f64,
+                    ),
+                    _ => (0, 0.0),
+                };
+                MoveStats {
+                    r#move: m,
+                    visits,
+                    win_rate,
+                }
+            })
+            .collect();
+        SearchResult { moves, iterations }
+    }
+
+    /// Walks down from `root` choosing moves by UCT until a position out of the tree or the end of
+    /// the game, adds the position to the tree, plays it out and credits the result.
+    fn iterate(&mut self, root: &GameState) {
+        let mut state = root.clone();
+        let mut path = vec![(state.zobrist_hash, state.colors_swapped())];
+        let mut on_path = HashSet::from([state.zobrist_hash]);
+
+        let result = loop {
+            if state.is_over() {
+                break rewards(&state);
+            }
+            let Some(visits) = self.tree.get(&state.zobrist_hash).map(|node| node.visits) else {
+                self.tree.insert(
+                    state.zobrist_hash,
+                    Node {
+                        visits: 0,
+                        rewards: vec![0.0; state.players.len()],
+                    },
+                );
+                break self.playout(state);
+            };
+            let Some(m) = self.select(&mut state, visits, &on_path) else {
+                // Every move leads back to the way down
+                break draw(state.players.len());
+            };
+            state.apply_move_normalize(m).unwrap();
+            path.push((state.zobrist_hash, state.colors_swapped()));
+            on_path.insert(state.zobrist_hash);
+        };
+
+        for (hash, colors_swapped) in path {
+            let node = self.tree.entry(hash).or_insert_with(|| Node {
+                visits: 0,
+                rewards: vec![0.0; result.len()],
+            });
+            node.visits += 1;
+            for (id, reward) in result.iter().enumerate() {
+                let id = if colors_swapped { id ^ 1 } else { id };
+                node.rewards[id] += reward;
+            }
+        }
+    }
+
+    /// The move of `state` with the highest UCT value for the player to move, moves to positions
+    /// out of the tree first. Moves to positions in `on_path` are skipped.
+    fn select(
+        &mut self,
+        state: &mut GameState,
+        parent_visits: u32,
+        on_path: &HashSet<u64>,
+    ) -> Option<Move> {
+        let player_id = state.player_to_move.id;
+        let log_visits = (parent_visits.max(1) as f64).ln();
+        let mut best: Option<(f64, Move)> = None;
+        let mut unvisited = Vec::new();
+        for m in state.legal_moves() {
+            let undo = state.apply_move_normalize(m).unwrap();
+            if !on_path.contains(&state.zobrist_hash) {
+                match self.tree.get(&state.zobrist_hash) {
+                    Some(node) if node.visits > 0 => {
+                        let visits = node.visits as f64;
+                        let value = node.rewards[frame(state, player_id)] / visits
+                            + self.exploration * (log_visits / visits).sqrt();
+                        if best.is_none_or(|(best, _)| value > best) {
+                            best = Some((value, m));
+                        }
+                    }
+                    _ => unvisited.push(m),
+                }
+            }
+            state.undo_move(undo);
+        }
+        if unvisited.is_empty() {
+            best.map(|(_, m)| m)
+        } else {
+            Some(unvisited[self.rng.random_range(0..unvisited.len())])
+        }
+    }
+
+    /// Plays `state` out with moves chosen by `self.rollout` and returns the rewards.
+    fn playout(&mut self, mut state: GameState) -> Vec<f64> {
+        for _ in 0..MAX_PLAYOUT_MOVES {
+            if state.is_over() {
+                return rewards(&state);
+            }
+            let moves = state.legal_moves();
+            if moves.is_empty() {
+                break;
+            }
+            let winning = match self.rollout {
+                Rollout::Random => None,
+                Rollout::Greedy => {
+                    let mover = state.player_to_move.id;
+                    moves.iter().copied().find(|&m| {
+                        let undo = state.apply_move(m).unwrap();
+                        let won = state.won().is_some_and(|(winner, _)| winner.id == mover);
+                        state.undo_move(undo);
+                        won
+                    })
+                }
+            };
+            let m = winning.unwrap_or_else(|| moves[self.rng.random_range(0..moves.len())]);
+            state.apply_move(m).unwrap();
+        }
+        draw(state.players.len())
+    }
+}
+
+/// Index of the rewards of `player_id` in the node of `state`.
+fn frame(state: &GameState, player_id: u8) -> usize {
+    if state.colors_swapped() {
+        (player_id ^ 1) as usize
+    } else {
+        player_id as usize
+    }
+}
+
+/// Rewards of a finished game for each player.
+fn rewards(state: &GameState) -> Vec<f64> {
+    let players = state.players.len();
+    match state.won() {
+        Some((winner, _)) => (0..players)
+            .map(|id| if id == winner.id as usize { 1.0 } else { 0.0 })
+            .collect(),
+        None => draw(players),
+    }
+}
+
+/// Rewards of a drawn game: the win shared among all players.
+fn draw(players: usize) -> Vec<f64> {
+    vec![1.0 / players as f64; players]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::{naive::lookup, retrograde};
+
+    #[test]
+    fn test_finds_immediate_win() {
+        // Blue can complete a line at once
+        let game: GameState = "....#....,....#....,BB..#RR.. B3/R3 1 - 3".parse().unwrap();
+        for game in [game.clone(), game.with_color_swap()] {
+            let result = Engine::new(1).search(&game, Budget::Iterations(500));
+            let best = result.best_move().unwrap();
+            let stats = result.moves.iter().find(|s| s.r#move == best).unwrap();
+            assert_eq!(stats.win_rate, 1.0);
+            let mut child = game.clone();
+            child.apply_move(best).unwrap();
+            assert_eq!(child.won().unwrap().0.id, 0);
+        }
+    }
+
+    #[test]
+    fn test_3_0_game_best_move_wins() {
+        let game = GameState::new(3, 0);
+        let exact = retrograde::evaluate(&game);
+        for rollout in [Rollout::Random, Rollout::Greedy] {
+            let result = Engine::new(2)
+                .with_rollout(rollout)
+                .search(&game, Budget::Iterations(500));
+            assert_eq!(result.iterations, 500);
+            assert_eq!(result.moves.len(), game.legal_moves().len());
+            let mut child = game.clone();
+            child
+                .apply_move_normalize(result.best_move().unwrap())
+                .unwrap();
+            assert_eq!(lookup(&exact, &child).unwrap().winner, Some(0));
+        }
+    }
+
+    #[test]
+    fn test_three_players() {
+        let game = GameState::with_cubies(&[2, 2, 2]);
+        let mut engine = Engine::new(3);
+        let result = engine.search(&game, Budget::Iterations(200));
+        assert_eq!(result.iterations, 200);
+        assert!(engine.tree_size() > 1);
+        // Every playout goes through one of the moves, or more with transpositions
+        let visits: u64 = result.moves.iter().map(|s| s.visits as u64).sum();
+        assert!(visits >= result.iterations);
+        assert!(
+            result
+                .moves
+                .iter()
+                .all(|s| (0.0..=1.0).contains(&s.win_rate))
+        );
+    }
+
+    #[test]
+    fn test_time_budget_stops() {
+        let game = GameState::new(12, 12);
+        let budget = Duration::from_millis(20);
+        let start = Instant::now();
+        let result = Engine::new(5).search(&game, Budget::Time(budget));
+        // The budget is checked between iterations, which take far less than a second
+        let elapsed = start.elapsed();
+        assert!(elapsed >= budget && elapsed < budget + Duration::from_secs(1));
+        assert!(result.iterations >= 1);
+        assert!(game.legal_moves().contains(&result.best_move().unwrap()));
+    }
+
+    #[test]
+    fn test_game_over() {
+        let mut game: GameState = "....#....,....#....,BB..#RR.. B3/R3 1 - 3".parse().unwrap();
+        let result = Engine::new(4).search(&game, Budget::Iterations(500));
+        game.apply_move(result.best_move().unwrap()).unwrap();
+        assert!(game.is_over());
+        let result = Engine::new(4).search(&game, Budget::Iterations(500));
+        assert_eq!(result.iterations, 0);
+        assert_eq!(result.best_move(), None);
+    }
+}
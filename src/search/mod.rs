@@ -1 +1,5 @@
-pub mod naive;
\ No newline at end of file
+pub mod alphabeta;
+pub mod mcts;
+pub mod naive;
+pub mod retrograde;
+pub mod table;
@@ -1,21 +1,50 @@
-use crate::core::game::GameState;
+use crate::{
+    core::{game::GameState, rules::RuleSet, zobrist},
+    search::table::TranspositionTable,
+};
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
 pub struct Evaluation {
-    /// 1 = P1 win, -1 = P2 win, 0 = draw
-    pub score: isize,
+    /// Id of the player who wins under optimal play, `None` if the position is drawn.
+    pub winner: Option<u8>,
     /// If the position is drawn, -1. If the position is won (lost), upper bound on number of
     /// moves to force a win (lower bound on number of moves to lose). If the search was done
     /// without pruning the bounds are exact under optimal play.
     pub moves_to_wl: isize,
 }
 
+impl Evaluation {
+    /// Whether `self` is a better outcome than `other` for `player`: a win, preferably fast, is
+    /// better than a draw, which is better than a loss, preferably slow. With more than two
+    /// players, a loss is a loss no matter who wins.
+    pub fn is_better_for(&self, player: u8, other: &Evaluation) -> bool {
+        let rank = |eval: &Evaluation| match eval.winner {
+            Some(winner) if winner == player => (2, -eval.moves_to_wl),
+            None => (1, 0),
+            Some(_) => (0, eval.moves_to_wl),
+        };
+        rank(self) > rank(other)
+    }
+
+    /// The evaluation of the position with the colors of the two players swapped, see
+    /// `GameState::with_color_swap`.
+    pub fn with_colors_swapped(self) -> Self {
+        Self {
+            winner: self.winner.map(|id| id ^ 1),
+            ..self
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SearchMode {
     /// Search all reachable positions. For instance, this will go past one move wins (as if the
@@ -31,79 +60,319 @@ pub enum SearchMode {
 
 impl std::fmt::Display for Evaluation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "score: {}, moves_to_wl: {}",
-            self.score, self.moves_to_wl
-        )
+        match self.winner {
+            Some(id) => write!(
+                f,
+                "winner: player {}, moves_to_wl: {}",
+                id + 1,
+                self.moves_to_wl
+            ),
+            None => write!(f, "draw"),
+        }
+    }
+}
+
+/// Where a search keeps its evaluations: a `HashMap` keeps all of them, a `TranspositionTable` as
+/// many as fit its memory budget.
+pub trait EvaluationStore {
+    fn get_evaluation(&self, hash: u64) -> Option<Evaluation>;
+
+    /// `depth` tells how valuable the evaluation is to keep if not all of them can be.
+    fn insert_evaluation(&mut self, hash: u64, eval: Evaluation, depth: u8);
+}
+
+impl EvaluationStore for HashMap<u64, Evaluation> {
+    fn get_evaluation(&self, hash: u64) -> Option<Evaluation> {
+        self.get(&hash).copied()
+    }
+
+    fn insert_evaluation(&mut self, hash: u64, eval: Evaluation, _depth: u8) {
+        self.insert(hash, eval);
     }
 }
 
+impl EvaluationStore for TranspositionTable<Evaluation> {
+    fn get_evaluation(&self, hash: u64) -> Option<Evaluation> {
+        self.get(hash)
+    }
+
+    fn insert_evaluation(&mut self, hash: u64, eval: Evaluation, depth: u8) {
+        self.insert(hash, eval, depth);
+    }
+}
+
+/// Evaluation of `game_state` among `evaluations` computed by `evaluate` for a game with the same
+/// `GameState::with_color_swap` setting. Positions with the colors swapped share an evaluation,
+/// which is stored for the one whose hash is not `colors_swapped`.
+pub fn lookup(evaluations: &impl EvaluationStore, game_state: &GameState) -> Option<Evaluation> {
+    let eval = evaluations.get_evaluation(game_state.zobrist_hash)?;
+    Some(if game_state.colors_swapped() {
+        eval.with_colors_swapped()
+    } else {
+        eval
+    })
+}
+
+/// Stores the evaluation of `game_state`, found `current_depth` moves from the root of the search,
+/// so that `lookup` finds it.
+fn store(
+    evaluations: &mut impl EvaluationStore,
+    game_state: &GameState,
+    eval: Evaluation,
+    current_depth: usize,
+) {
+    let eval = if game_state.colors_swapped() {
+        eval.with_colors_swapped()
+    } else {
+        eval
+    };
+    // Evaluations closer to the root took more searching
+    let depth = u8::MAX.saturating_sub(current_depth.min(u8::MAX as usize) as u8);
+    evaluations.insert_evaluation(game_state.zobrist_hash, eval, depth);
+}
+
+/// Evaluates all positions reachable from `game_state`.
 pub fn evaluate(game_state: &GameState, mode: SearchMode) -> HashMap<u64, Evaluation> {
+    let mut evaluated = HashMap::new();
+    evaluate_into(game_state, mode, &mut evaluated);
+    evaluated
+}
+
+/// Evaluates `game_state`, keeping the evaluations of the positions searched in `evaluated`.
+/// With a `TranspositionTable`, the memory stays bounded but forgotten positions are searched
+/// again when reached again.
+pub fn evaluate_into(
+    game_state: &GameState,
+    mode: SearchMode,
+    evaluated: &mut impl EvaluationStore,
+) -> Option<Evaluation> {
     // visited tracks states seen in a *particular* game, to avoid searching cycles
     let mut visited = HashSet::new();
-    let mut evaluated = HashMap::new();
     let mut game_state = game_state.clone();
     game_state.normalize();
 
     // Start with no best win depth
-    minimax(&game_state, &mut visited, &mut evaluated, &mode, 0, None);
-    evaluated
+    minimax(&mut game_state, &mut visited, evaluated, &mode, 0, None)
+}
+
+/// Stack size of the threads searching with `minimax`, which recurses once per move of the game
+/// being searched.
+pub const STACK_SIZE: usize = 32 * 1024 * 1024;
+
+/// Number of independently locked parts of a `SharedEvaluations`.
+const NUM_SHARDS: usize = 64;
+
+/// Positions handed out per thread by `evaluate_parallel`, so that threads finishing early can
+/// pick up more work.
+const POSITIONS_PER_THREAD: usize = 16;
+
+/// Deepest level `evaluate_parallel` splits the search at.
+const MAX_SPLIT_DEPTH: usize = 8;
+
+/// Evaluations shared by the threads of `evaluate_parallel`. Positions are spread over shards
+/// with a lock each, so threads storing different positions rarely wait for each other.
+struct SharedEvaluations {
+    shards: Vec<Mutex<HashMap<u64, Evaluation>>>,
+}
+
+impl SharedEvaluations {
+    fn new() -> Self {
+        Self {
+            shards: (0..NUM_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, hash: u64) -> &Mutex<HashMap<u64, Evaluation>> {
+        &self.shards[(hash % NUM_SHARDS as u64) as usize]
+    }
+
+    fn into_map(self) -> HashMap<u64, Evaluation> {
+        let mut evaluated = HashMap::new();
+        for shard in self.shards {
+            evaluated.extend(shard.into_inner().unwrap());
+        }
+        evaluated
+    }
+}
+
+impl EvaluationStore for &SharedEvaluations {
+    fn get_evaluation(&self, hash: u64) -> Option<Evaluation> {
+        self.shard(hash).lock().unwrap().get(&hash).copied()
+    }
+
+    fn insert_evaluation(&mut self, hash: u64, eval: Evaluation, _depth: u8) {
+        self.shard(hash).lock().unwrap().insert(hash, eval);
+    }
+}
+
+/// A position to search on its own, along with the positions on the line leading to it.
+struct Split {
+    game_state: GameState,
+    visited: HashSet<u64>,
+}
+
+/// The search of `game_state` split `depth` moves away from it along lines without repetitions:
+/// the distinct positions reached at that depth or finished before it in the order `minimax`
+/// first reaches them, and the positions before them, each after the ones it leads to.
+fn split(game_state: &mut GameState, depth: usize) -> (Vec<Split>, Vec<Split>) {
+    let (mut splits, mut above) = (Vec::new(), Vec::new());
+    let (mut seen, mut visited) = (HashSet::new(), HashSet::new());
+    collect_splits(
+        game_state,
+        &mut visited,
+        depth,
+        &mut seen,
+        &mut splits,
+        &mut above,
+    );
+    (splits, above)
+}
+
+fn collect_splits(
+    game_state: &mut GameState,
+    visited: &mut HashSet<u64>,
+    depth: usize,
+    seen: &mut HashSet<u64>,
+    splits: &mut Vec<Split>,
+    above: &mut Vec<Split>,
+) {
+    let at_split = depth == 0 || game_state.is_over();
+    if !seen.insert(game_state.zobrist_hash) && at_split {
+        return;
+    }
+    let position = Split {
+        game_state: game_state.clone(),
+        visited: visited.clone(),
+    };
+    if at_split {
+        splits.push(position);
+        return;
+    }
+    visited.insert(game_state.zobrist_hash);
+    for m in game_state.legal_moves() {
+        let undo = game_state.apply_move_normalize(m).unwrap();
+        if !visited.contains(&game_state.zobrist_hash) {
+            collect_splits(game_state, visited, depth - 1, seen, splits, above);
+        }
+        game_state.undo_move(undo);
+    }
+    visited.remove(&game_state.zobrist_hash);
+    above.push(position);
 }
 
+/// Searches `position` with `minimax` in `SearchMode::Full`, unless it is already evaluated.
+fn search_split(position: &Split, evaluated: &mut impl EvaluationStore) {
+    let (mut game_state, mut visited) = (position.game_state.clone(), position.visited.clone());
+    let depth = visited.len();
+    minimax(
+        &mut game_state,
+        &mut visited,
+        evaluated,
+        &SearchMode::Full,
+        depth,
+        None,
+    );
+}
+
+/// Evaluates all positions reachable from `game_state` like `evaluate` in `SearchMode::Full`, on
+/// `threads` threads. The search is split a few moves from the root into positions the threads
+/// take turns picking up and searching into a shared table, before the positions leading to them
+/// are evaluated from it.
+///
+/// Positions on the line being searched count as draws, so where lines of different threads cross
+/// a cycle, a position can be evaluated as reached along another line than in `evaluate`. Games
+/// without such cycles are evaluated the same.
+pub fn evaluate_parallel(game_state: &GameState, threads: usize) -> HashMap<u64, Evaluation> {
+    if threads <= 1 {
+        return evaluate(game_state, SearchMode::Full);
+    }
+    let mut root = game_state.clone();
+    root.normalize();
+
+    // Split deep enough to keep every thread busy
+    let (mut splits, mut above) = split(&mut root, 1);
+    for depth in 2..=MAX_SPLIT_DEPTH {
+        if splits.len() >= threads * POSITIONS_PER_THREAD {
+            break;
+        }
+        let deeper = split(&mut root, depth);
+        if deeper.0.len() <= splits.len() {
+            break;
+        }
+        (splits, above) = deeper;
+    }
+
+    let shared = SharedEvaluations::new();
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            thread::Builder::new()
+                .stack_size(STACK_SIZE)
+                .spawn_scoped(scope, || {
+                    while let Some(position) = splits.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        search_split(position, &mut &shared);
+                    }
+                })
+                .unwrap();
+        }
+    });
+
+    // The threads skipped the positions on the lines leading to their splits: evaluate those
+    // from the evaluations below them, ending with the root
+    for position in &above {
+        search_split(position, &mut &shared);
+    }
+    shared.into_map()
+}
+
+/// Evaluates `game_state`, applying and taking back moves in place to search the children.
 pub fn minimax(
-    game_state: &GameState,
+    game_state: &mut GameState,
     visited: &mut HashSet<u64>,
-    evaluated: &mut HashMap<u64, Evaluation>,
+    evaluated: &mut impl EvaluationStore,
     mode: &SearchMode,
     current_depth: usize,
     best_win_depth: Option<usize>,
 ) -> Option<Evaluation> {
     // 1. Check if we've either seen this position, win is on board or we can prune
-    if let Some(&eval) = evaluated.get(&game_state.zobrist_hash) {
+    if let Some(eval) = lookup(evaluated, game_state) {
         return Some(eval);
     }
 
     // If we've seen a shorter win somewhere upwards in the tree, prune
-    if mode != &SearchMode::Full {
-        if let Some(win_depth) = best_win_depth {
-            if current_depth >= win_depth {
-                return None;
-            }
-        }
+    if mode != &SearchMode::Full
+        && let Some(win_depth) = best_win_depth
+        && current_depth >= win_depth
+    {
+        return None;
     }
 
     visited.insert(game_state.zobrist_hash);
 
-    if let Some((winner, _)) = game_state.won() {
-        let score = if winner.id == game_state.players[0].id {
-            1 // First player win
-        } else {
-            -1 // Second player win
-        };
+    if game_state.is_over() {
         visited.remove(&game_state.zobrist_hash);
-        let eval = Evaluation {
-            score,
-            moves_to_wl: 0,
+        // Lines of several players at once can end the game without a winner
+        let eval = match game_state.won() {
+            Some((winner, _)) => Evaluation {
+                winner: Some(winner.id),
+                moves_to_wl: 0,
+            },
+            None => Evaluation {
+                winner: None,
+                moves_to_wl: -1,
+            },
         };
-        evaluated.insert(game_state.zobrist_hash, eval);
+        store(evaluated, game_state, eval, current_depth);
         return Some(eval);
     }
 
     // 2. If we didn't resolve the position yet, evaluate all children
-    // Each player can lose or better
-    let mut best_score = match game_state.player_to_move.id {
-        0 => -1,
-        1 => 1,
-        _ => unreachable!(),
-    };
-
-    // If we're P1 and there are wins, track the fastest. If loss is the best we can do, track
-    // the slowest. If draw mark as -1. For P2 vice versa.
-    let mut moves_to_wl_p1win_max = 0;
-    let mut moves_to_wl_p1win_min = isize::MAX;
-    let mut moves_to_wl_p1loss_max = 0;
-    let mut moves_to_wl_p1loss_min = isize::MAX;
+    // Track the best child evaluation from the point of view of the player to move: the fastest
+    // win if there are wins, the slowest loss if loss is the best we can do.
+    let player_id = game_state.player_to_move.id;
+    let mut best: Option<Evaluation> = None;
 
     let mut no_children = true;
     let moves = game_state.legal_moves();
@@ -111,140 +380,153 @@ pub fn minimax(
     // If we see a 1 move win, prune everything else unless in exhaustive search mode
     if mode != &SearchMode::Full {
         for m in &moves {
-            let mut new_game_state = game_state.clone();
-            new_game_state.apply_move_normalize(*m).unwrap();
-            if let Some((winner, _)) = new_game_state.won() {
+            let undo = game_state.apply_move_normalize(*m).unwrap();
+            if let Some((winner, _)) = game_state.won() {
                 // this will not recurse since the game is won
                 minimax(
-                    &new_game_state,
+                    game_state,
                     visited,
                     evaluated,
                     mode,
                     current_depth + 1,
                     best_win_depth,
                 );
+                game_state.undo_move(undo);
                 // now prune if the winning player
-                if game_state.player_to_move.id == winner.id {
+                if player_id == winner.id {
                     let eval = Evaluation {
-                        score: match game_state.player_to_move.id {
-                            0 => 1,
-                            1 => -1,
-                            _ => unreachable!(),
-                        },
+                        winner: Some(player_id),
                         moves_to_wl: 1,
                     };
                     visited.remove(&game_state.zobrist_hash);
-                    evaluated.insert(game_state.zobrist_hash, eval);
+                    store(evaluated, game_state, eval, current_depth);
                     return Some(eval);
                 }
+            } else {
+                game_state.undo_move(undo);
             }
         }
     }
 
     let mut local_best_win_depth = best_win_depth;
     for m in moves {
-        let mut new_game_state = game_state.clone();
-        new_game_state.apply_move_normalize(m).unwrap();
-        if visited.contains(&new_game_state.zobrist_hash) {
+        let undo = game_state.apply_move_normalize(m).unwrap();
+        if visited.contains(&game_state.zobrist_hash) {
+            game_state.undo_move(undo);
             continue;
         }
 
         no_children = false;
         let eval = minimax(
-            &new_game_state,
+            game_state,
             visited,
             evaluated,
             mode,
             current_depth + 1,
             local_best_win_depth,
         );
-        if eval.is_none() {
+        game_state.undo_move(undo);
+        let Some(eval) = eval else {
             // Pruned
             continue;
-        }
-        let eval = eval.unwrap();
+        };
 
         // Track fastest win
-        if eval.score == 1 {
-            moves_to_wl_p1win_max = moves_to_wl_p1win_max.max(eval.moves_to_wl);
-            moves_to_wl_p1win_min = moves_to_wl_p1win_min.min(eval.moves_to_wl);
+        if eval.winner == Some(player_id) {
             // Update local_best_win_depth if we found a faster win
             let win_depth = current_depth + 1 + eval.moves_to_wl as usize;
-            if local_best_win_depth.is_none() || win_depth < local_best_win_depth.unwrap() {
+            if local_best_win_depth.is_none_or(|depth| win_depth < depth) {
                 local_best_win_depth = Some(win_depth);
             }
         }
 
-        if eval.score == -1 {
-            moves_to_wl_p1loss_max = moves_to_wl_p1loss_max.max(eval.moves_to_wl);
-            moves_to_wl_p1loss_min = moves_to_wl_p1loss_min.min(eval.moves_to_wl);
+        if best.is_none_or(|best| eval.is_better_for(player_id, &best)) {
+            best = Some(eval);
         }
-
-        if game_state.player_to_move.id == 0 {
-            best_score = best_score.max(eval.score);
-            if best_score == 1 && mode == &SearchMode::Pruned {
-                break;
-            }
-        } else {
-            best_score = best_score.min(eval.score);
-            if best_score == -1 && mode == &SearchMode::Pruned {
-                break;
-            }
+        if eval.winner == Some(player_id) && mode == &SearchMode::Pruned {
+            break;
         }
     }
 
-    // We found no positions we haven't seen and no win along the way, so it must be draw
-    if no_children {
-        best_score = 0;
-    }
-
-    let moves_to_wl: isize = match (best_score, game_state.player_to_move.id) {
-        // P1 wins and is to move: track fastest win
-        (1, 0) => moves_to_wl_p1win_min + 1,
-        // P1 wins, P2 to move: track slowest loss
-        (1, 1) => moves_to_wl_p1win_max + 1,
-        // P2 wins, P1 to move: track slowest loss
-        (-1, 0) => moves_to_wl_p1loss_max + 1,
-        // P2 wins and is to move: track fastest win
-        (-1, 1) => moves_to_wl_p1loss_min + 1,
-        // Draw
-        _ => -1,
-    };
-
-    let eval = Evaluation {
-        score: best_score,
-        moves_to_wl,
+    let eval = match best {
+        // We found no positions we haven't seen and no win along the way, so it must be draw
+        _ if no_children => Evaluation {
+            winner: None,
+            moves_to_wl: -1,
+        },
+        Some(Evaluation {
+            winner: Some(winner),
+            moves_to_wl,
+        }) => Evaluation {
+            winner: Some(winner),
+            moves_to_wl: moves_to_wl + 1,
+        },
+        Some(draw) => draw,
+        // Every child was pruned: each player can lose or better, assume the next player wins
+        None => Evaluation {
+            winner: Some((player_id + 1) % game_state.players.len() as u8),
+            moves_to_wl: 1,
+        },
     };
 
     visited.remove(&game_state.zobrist_hash);
-    evaluated.insert(game_state.zobrist_hash, eval);
+    store(evaluated, game_state, eval, current_depth);
 
     Some(eval)
 }
 
-pub fn save_eval(map: &HashMap<u64, Evaluation>, path: &str) -> Result<(), Box<dyn Error>> {
+/// Evaluations as stored on disk, along with the rules they were computed under.
+#[derive(Debug, PartialEq, Encode, Decode)]
+pub struct EvalFile {
+    /// `zobrist::HASH_VERSION` at the time of the evaluation. The evaluations are keyed by
+    /// position hashes, which are meaningless under another version.
+    pub hash_version: u32,
+    pub rules: RuleSet,
+    /// Whether the evaluations were computed with `GameState::with_color_swap`. They need to be
+    /// looked up with `lookup` on positions hashed the same way.
+    pub color_swap: bool,
+    pub evaluations: HashMap<u64, Evaluation>,
+}
+
+impl EvalFile {
+    pub fn new(rules: RuleSet, color_swap: bool, evaluations: HashMap<u64, Evaluation>) -> Self {
+        Self {
+            hash_version: zobrist::HASH_VERSION,
+            rules,
+            color_swap,
+            evaluations,
+        }
+    }
+
+    /// Whether the evaluations can be looked up with the current position hashes.
+    pub fn is_current(&self) -> bool {
+        self.hash_version == zobrist::HASH_VERSION
+    }
+}
+
+pub fn save_eval(eval_file: &EvalFile, path: &str) -> Result<(), Box<dyn Error>> {
     let config = bincode::config::standard();
-    let encoded: Vec<u8> = bincode::encode_to_vec(map, config)?;
+    let encoded: Vec<u8> = bincode::encode_to_vec(eval_file, config)?;
     let mut file = File::create(path)?;
     file.write_all(&encoded)?;
 
     Ok(())
 }
 
-pub fn load_eval(path: &str) -> Result<HashMap<u64, Evaluation>, Box<dyn Error>> {
+pub fn load_eval(path: &str) -> Result<EvalFile, Box<dyn Error>> {
     let mut file = File::open(path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
     let config = bincode::config::standard();
-    let (decoded_map, _len): (HashMap<u64, Evaluation>, usize) =
-        bincode::decode_from_slice(&buffer, config)?;
+    let (eval_file, _len): (EvalFile, usize) = bincode::decode_from_slice(&buffer, config)?;
 
-    Ok(decoded_map)
+    Ok(eval_file)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::cubie::{Cubie, CubieSet};
 
     #[test]
     fn test_1_1_game_draw() {
@@ -253,8 +535,8 @@ mod tests {
         assert_eq!(
             evaluated[&game.zobrist_hash],
             Evaluation {
-                score: -1,
-                moves_to_wl: 0
+                winner: None,
+                moves_to_wl: -1
             }
         );
     }
@@ -266,7 +548,7 @@ mod tests {
         assert_eq!(
             evaluated[&game.zobrist_hash],
             Evaluation {
-                score: 1,
+                winner: Some(0),
                 moves_to_wl: 5
             }
         );
@@ -276,7 +558,52 @@ mod tests {
     fn test_1_4_game_won_by_p2() {
         let game = GameState::new(1, 4);
         let evaluated = evaluate(&game, SearchMode::Pruned);
-        assert_eq!(evaluated[&game.zobrist_hash].score, -1);
+        assert_eq!(evaluated[&game.zobrist_hash].winner, Some(1));
+    }
+
+    #[test]
+    fn test_3_0_0_game_won_by_p1() {
+        let game = GameState::with_cubies(&[3, 0, 0]);
+        let evaluated = evaluate(&game, SearchMode::Full);
+        assert_eq!(evaluated[&game.zobrist_hash].winner, Some(0));
+    }
+
+    #[test]
+    fn test_3_0_game_without_flips() {
+        let rules = RuleSet {
+            flips: false,
+            ..RuleSet::default()
+        };
+        let game = GameState::new(3, 0).with_rules(rules);
+        let evaluated = evaluate(&game, SearchMode::Full);
+        assert_eq!(evaluated[&game.zobrist_hash].winner, Some(0));
+        assert!(evaluated[&game.zobrist_hash].moves_to_wl >= 5);
+    }
+
+    #[test]
+    fn test_team_game_won_by_p1() {
+        let blue_green = CubieSet::from_iter([Cubie::Blue, Cubie::Green]);
+        let game = GameState::with_colors(&[blue_green, CubieSet::single(Cubie::Red)], &[3, 0]);
+        let evaluated = evaluate(&game, SearchMode::Full);
+        assert_eq!(evaluated[&game.zobrist_hash].winner, Some(0));
+    }
+
+    #[test]
+    fn test_color_swap() {
+        let game = GameState::new(2, 2);
+        let evaluated = evaluate(&game, SearchMode::Full);
+        let swapped_game = game.clone().with_color_swap();
+        let swapped = evaluate(&swapped_game, SearchMode::Full);
+        assert!(swapped.len() < evaluated.len());
+        assert_eq!(lookup(&swapped, &swapped_game), lookup(&evaluated, &game));
+
+        // The children are evaluated from the point of view of their own player to move
+        for m in game.legal_moves() {
+            let (mut child, mut swapped_child) = (game.clone(), swapped_game.clone());
+            child.apply_move_normalize(m).unwrap();
+            swapped_child.apply_move_normalize(m).unwrap();
+            assert_eq!(lookup(&swapped, &swapped_child), lookup(&evaluated, &child));
+        }
     }
 
     /// cargo test --release test_4_4_game -- --nocapture --ignored
@@ -289,10 +616,56 @@ mod tests {
         println!("Game evaluation: {}", evaluated[&game.zobrist_hash]);
         println!("Number of evaluated states: {}", evaluated.len());
 
-        save_eval(&evaluated, "eval.bin").unwrap();
+        let eval_file = EvalFile::new(game.rules, false, evaluated);
+        save_eval(&eval_file, "eval.bin").unwrap();
         let loaded = load_eval("eval.bin").unwrap();
-        assert_eq!(loaded, evaluated);
+        assert_eq!(loaded, eval_file);
 
         std::fs::remove_file("eval.bin").unwrap();
     }
+
+    #[test]
+    fn test_evaluate_into_table() {
+        let game = GameState::new(3, 0);
+        let mut table = TranspositionTable::with_megabytes(1);
+        let eval = evaluate_into(&game, SearchMode::Full, &mut table);
+        assert_eq!(
+            eval,
+            evaluate(&game, SearchMode::Full)
+                .get(&game.zobrist_hash)
+                .copied()
+        );
+        assert_eq!(lookup(&table, &game), eval);
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let allow_undos = RuleSet {
+            no_undo: false,
+            ..RuleSet::default()
+        };
+        let games = [
+            GameState::new(1, 1),
+            GameState::new(2, 1),
+            GameState::new(2, 2),
+            GameState::new(2, 2).with_rules(allow_undos),
+        ];
+        for game in games {
+            let evaluated = evaluate(&game, SearchMode::Full);
+            for threads in 1..=3 {
+                assert_eq!(evaluate_parallel(&game, threads), evaluated);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bundled_eval_file_decodes() {
+        let eval_file = load_eval("assets/eval.bin").unwrap();
+        // The webapp discards files it can't look its positions up in
+        assert!(eval_file.is_current());
+        assert!(!eval_file.color_swap);
+        assert_eq!(eval_file.rules, RuleSet::default());
+        let root = GameState::new(12, 12);
+        assert!(lookup(&eval_file.evaluations, &root).is_some());
+    }
 }
@@ -0,0 +1,336 @@
+//! Exact solver by retrograde analysis: the graph of all positions reachable in a game is built
+//! first and then solved backwards from the positions where the game is over. `naive::minimax`
+//! skips positions already on the path it searches, so whether a position is a draw can depend on
+//! the path by which it was first reached. Here the results don't depend on any search order:
+//! positions from which neither player can force a win are draws, wins take the fewest moves and
+//! losses the most under optimal play.
+
+use crate::{core::game::GameState, search::naive::Evaluation};
+use std::collections::{HashMap, VecDeque};
+
+/// Value of a position for the player to move, with the number of moves to the end of the game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    Unknown,
+    Draw,
+    Win(u32),
+    Loss(u32),
+}
+
+/// Reachable positions of a game and the moves between them. Positions are indexed in the order
+/// they were found.
+struct Graph {
+    hashes: Vec<u64>,
+    /// Player to move in the position the evaluation is stored for, see `naive::lookup`.
+    movers: Vec<u8>,
+    /// Values of the positions where the game is over, `Unknown` for the others until solved.
+    values: Vec<Value>,
+    /// Positions reached from position `i` are `children[child_offsets[i]..child_offsets[i + 1]]`.
+    child_offsets: Vec<usize>,
+    children: Vec<u32>,
+}
+
+impl Graph {
+    /// Explores the positions reachable from `start` breadth-first. Games that are over aren't
+    /// continued.
+    fn build(start: &GameState) -> Self {
+        let mut start = start.clone();
+        start.normalize();
+        let mut graph = Graph {
+            hashes: Vec::new(),
+            movers: Vec::new(),
+            values: Vec::new(),
+            child_offsets: vec![0],
+            children: Vec::new(),
+        };
+        let mut index = HashMap::from([(start.zobrist_hash, 0)]);
+        graph.add(&start);
+        let mut queue = VecDeque::from([start]);
+
+        // Positions are expanded in the order they were found, i.e. by index
+        while let Some(mut state) = queue.pop_front() {
+            let first = graph.children.len();
+            if !state.is_over() {
+                for m in state.legal_moves() {
+                    let undo = state.apply_move_normalize(m).unwrap();
+                    let next = index.len() as u32;
+                    let child = *index.entry(state.zobrist_hash).or_insert_with(|| {
+                        graph.add(&state);
+                        queue.push_back(state.clone());
+                        next
+                    });
+                    graph.children.push(child);
+                    state.undo_move(undo);
+                }
+            }
+            // Several moves can lead to the same position
+            let mut children = graph.children.split_off(first);
+            children.sort_unstable();
+            children.dedup();
+            graph.children.extend(children);
+            graph.child_offsets.push(graph.children.len());
+        }
+
+        graph
+    }
+
+    fn children_of(&self, i: usize) -> &[u32] {
+        &self.children[self.child_offsets[i]..self.child_offsets[i + 1]]
+    }
+
+    fn add(&mut self, state: &GameState) {
+        let mover = state.player_to_move.id;
+        self.hashes.push(state.zobrist_hash);
+        self.movers.push(if state.colors_swapped() {
+            mover ^ 1
+        } else {
+            mover
+        });
+        self.values.push(match state.won() {
+            _ if !state.is_over() => Value::Unknown,
+            Some((winner, _)) if winner.id == mover => Value::Win(0),
+            Some(_) => Value::Loss(0),
+            None => Value::Draw,
+        });
+    }
+
+    /// Propagates the values of finished games backwards: a position is won if one of its
+    /// children is lost for the player to move there, and lost once all of its children are won.
+    /// Positions are solved in the order of their distance to the end of the game, so that wins
+    /// are as fast and losses as slow as possible. Positions left unsolved are draws.
+    fn solve(&mut self) {
+        let n = self.hashes.len();
+        let mut parent_offsets = vec![0; n + 1];
+        for &child in &self.children {
+            parent_offsets[child as usize + 1] += 1;
+        }
+        for i in 0..n {
+            parent_offsets[i + 1] += parent_offsets[i];
+        }
+        let mut parents = vec![0; self.children.len()];
+        let mut next_parent = parent_offsets.clone();
+        for parent in 0..n {
+            for &child in self.children_of(parent) {
+                parents[next_parent[child as usize]] = parent as u32;
+                next_parent[child as usize] += 1;
+            }
+        }
+
+        // Children not known to be won for the player to move there
+        let mut unsolved: Vec<usize> = self
+            .child_offsets
+            .windows(2)
+            .map(|offsets| offsets[1] - offsets[0])
+            .collect();
+        let mut queue: VecDeque<usize> = (0..n)
+            .filter(|&i| matches!(self.values[i], Value::Win(_) | Value::Loss(_)))
+            .collect();
+        while let Some(child) = queue.pop_front() {
+            let (child_lost, moves) = match self.values[child] {
+                Value::Win(moves) => (false, moves),
+                Value::Loss(moves) => (true, moves),
+                _ => unreachable!("Only solved positions are queued"),
+            };
+            for &parent in &parents[parent_offsets[child]..parent_offsets[child + 1]] {
+                let parent = parent as usize;
+                if self.values[parent] != Value::Unknown {
+                    continue;
+                }
+                if child_lost {
+                    self.values[parent] = Value::Win(moves + 1);
+                    queue.push_back(parent);
+                } else {
+                    unsolved[parent] -= 1;
+                    if unsolved[parent] == 0 {
+                        self.values[parent] = Value::Loss(moves + 1);
+                        queue.push_back(parent);
+                    }
+                }
+            }
+        }
+
+        for value in self.values.iter_mut() {
+            if *value == Value::Unknown {
+                *value = Value::Draw;
+            }
+        }
+    }
+
+    fn evaluations(&self) -> HashMap<u64, Evaluation> {
+        self.hashes
+            .iter()
+            .zip(&self.movers)
+            .zip(&self.values)
+            .map(|((&hash, &mover), &value)| {
+                let (winner, moves_to_wl) = match value {
+                    Value::Win(moves) => (Some(mover), moves as isize),
+                    Value::Loss(moves) => (Some(mover ^ 1), moves as isize),
+                    _ => (None, -1),
+                };
+                (
+                    hash,
+                    Evaluation {
+                        winner,
+                        moves_to_wl,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// Evaluates all positions reachable from `game_state` exactly, keyed like the evaluations of
+/// `naive::evaluate`. Only for two players, since with more a player who can't win has no
+/// preference among the others.
+pub fn evaluate(game_state: &GameState) -> HashMap<u64, Evaluation> {
+    assert!(
+        game_state.players.len() == 2,
+        "Retrograde analysis needs two players"
+    );
+    let mut graph = Graph::build(game_state);
+    graph.solve();
+    graph.evaluations()
+}
+
+/// How two sets of evaluations of the same game differ, e.g. naive ones from exact ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// Positions evaluated the same in both.
+    pub same: usize,
+    /// Positions with another winner or a draw in only one of them.
+    pub different_winner: usize,
+    /// Positions with the same winner but another number of moves to win.
+    pub different_moves: usize,
+    pub only_first: usize,
+    pub only_second: usize,
+}
+
+pub fn compare(first: &HashMap<u64, Evaluation>, second: &HashMap<u64, Evaluation>) -> Comparison {
+    let mut comparison = Comparison::default();
+    for (hash, eval) in first {
+        match second.get(hash) {
+            None => comparison.only_first += 1,
+            Some(other) if other == eval => comparison.same += 1,
+            Some(other) if other.winner == eval.winner => comparison.different_moves += 1,
+            Some(_) => comparison.different_winner += 1,
+        }
+    }
+    comparison.only_second = second
+        .keys()
+        .filter(|hash| !first.contains_key(hash))
+        .count();
+    comparison
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::rules::RuleSet,
+        search::naive::{self, SearchMode, lookup},
+    };
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_small_games() {
+        let game = GameState::new(1, 1);
+        assert_eq!(
+            evaluate(&game)[&game.zobrist_hash],
+            Evaluation {
+                winner: None,
+                moves_to_wl: -1
+            }
+        );
+
+        let game = GameState::new(3, 0);
+        assert_eq!(
+            evaluate(&game)[&game.zobrist_hash],
+            Evaluation {
+                winner: Some(0),
+                moves_to_wl: 5
+            }
+        );
+    }
+
+    /// Every evaluation must follow from those of the children: the fastest win if there is one,
+    /// a draw if there is one, otherwise the slowest loss.
+    #[test]
+    fn test_evaluations_are_consistent() {
+        let mut rng = StdRng::seed_from_u64(21);
+        let rules = RuleSet {
+            no_undo: false,
+            ..RuleSet::default()
+        };
+        for game in [GameState::new(2, 2), GameState::new(3, 1).with_rules(rules)] {
+            let evaluated = evaluate(&game);
+            for _ in 0..50 {
+                let mut state = game.clone();
+                while !state.is_over() && rng.random_bool(0.9) {
+                    let moves = state.legal_moves();
+                    state
+                        .apply_move_normalize(moves[rng.random_range(0..moves.len())])
+                        .unwrap();
+                }
+                let eval = lookup(&evaluated, &state).unwrap();
+                if state.is_over() {
+                    assert!(eval.moves_to_wl <= 0);
+                    continue;
+                }
+                let player = state.player_to_move.id;
+                let best = state
+                    .legal_moves()
+                    .into_iter()
+                    .map(|m| {
+                        let mut child = state.clone();
+                        child.apply_move_normalize(m).unwrap();
+                        lookup(&evaluated, &child).unwrap()
+                    })
+                    .reduce(|best, eval| {
+                        if eval.is_better_for(player, &best) {
+                            eval
+                        } else {
+                            best
+                        }
+                    })
+                    .unwrap();
+                let expected = match best.winner {
+                    Some(_) => Evaluation {
+                        moves_to_wl: best.moves_to_wl + 1,
+                        ..best
+                    },
+                    None => best,
+                };
+                assert_eq!(eval, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compare_with_naive() {
+        let game = GameState::new(2, 2);
+        let exact = evaluate(&game);
+        let naive = naive::evaluate(&game, SearchMode::Full);
+        let comparison = compare(&naive, &exact);
+        assert_eq!(comparison.only_first + comparison.only_second, 0);
+        assert_eq!(
+            comparison.same + comparison.different_winner + comparison.different_moves,
+            exact.len()
+        );
+        assert_eq!(naive[&game.zobrist_hash], exact[&game.zobrist_hash]);
+    }
+
+    #[test]
+    fn test_color_swap() {
+        let game = GameState::new(2, 2);
+        let swapped_game = game.clone().with_color_swap();
+        let evaluated = evaluate(&game);
+        let swapped = evaluate(&swapped_game);
+        assert!(swapped.len() < evaluated.len());
+        for m in game.legal_moves() {
+            let (mut child, mut swapped_child) = (game.clone(), swapped_game.clone());
+            child.apply_move_normalize(m).unwrap();
+            swapped_child.apply_move_normalize(m).unwrap();
+            assert_eq!(lookup(&swapped, &swapped_child), lookup(&evaluated, &child));
+        }
+    }
+}
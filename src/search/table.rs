@@ -0,0 +1,157 @@
+//! Fixed-size hash table for search results, like the transposition tables of chess engines.
+//! Positions are stored by hash in buckets of a few entries. Once a bucket is full, a new entry
+//! replaces the least valuable one, so the table never grows beyond its memory budget but may
+//! forget results. Entries keep the high bits of the hash to tell apart the positions sharing a
+//! bucket.
+
+use std::mem::size_of;
+
+/// Entries per bucket.
+const BUCKET_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Slot<V> {
+    /// High bits of the hash, the low ones choose the bucket.
+    check: u32,
+    /// How valuable the entry is to keep, e.g. how deep the search behind it was.
+    depth: u8,
+    /// Search the entry was stored in, see `TranspositionTable::new_search`.
+    age: u8,
+    /// `None` if the slot is empty.
+    value: Option<V>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TranspositionTable<V> {
+    buckets: Vec<[Slot<V>; BUCKET_SIZE]>,
+    age: u8,
+    len: usize,
+}
+
+impl<V: Copy> TranspositionTable<V> {
+    /// An empty table taking up about `megabytes` of memory, with room for at least one bucket.
+    pub fn with_megabytes(megabytes: usize) -> Self {
+        let buckets = (megabytes << 20) / size_of::<[Slot<V>; BUCKET_SIZE]>();
+        let empty = Slot {
+            check: 0,
+            depth: 0,
+            age: 0,
+            value: None,
+        };
+        Self {
+            buckets: vec![[empty; BUCKET_SIZE]; buckets.max(1)],
+            age: 0,
+            len: 0,
+        }
+    }
+
+    /// Number of entries the table can hold.
+    pub fn capacity(&self) -> usize {
+        self.buckets.len() * BUCKET_SIZE
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn bucket_and_check(&self, hash: u64) -> (usize, u32) {
+        (
+            (hash % self.buckets.len() as u64) as usize,
+            (hash >> 32) as u32,
+        )
+    }
+
+    pub fn get(&self, hash: u64) -> Option<V> {
+        let (bucket, check) = self.bucket_and_check(hash);
+        self.buckets[bucket]
+            .iter()
+            .find(|slot| slot.value.is_some() && slot.check == check)
+            .and_then(|slot| slot.value)
+    }
+
+    /// Stores `value` for `hash`, replacing an earlier value for it. If the bucket of `hash` is
+    /// full, the entry replaced is one from an earlier search if any, and the one with the
+    /// smallest `depth` among those.
+    pub fn insert(&mut self, hash: u64, value: V, depth: u8) {
+        let (bucket, check) = self.bucket_and_check(hash);
+        let age = self.age;
+        let slots = &mut self.buckets[bucket];
+        let i = slots
+            .iter()
+            .position(|slot| slot.value.is_some() && slot.check == check)
+            .unwrap_or_else(|| {
+                (0..BUCKET_SIZE)
+                    .min_by_key(|&i| {
+                        let slot = &slots[i];
+                        (slot.value.is_some(), slot.age == age, slot.depth)
+                    })
+                    .unwrap()
+            });
+        if slots[i].value.is_none() {
+            self.len += 1;
+        }
+        slots[i] = Slot {
+            check,
+            depth,
+            age,
+            value: Some(value),
+        };
+    }
+
+    /// Marks the entries stored so far as older, so that new ones replace them first.
+    pub fn new_search(&mut self) {
+        self.age = self.age.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut table = TranspositionTable::with_megabytes(1);
+        assert!(table.is_empty());
+        table.insert(12345, 'a', 3);
+        table.insert(12345, 'b', 1);
+        assert_eq!(table.get(12345), Some('b'));
+        assert_eq!(table.len(), 1);
+        // Same bucket, other check bits
+        assert_eq!(
+            table.get(12345 + (1 << 32) * table.buckets.len() as u64),
+            None
+        );
+        assert_eq!(table.get(54321), None);
+    }
+
+    #[test]
+    fn test_replacement() {
+        let mut table = TranspositionTable::with_megabytes(0);
+        assert_eq!(table.capacity(), BUCKET_SIZE);
+        // All hashes share the only bucket
+        let hash = |i: u64| i << 32;
+        for i in 0..BUCKET_SIZE as u64 {
+            table.insert(hash(i), i, 10 - i as u8);
+        }
+        assert_eq!(table.len(), BUCKET_SIZE);
+
+        // The shallowest entry goes first
+        table.insert(hash(10), 10, 5);
+        assert_eq!(table.get(hash(3)), None);
+        assert_eq!(table.get(hash(10)), Some(10));
+        assert_eq!(table.len(), BUCKET_SIZE);
+
+        // Then entries of earlier searches, however deep
+        table.new_search();
+        table.insert(hash(11), 11, 0);
+        assert_eq!(table.get(hash(10)), None);
+        table.insert(hash(12), 12, 0);
+        assert_eq!(table.get(hash(2)), None);
+        assert_eq!(table.get(hash(0)), Some(0));
+        assert_eq!(table.get(hash(11)), Some(11));
+    }
+}